@@ -53,7 +53,7 @@ export interface Model {
   };
 }
 
-export type StepType = 'thinking' | 'tool_call' | 'tool_result' | 'final_answer' | 'error';
+export type StepType = 'thinking' | 'tool_call' | 'tool_result' | 'final_answer' | 'error' | 'content_delta' | 'plan';
 
 export interface AgentStep {
   step_id?: string; 
@@ -62,6 +62,7 @@ export interface AgentStep {
   tool_name?: string;
   tool_input?: string;
   tool_output?: string;
+  server?: string;
 }
 
 export interface AgentRunResponse {
@@ -69,6 +70,14 @@ export interface AgentRunResponse {
   final_answer: string;
   steps: AgentStep[];
   iterations: number;
+  warnings?: string[];
+  truncated: boolean;
+  model: string;
+  usage?: {
+    prompt_tokens: number;
+    completion_tokens: number;
+    total_tokens: number;
+  };
 }
 
 export interface Tool {
@@ -1,19 +1,51 @@
 use crate::config::Config;
 use crate::error::AgentError;
-use crate::mcp::McpManager;
-use crate::models::{FunctionDefinition, Message, MessageFunctionCall, MessageToolCall, Tool};
+use crate::formatting::split_reasoning;
+use crate::mcp::{full_tool_name, McpManager};
+use crate::metrics::AgentMetrics;
+use crate::models::{
+    merge_duplicate_tool_results, ChatCompletionResponse, FunctionDefinition, GenerationParams,
+    Message, MessageFunctionCall, MessageToolCall, Tool, ToolChoice, ToolChoiceMode, UsageInfo,
+};
+use crate::notes::NoteStore;
 use crate::openrouter::OpenRouterClient;
+use crate::retry::{self, RetryBudget};
+use crate::scratchpad::Scratchpad;
+use crate::search;
+use crate::tools::ToolRegistry;
+use futures::stream::{self, StreamExt};
 use serde::{Deserialize, Serialize};
-use serde_json::{json, Value};
-use std::sync::Arc;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, RwLock};
+use tokio_util::sync::CancellationToken;
 use tracing::{debug, info, warn};
 
+mod strategy;
+pub use strategy::StrategyKind;
+
 const MAX_ITERATIONS: usize = 10;
 
+/// OpenRouter/OpenAI limit on function name length.
+const MAX_TOOL_NAME_LEN: usize = 64;
+
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+const EMPTY_ANSWER_RETRY_PROMPT: &str =
+    "Your previous reply was empty. Please provide your final answer.";
+
 pub struct Agent {
     client: OpenRouterClient,
     config: Config,
     mcp: Option<Arc<McpManager>>,
+    tools: ToolRegistry,
+    metrics: AgentMetrics,
+    /// Sanitized MCP tool names as sent to the model, mapped back to the
+    /// server and tool they stand for.
+    mcp_tool_names: RwLock<HashMap<String, (String, String)>>,
+    session_cache: SessionToolCache,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -26,6 +58,66 @@ pub struct AgentStep {
     pub tool_input: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub tool_output: Option<String>,
+    /// MCP server that serves the tool, for tool call and result steps.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server: Option<String>,
+}
+
+impl AgentStep {
+    pub fn new(step_type: StepType, content: impl Into<String>) -> Self {
+        Self {
+            step_type,
+            content: content.into(),
+            tool_name: None,
+            tool_input: None,
+            tool_output: None,
+            server: None,
+        }
+    }
+
+    fn tool_call(tool_name: &str, tool_input: &str) -> Self {
+        Self {
+            tool_name: Some(tool_name.to_string()),
+            tool_input: Some(tool_input.to_string()),
+            ..Self::new(StepType::ToolCall, format!("Calling: {}", tool_name))
+        }
+    }
+
+    fn tool_output(step_type: StepType, tool_name: &str, output: &str) -> Self {
+        Self {
+            tool_name: Some(tool_name.to_string()),
+            tool_output: Some(output.to_string()),
+            ..Self::new(step_type, output)
+        }
+    }
+
+    fn with_server(self, server: Option<String>) -> Self {
+        Self { server, ..self }
+    }
+
+    /// Copy of the step with its text fields cut to `max_chars` characters
+    /// each, for returning to clients.
+    fn truncated(&self, max_chars: Option<usize>) -> Self {
+        let mut step = self.clone();
+        if let Some(max_chars) = max_chars {
+            truncate_text(&mut step.content, max_chars);
+            for text in [&mut step.tool_input, &mut step.tool_output]
+                .into_iter()
+                .flatten()
+            {
+                truncate_text(text, max_chars);
+            }
+        }
+        step
+    }
+}
+
+fn truncate_text(text: &mut String, max_chars: usize) {
+    if let Some((cut, _)) = text.char_indices().nth(max_chars) {
+        let omitted = text[cut..].chars().count();
+        text.truncate(cut);
+        text.push_str(&format!("... [truncated {} chars]", omitted));
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -35,7 +127,173 @@ pub enum StepType {
     ToolCall,
     ToolResult,
     FinalAnswer,
+    ConfirmationRequired,
     Error,
+    /// A piece of model output, streamed while it is generated. The
+    /// `plan`, `thinking` or `final_answer` step that follows carries the
+    /// full content and marks the text as complete. Never stored with the run.
+    ContentDelta,
+    /// The plan a `plan_execute` run starts with.
+    Plan,
+}
+
+impl StepType {
+    /// SSE event name used when the step is streamed to clients.
+    pub fn event_name(&self) -> &'static str {
+        match self {
+            Self::Thinking => "thinking",
+            Self::ToolCall => "tool_call",
+            Self::ToolResult => "tool_result",
+            Self::FinalAnswer => "final_answer",
+            Self::ConfirmationRequired => "confirmation_required",
+            Self::Error => "error",
+            Self::ContentDelta => "content_delta",
+            Self::Plan => "plan",
+        }
+    }
+}
+
+/// (session id, server, tool, arguments)
+type SessionCacheKey = (String, String, String, String);
+
+/// MCP tool results remembered per session, so a conversation that asks for
+/// the same data again reuses it while other sessions still fetch fresh.
+struct SessionToolCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<SessionCacheKey, (Instant, String)>>,
+}
+
+impl SessionToolCache {
+    fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        !self.ttl.is_zero()
+    }
+
+    fn get(&self, key: &SessionCacheKey) -> Option<String> {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries
+            .get(key)
+            .filter(|(stored, _)| stored.elapsed() < self.ttl)
+            .map(|(_, result)| result.clone())
+    }
+
+    fn insert(&self, key: SessionCacheKey, result: String) {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.retain(|_, (stored, _)| stored.elapsed() < self.ttl);
+        entries.insert(key, (Instant::now(), result));
+    }
+}
+
+/// Collects the steps of a run and, when streaming, forwards each one as
+/// soon as it is recorded. Steps are kept whole for the run itself and only
+/// truncated to `max_chars` on the way out to the client. Also gathers
+/// warnings about adjustments the run made that the client should know of.
+struct StepLog {
+    steps: Vec<AgentStep>,
+    sink: Option<mpsc::Sender<AgentStep>>,
+    max_chars: Option<usize>,
+    max_steps: Option<usize>,
+    stream_deltas: bool,
+    dropped: usize,
+    warnings: Vec<String>,
+}
+
+impl StepLog {
+    fn new(
+        sink: Option<mpsc::Sender<AgentStep>>,
+        max_chars: Option<usize>,
+        max_steps: Option<usize>,
+        stream_deltas: bool,
+    ) -> Self {
+        Self {
+            steps: Vec::new(),
+            sink,
+            max_chars,
+            max_steps,
+            stream_deltas,
+            dropped: 0,
+            warnings: Vec::new(),
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.max_steps.is_some_and(|max| self.steps.len() >= max)
+    }
+
+    /// Where content deltas go, if the run streams them.
+    fn delta_sink(&self) -> Option<mpsc::Sender<AgentStep>> {
+        self.sink.clone().filter(|_| self.stream_deltas)
+    }
+
+    /// Whether steps were lost to `MAX_STEPS`.
+    fn truncated(&self) -> bool {
+        self.dropped > 0
+    }
+
+    fn warn(&mut self, warning: impl Into<String>) {
+        let warning = warning.into();
+        warn!("{}", warning);
+        self.warnings.push(warning);
+    }
+
+    /// Steps past `MAX_STEPS` are dropped; the run stops before its next
+    /// iteration once the log is full. The final answer is kept regardless,
+    /// so a capped run still ends with one.
+    async fn push(&mut self, step: AgentStep) {
+        if self.is_full() && step.step_type != StepType::FinalAnswer {
+            self.dropped += 1;
+            return;
+        }
+        if let Some(ref sink) = self.sink {
+            // A closed receiver only means the client went away; the run
+            // still completes normally.
+            let _ = sink.send(step.truncated(self.max_chars)).await;
+        }
+        self.steps.push(step);
+    }
+
+    /// The steps as returned to the client, and the run's warnings.
+    fn finish(mut self) -> (Vec<AgentStep>, Vec<String>) {
+        if self.dropped > 0 {
+            self.warn(format!(
+                "{} step(s) dropped after reaching the {} step limit",
+                self.dropped,
+                self.max_steps.unwrap_or_default()
+            ));
+        }
+
+        let Some(max_chars) = self.max_chars else {
+            return (self.steps, self.warnings);
+        };
+
+        let steps: Vec<AgentStep> = self
+            .steps
+            .iter()
+            .map(|step| step.truncated(Some(max_chars)))
+            .collect();
+        let truncated = steps
+            .iter()
+            .zip(&self.steps)
+            .filter(|(short, full)| {
+                short.content != full.content
+                    || short.tool_input != full.tool_input
+                    || short.tool_output != full.tool_output
+            })
+            .count();
+        if truncated > 0 {
+            self.warn(format!(
+                "{} step(s) truncated to {} characters",
+                truncated, max_chars
+            ));
+        }
+        (steps, self.warnings)
+    }
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -43,47 +301,313 @@ pub struct AgentResponse {
     pub steps: Vec<AgentStep>,
     pub final_answer: String,
     pub iterations: usize,
+    pub generation_ids: Vec<String>,
+    pub pending_confirmation: Option<PendingToolCall>,
+    /// Content of reasoning tags stripped from the final answer.
+    pub reasoning: Option<String>,
+    /// Adjustments made during the run, such as retries or truncation.
+    pub warnings: Vec<String>,
+    /// The run was cut short by `MAX_STEPS`.
+    pub truncated: bool,
+    /// Why the run ended: `stop` with an answer, `confirmation_required`,
+    /// `tool_error` when `abort_on_tool_error` stopped it, or `step_limit` /
+    /// `iteration_limit`.
+    pub finish_reason: &'static str,
+    /// Token usage summed over every model call in the run; `None` when no
+    /// response reported usage.
+    pub usage: Option<UsageInfo>,
+    /// Model that produced the last response, which differs from the one
+    /// requested when OpenRouter or `MODEL_FALLBACKS` routed elsewhere.
+    pub model: String,
+    /// Messages of the last model request, exactly as sent, when the run
+    /// was started with `capture_prompt`.
+    pub prompt: Option<Vec<Message>>,
+}
+
+/// A destructive tool call the run stopped at. The caller resumes by sending
+/// the request again with the tool name in `confirmed_tools`.
+#[derive(Debug, Clone, Serialize)]
+pub struct PendingToolCall {
+    pub id: String,
+    pub name: String,
+    pub arguments: String,
+}
+
+/// Per-request settings for an agent run.
+#[derive(Debug, Default)]
+pub struct RunOptions {
+    pub system_prompt: Option<String>,
+    pub model: Option<String>,
+    /// Destructive tools the caller has approved for this run.
+    pub confirmed_tools: Vec<String>,
+    /// Ends the run on the first failed tool call, with an `error` step and
+    /// `finish_reason` `tool_error`, instead of letting the model retry.
+    /// Falls back to the configured default.
+    pub abort_on_tool_error: Option<bool>,
+    /// Sent as `parallel_tool_calls`. When false the run executes one tool
+    /// call per turn, even if the model ignores the flag and sends more.
+    pub parallel_tool_calls: Option<bool>,
+    /// `"none"` runs without tools, like plain chat. A choice that forces a
+    /// call applies to the first turn only: forced on every turn, the model
+    /// could never answer and the run would end at `MAX_ITERATIONS`.
+    pub tool_choice: Option<ToolChoice>,
+    /// Passed through with every model request in the run, e.g.
+    /// `response_format` or OpenRouter's `models`.
+    pub generation: GenerationParams,
+    /// Conversation the run belongs to; MCP results are cached per session
+    /// when `SESSION_TOOL_CACHE_TTL_SECS` is set.
+    pub session_id: Option<String>,
+    /// Stops the run, including an in-flight MCP tool call, when cancelled.
+    pub cancel: CancellationToken,
+    /// Keeps the messages of the last model request in the response.
+    pub capture_prompt: bool,
+    /// Orchestration of the run; ReAct unless the request picks another.
+    pub strategy: StrategyKind,
+    /// Retries left for the run, set from `RUN_RETRY_BUDGET` when it starts.
+    pub retry_budget: RetryBudget,
 }
 
 impl Agent {
     pub fn new(config: Config, mcp: Option<Arc<McpManager>>) -> Self {
+        let mut tools = ToolRegistry::with_default_tools();
+        if std::path::Path::new(&config.tools_config_path).exists() {
+            match tools.load_http_tools(&config.tools_config_path) {
+                Ok(count) => info!("Loaded {} tools from {}", count, config.tools_config_path),
+                Err(e) => warn!(
+                    "Failed to load tools from {}: {}",
+                    config.tools_config_path, e
+                ),
+            }
+        }
+        match NoteStore::open(&config.notes_db_path) {
+            Ok(store) => tools.set_note_store(store),
+            Err(e) => warn!("Notes tools disabled: {:#}", e),
+        }
+        if let Some(provider) = search::provider_from_config(&config) {
+            info!("Using {} for web_search", provider.name());
+            tools.set_search_provider(provider, config.search_result_count);
+        }
+
+        let session_cache =
+            SessionToolCache::new(Duration::from_secs(config.session_tool_cache_ttl_secs));
+
         Self {
             client: OpenRouterClient::new(config.clone()),
             config,
             mcp,
+            tools,
+            metrics: AgentMetrics::default(),
+            mcp_tool_names: RwLock::new(HashMap::new()),
+            session_cache,
         }
     }
 
+    pub fn metrics(&self) -> &AgentMetrics {
+        &self.metrics
+    }
+
     pub async fn get_tools(&self) -> Vec<Tool> {
+        let mut tools = self.tools.get_all();
+
         let Some(ref mcp) = self.mcp else {
-            return Vec::new();
+            return tools;
         };
 
-        mcp.get_all_tools()
-            .await
-            .into_iter()
-            .map(|(server_name, tool)| Tool {
+        // Built-in and scratchpad tools keep their names; a clashing MCP tool
+        // is renamed instead, so the same tools always resolve the same way.
+        let mut used_names: HashSet<String> = tools
+            .iter()
+            .chain(Scratchpad::tools().iter())
+            .map(|t| t.function.name.clone())
+            .collect();
+        let mut mcp_tool_names = self.mcp_tool_names.write().await;
+
+        for (server_name, tool) in mcp.get_all_tools().await {
+            let base_name = Self::sanitize_tool_name(&format!(
+                "{}{}",
+                self.config.mcp_tool_prefix,
+                full_tool_name(&server_name, &tool.name)
+            ));
+            let name = Self::unique_tool_name(base_name.clone(), &used_names);
+            if name != base_name {
+                warn!(
+                    "MCP tool '{}' from {} collides with an existing tool named '{}'; offering it as '{}'",
+                    tool.name, server_name, base_name, name
+                );
+            }
+            used_names.insert(name.clone());
+            mcp_tool_names.insert(name.clone(), (server_name.clone(), tool.name.clone()));
+
+            tools.push(Tool {
                 tool_type: "function".to_string(),
                 function: FunctionDefinition {
-                    name: format!("mcp_{}_{}", server_name, tool.name),
+                    parameters: Self::normalize_tool_schema(&name, tool.input_schema),
+                    name,
                     description: tool
                         .description
                         .unwrap_or_else(|| format!("MCP tool from {}", server_name)),
-                    parameters: tool.input_schema,
                 },
+            });
+        }
+
+        tools
+    }
+
+    /// Maps a name onto the `^[a-zA-Z0-9_-]{1,64}$` charset function names
+    /// must match, replacing anything else with `_`.
+    fn sanitize_tool_name(name: &str) -> String {
+        name.chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
             })
+            .take(MAX_TOOL_NAME_LEN)
             .collect()
     }
 
-    fn parse_mcp_tool_name(name: &str) -> Option<(String, String)> {
-        let rest = name.strip_prefix("mcp_")?;
-        let pos = rest.find('_')?;
-        Some((rest[..pos].to_string(), rest[pos + 1..].to_string()))
+    /// Sanitizing and truncation can map two tools to one name; later ones
+    /// get a numeric suffix.
+    fn unique_tool_name(name: String, used_names: &HashSet<String>) -> String {
+        if !used_names.contains(&name) {
+            return name;
+        }
+
+        (2..)
+            .map(|n| {
+                let suffix = format!("_{}", n);
+                let keep = MAX_TOOL_NAME_LEN
+                    .saturating_sub(suffix.len())
+                    .min(name.len());
+                format!("{}{}", &name[..keep], suffix)
+            })
+            .find(|candidate| !used_names.contains(candidate))
+            .unwrap_or(name)
+    }
+
+    /// Repairs MCP input schemas that some models reject: non-object schemas
+    /// are replaced with an empty object schema, and object schemas get the
+    /// `type` and `properties` keys they are missing.
+    fn normalize_tool_schema(tool_name: &str, schema: Value) -> Value {
+        let Value::Object(mut map) = schema else {
+            warn!("Replacing non-object input schema for tool {}", tool_name);
+            return serde_json::json!({ "type": "object", "properties": {} });
+        };
+
+        let mut repaired = false;
+        if !map.contains_key("type") {
+            map.insert("type".to_string(), Value::String("object".to_string()));
+            repaired = true;
+        }
+        if map.get("type").and_then(|t| t.as_str()) == Some("object")
+            && !map.get("properties").is_some_and(Value::is_object)
+        {
+            map.insert("properties".to_string(), Value::Object(Default::default()));
+            repaired = true;
+        }
+
+        if repaired {
+            warn!("Repaired malformed input schema for tool {}", tool_name);
+        }
+        Value::Object(map)
+    }
+
+    /// Pause between iterations: the configured delay plus a random share
+    /// of the jitter, so concurrent runs don't hit the upstream in lockstep.
+    fn iteration_delay(&self) -> Duration {
+        Duration::from_millis(self.config.iteration_delay_ms)
+            + retry::jitter(self.config.iteration_jitter_ms)
+    }
+
+    /// Runs one tool call of a turn. A call to a tool that was never offered
+    /// (e.g. an MCP tool while MCP is unavailable) is answered with a note the
+    /// model can act on instead of a generic execution error.
+    async fn run_tool(
+        &self,
+        tool_name: &str,
+        tool_args: &str,
+        offered_tools: &HashSet<&str>,
+        options: &RunOptions,
+    ) -> (StepType, String) {
+        if !offered_tools.contains(tool_name) {
+            warn!("Model called unavailable tool {}", tool_name);
+            return (
+                StepType::Error,
+                format!(
+                    "Error: tool '{}' is not available. Continue without it.",
+                    tool_name
+                ),
+            );
+        }
+
+        Self::tool_outcome(self.execute_tool(tool_name, tool_args, options).await)
+    }
+
+    fn tool_outcome(outcome: Result<String, AgentError>) -> (StepType, String) {
+        match outcome {
+            Ok(text) => (StepType::ToolResult, text),
+            Err(e) => {
+                warn!("Tool execution failed: {}", e);
+                (StepType::Error, format!("Error: {}", e))
+            }
+        }
     }
 
-    async fn execute_tool(&self, tool_name: &str, args_json: &str) -> Result<String, AgentError> {
-        let (server_name, mcp_tool_name) = Self::parse_mcp_tool_name(tool_name)
-            .ok_or_else(|| AgentError::ToolError(format!("Unknown tool: {}", tool_name)))?;
+    /// Server behind an MCP tool offered in this process, from the name map
+    /// built by `get_tools`.
+    async fn mcp_server_for(&self, tool_name: &str) -> Option<String> {
+        self.mcp_tool_names
+            .read()
+            .await
+            .get(tool_name)
+            .map(|(server, _)| server.clone())
+    }
+
+    /// Fallback for names `get_tools` has not mapped. Without a prefix there
+    /// is no telling an MCP name from any other, so nothing is parsed.
+    async fn parse_mcp_tool_name(&self, name: &str) -> Option<(String, String)> {
+        let prefix = self.config.mcp_tool_prefix.as_str();
+        if prefix.is_empty() {
+            return None;
+        }
+        let full_name = name.strip_prefix(prefix)?;
+        match self.mcp.as_ref()?.resolve_full_name(full_name).await {
+            Ok(target) => Some(target),
+            Err(e) => {
+                warn!("Cannot resolve MCP tool {}: {:#}", name, e);
+                None
+            }
+        }
+    }
+
+    async fn execute_tool(
+        &self,
+        tool_name: &str,
+        args_json: &str,
+        options: &RunOptions,
+    ) -> Result<String, AgentError> {
+        if args_json.len() > self.config.max_tool_argument_bytes {
+            return Err(AgentError::ToolError(format!(
+                "Arguments for '{}' are {} bytes, over the {} byte limit",
+                tool_name,
+                args_json.len(),
+                self.config.max_tool_argument_bytes
+            )));
+        }
+
+        // Mapped names never clash with built-ins, so built-ins are only
+        // checked before the prefix fallback.
+        let mcp_target = self.mcp_tool_names.read().await.get(tool_name).cloned();
+        let mcp_target = match mcp_target {
+            Some(target) => Some(target),
+            None if self.tools.contains(tool_name) => None,
+            None => self.parse_mcp_tool_name(tool_name).await,
+        };
+        let Some((server_name, mcp_tool_name)) = mcp_target else {
+            return self.execute_builtin_tool(tool_name, args_json).await;
+        };
 
         let mcp = self.mcp.as_ref()
             .ok_or_else(|| AgentError::ToolError("MCP not configured".to_string()))?;
@@ -91,77 +615,411 @@ impl Agent {
         let args: Value = serde_json::from_str(args_json)
             .map_err(|e| AgentError::ToolError(format!("Invalid arguments: {}", e)))?;
 
-        mcp.call_tool_text(&server_name, &mcp_tool_name, args)
+        // Destructive tools always run: a cached result would skip the effect.
+        let cache_key = options
+            .session_id
+            .as_ref()
+            .filter(|_| self.session_cache.enabled())
+            .filter(|_| !self.config.destructive_tools.iter().any(|t| t == tool_name))
+            .map(|session| {
+                (
+                    session.clone(),
+                    server_name.clone(),
+                    mcp_tool_name.clone(),
+                    args.to_string(),
+                )
+            });
+        if let Some(key) = &cache_key {
+            if let Some(result) = self.session_cache.get(key) {
+                debug!("Using cached result for {} in session {}", tool_name, key.0);
+                return Ok(result);
+            }
+        }
+
+        let result = mcp
+            .call_tool_text(
+                &server_name,
+                &mcp_tool_name,
+                args,
+                &options.cancel,
+                Some(&options.retry_budget),
+            )
             .await
-            .map_err(|e| AgentError::ToolError(e.to_string()))
+            .map_err(|e| AgentError::ToolError(e.to_string()))?;
+
+        if let Some(key) = cache_key {
+            self.session_cache.insert(key, result.clone());
+        }
+        Ok(result)
+    }
+
+    async fn execute_builtin_tool(
+        &self,
+        tool_name: &str,
+        args_json: &str,
+    ) -> Result<String, AgentError> {
+        if !self.tools.contains(tool_name) {
+            return Err(AgentError::ToolError(format!(
+                "Unknown tool: {}",
+                tool_name
+            )));
+        }
+
+        let args: Value = serde_json::from_str(args_json)
+            .map_err(|e| AgentError::ToolError(format!("Invalid arguments: {}", e)))?;
+
+        let result = self.tools.execute(tool_name, &args).await;
+        if result.success {
+            Ok(result.output)
+        } else {
+            Err(AgentError::ToolError(result.output))
+        }
     }
 
     pub async fn run(
         &self,
         user_message: &str,
         conversation_history: Vec<Message>,
-        system_prompt: Option<String>,
-        model: Option<String>,
+        options: RunOptions,
+    ) -> Result<AgentResponse, AgentError> {
+        self.run_with_sink(user_message, conversation_history, options, None, false)
+            .await
+    }
+
+    /// Same as [`Agent::run`], but also sends every step to `steps_tx` as it
+    /// happens, with the model's output streamed as `content_delta` steps.
+    pub async fn run_streaming(
+        &self,
+        user_message: &str,
+        conversation_history: Vec<Message>,
+        options: RunOptions,
+        steps_tx: mpsc::Sender<AgentStep>,
+    ) -> Result<AgentResponse, AgentError> {
+        self.run_with_sink(
+            user_message,
+            conversation_history,
+            options,
+            Some(steps_tx),
+            true,
+        )
+        .await
+    }
+
+    /// Same as [`Agent::run`], but gives up after `limit`. Steps are collected
+    /// as they happen so a run that times out can report how far it got.
+    pub async fn run_with_timeout(
+        &self,
+        user_message: &str,
+        conversation_history: Vec<Message>,
+        options: RunOptions,
+        limit: Duration,
     ) -> Result<AgentResponse, AgentError> {
-        let system_prompt = system_prompt.unwrap_or_else(|| self.config.system_prompt.clone());
+        let (tx, mut rx) = mpsc::channel(32);
+        let run = self.run_with_sink(user_message, conversation_history, options, Some(tx), false);
+        tokio::pin!(run);
+        let deadline = tokio::time::sleep(limit);
+        tokio::pin!(deadline);
+
+        let mut steps = Vec::new();
+        loop {
+            tokio::select! {
+                result = &mut run => return result,
+                Some(step) = rx.recv() => steps.push(step),
+                _ = &mut deadline => break,
+            }
+        }
+
+        // The run is dropped here, which abandons whatever it was awaiting.
+        while let Ok(step) = rx.try_recv() {
+            steps.push(step);
+        }
+        warn!("Agent run timed out after {:?}", limit);
+        self.metrics.record_run_failed();
+        self.metrics.record_timeout();
+        Err(AgentError::RunTimedOut {
+            limit_secs: limit.as_secs(),
+            steps,
+        })
+    }
+
+    async fn run_with_sink(
+        &self,
+        user_message: &str,
+        conversation_history: Vec<Message>,
+        options: RunOptions,
+        steps_tx: Option<mpsc::Sender<AgentStep>>,
+        stream_deltas: bool,
+    ) -> Result<AgentResponse, AgentError> {
+        self.metrics.record_run_started();
+        let options = RunOptions {
+            retry_budget: RetryBudget::new(self.config.run_retry_budget),
+            ..options
+        };
+
+        let strategy = options.strategy.strategy();
+        let result = strategy
+            .run(
+                self,
+                user_message,
+                conversation_history,
+                options,
+                steps_tx,
+                stream_deltas,
+            )
+            .await;
+
+        if result.is_err() {
+            self.metrics.record_run_failed();
+        }
+        result
+    }
+
+    /// Calls the model, streaming its content to the run's client as
+    /// `content_delta` steps when the run streams deltas.
+    async fn call_model(
+        &self,
+        steps: &StepLog,
+        messages: Vec<Message>,
+        model: &str,
+        tools: Option<Vec<Tool>>,
+        tool_choice: Option<ToolChoice>,
+        options: &RunOptions,
+    ) -> Result<ChatCompletionResponse, AgentError> {
+        let call = |deltas| {
+            self.client.chat_completion_with_tools(
+                messages,
+                Some(model.to_string()),
+                tools,
+                options.parallel_tool_calls,
+                tool_choice,
+                options.generation.clone(),
+                deltas,
+                Some(&options.retry_budget),
+            )
+        };
+        let Some(sink) = steps.delta_sink() else {
+            return call(None).await;
+        };
+
+        let (delta_tx, mut delta_rx) = mpsc::channel::<String>(64);
+        let forward = async {
+            while let Some(delta) = delta_rx.recv().await {
+                let _ = sink
+                    .send(AgentStep::new(StepType::ContentDelta, delta))
+                    .await;
+            }
+        };
+        let (result, ()) = tokio::join!(call(Some(delta_tx)), forward);
+        result
+    }
+
+    fn step_log(&self, sink: Option<mpsc::Sender<AgentStep>>, stream_deltas: bool) -> StepLog {
+        StepLog::new(
+            sink,
+            self.config.step_content_max_chars,
+            self.config.max_steps,
+            stream_deltas,
+        )
+    }
+
+    /// The ReAct loop: the model reasons and calls tools until it answers.
+    async fn run_loop(
+        &self,
+        user_message: &str,
+        conversation_history: Vec<Message>,
+        options: RunOptions,
+        mut steps: StepLog,
+    ) -> Result<AgentResponse, AgentError> {
+        let system_prompt = options
+            .system_prompt
+            .clone()
+            .unwrap_or_else(|| self.config.system_prompt.clone());
 
         let mut messages = vec![Message::system(&system_prompt)];
         messages.extend(conversation_history);
         messages.push(Message::user(user_message));
 
-        let tools = self.get_tools().await;
-        info!("Agent has {} MCP tools available", tools.len());
+        let mut tools = self.get_tools().await;
+        tools.extend(Scratchpad::tools());
+        info!("Agent has {} tools available", tools.len());
+        let offered_tools: HashSet<&str> = tools.iter().map(|t| t.function.name.as_str()).collect();
+        let forced_tool = options
+            .tool_choice
+            .as_ref()
+            .and_then(ToolChoice::function_name);
+        if let Some(name) = forced_tool {
+            if !offered_tools.contains(name) {
+                return Err(AgentError::InvalidRequest(format!(
+                    "tool_choice names unknown tool '{}'",
+                    name
+                )));
+            }
+        }
+        let use_tools = options.tool_choice != Some(ToolChoice::Mode(ToolChoiceMode::None));
 
-        let model = model.unwrap_or_else(|| self.config.default_model.clone());
-        let mut steps = Vec::new();
+        let model = options
+            .model
+            .clone()
+            .unwrap_or_else(|| self.config.default_agent_model.clone());
+        let mut scratchpad = Scratchpad::default();
+        let abort_on_tool_error = options
+            .abort_on_tool_error
+            .unwrap_or(self.config.abort_on_tool_error);
+        let mut generation_ids = Vec::new();
         let mut iterations = 0;
+        let mut retried_empty_answer = false;
+        let mut step_limit_reached = false;
+        let mut usage: Option<UsageInfo> = None;
+        let mut responding_model = model.clone();
+        let mut prompt = None;
+        let retry_budget = &options.retry_budget;
 
         loop {
+            if options.cancel.is_cancelled() {
+                return Err(AgentError::Cancelled);
+            }
+
+            if steps.is_full() {
+                info!("Stopping run at the {} step limit", steps.steps.len());
+                self.metrics.record_step_limit();
+                step_limit_reached = true;
+                break;
+            }
+
             iterations += 1;
             if iterations > MAX_ITERATIONS {
-                steps.push(AgentStep {
-                    step_type: StepType::Error,
-                    content: "Maximum iterations reached".to_string(),
-                    tool_name: None,
-                    tool_input: None,
-                    tool_output: None,
-                });
+                self.metrics.record_iteration_limit();
+                steps
+                    .push(AgentStep::new(
+                        StepType::Error,
+                        "Maximum iterations reached",
+                    ))
+                    .await;
                 break;
             }
 
+            if iterations > 1 {
+                let delay = self.iteration_delay();
+                if !delay.is_zero() {
+                    debug!("Pausing {:?} before iteration {}", delay, iterations);
+                    tokio::select! {
+                        _ = options.cancel.cancelled() => return Err(AgentError::Cancelled),
+                        _ = tokio::time::sleep(delay) => {}
+                    }
+                }
+            }
+
             info!("Agent iteration {}", iterations);
             debug!("Messages: {:?}", messages);
 
-            let response = self
-                .client
-                .chat_completion_with_tools(messages.clone(), Some(model.clone()), Some(tools.clone()))
-                .await?;
+            let tool_choice = match options.tool_choice {
+                Some(ref choice) if choice.forces_call() && iterations > 1 => None,
+                Some(ToolChoice::Mode(ToolChoiceMode::None)) => None,
+                ref choice => choice.clone(),
+            };
+
+            if options.capture_prompt {
+                prompt = Some(merge_duplicate_tool_results(messages.clone()));
+            }
+            let response = loop {
+                match self
+                    .call_model(
+                        &steps,
+                        messages.clone(),
+                        &model,
+                        use_tools.then(|| tools.clone()),
+                        tool_choice.clone(),
+                        &options,
+                    )
+                    .await
+                {
+                    Ok(response) => break response,
+                    Err(e) if e.is_transient() => {
+                        if !retry_budget.try_consume() {
+                            self.metrics.record_retry_budget_exhausted();
+                            return Err(AgentError::RetryBudgetExhausted {
+                                budget: retry_budget.budget(),
+                                last_error: e.to_string(),
+                            });
+                        }
+                        steps.warn(format!(
+                            "Retried after a transient upstream error ({} retries left): {}",
+                            retry_budget.remaining(),
+                            e
+                        ));
+                        tokio::time::sleep(RETRY_DELAY).await;
+                    }
+                    Err(e) => return Err(e),
+                }
+            };
+            generation_ids.push(response.id.clone());
+            if !response.model.is_empty() {
+                responding_model.clone_from(&response.model);
+            }
+            if let Some(ref response_usage) = response.usage {
+                usage
+                    .get_or_insert_with(UsageInfo::default)
+                    .add(response_usage);
+            }
 
             let choice = response
                 .choices
                 .first()
                 .ok_or_else(|| AgentError::ParseError("No choices in response".to_string()))?;
 
-            let Some(tool_calls) = &choice.message.tool_calls else {
-                return Ok(self.create_final_response(steps, &choice.message.content, iterations));
-            };
+            let tool_calls = match &choice.message.tool_calls {
+                Some(tool_calls) if !tool_calls.is_empty() => tool_calls.as_slice(),
+                _ => {
+                    let is_empty = choice
+                        .message
+                        .content
+                        .as_deref()
+                        .is_none_or(|c| c.trim().is_empty());
+                    if is_empty
+                        && self.config.retry_empty_answer
+                        && !retried_empty_answer
+                        && retry_budget.try_consume()
+                    {
+                        steps.warn("Model returned an empty answer and was asked again");
+                        retried_empty_answer = true;
+                        messages.push(Message::user(EMPTY_ANSWER_RETRY_PROMPT));
+                        continue;
+                    }
 
-            if tool_calls.is_empty() {
-                return Ok(self.create_final_response(steps, &choice.message.content, iterations));
-            }
+                    let response = self
+                        .create_final_response(
+                            steps,
+                            &choice.message.content,
+                            iterations,
+                            generation_ids,
+                            usage,
+                            responding_model,
+                        )
+                        .await;
+                    return Ok(AgentResponse { prompt, ..response });
+                }
+            };
 
             if let Some(ref content) = choice.message.content {
                 if !content.is_empty() {
-                    steps.push(AgentStep {
-                        step_type: StepType::Thinking,
-                        content: content.clone(),
-                        tool_name: None,
-                        tool_input: None,
-                        tool_output: None,
-                    });
+                    steps
+                        .push(AgentStep::new(StepType::Thinking, content))
+                        .await;
                 }
             }
 
+            // Calls past the first are dropped from the assistant message too,
+            // so every call the model sees answered is one that ran.
+            let tool_calls = if options.parallel_tool_calls == Some(false) && tool_calls.len() > 1 {
+                steps.warn(format!(
+                    "Model sent {} tool calls with parallel_tool_calls disabled; only the first ran",
+                    tool_calls.len()
+                ));
+                &tool_calls[..1]
+            } else {
+                tool_calls
+            };
+
             let message_tool_calls: Vec<MessageToolCall> = tool_calls
                 .iter()
                 .map(|tc| MessageToolCall {
@@ -174,74 +1032,738 @@ impl Agent {
                 })
                 .collect();
 
+            // Nothing in the turn runs until every destructive call in it has
+            // been confirmed, so a paused run never leaves half its work done.
+            if let Some(tool_call) = tool_calls.iter().find(|tc| {
+                self.config.destructive_tools.contains(&tc.function.name)
+                    && !options.confirmed_tools.contains(&tc.function.name)
+            }) {
+                let tool_name = &tool_call.function.name;
+                info!("Pausing run for confirmation of {}", tool_name);
+                let final_answer = format!("Confirmation required before running {}", tool_name);
+                steps
+                    .push(AgentStep {
+                        tool_name: Some(tool_name.clone()),
+                        tool_input: Some(tool_call.function.arguments.clone()),
+                        ..AgentStep::new(StepType::ConfirmationRequired, &final_answer)
+                    })
+                    .await;
+
+                let truncated = steps.truncated();
+                let (steps, warnings) = steps.finish();
+                return Ok(AgentResponse {
+                    steps,
+                    final_answer,
+                    iterations,
+                    generation_ids,
+                    pending_confirmation: Some(PendingToolCall {
+                        id: tool_call.id.clone(),
+                        name: tool_name.clone(),
+                        arguments: tool_call.function.arguments.clone(),
+                    }),
+                    reasoning: None,
+                    warnings,
+                    truncated,
+                    finish_reason: "confirmation_required",
+                    usage,
+                    model: responding_model,
+                    prompt,
+                });
+            }
+
             messages.push(Message::assistant_with_tool_calls(
                 choice.message.content.clone(),
                 message_tool_calls,
             ));
 
-            for tool_call in tool_calls {
-                let tool_name = &tool_call.function.name;
-                let tool_args = &tool_call.function.arguments;
-
-                steps.push(AgentStep {
-                    step_type: StepType::ToolCall,
-                    content: format!("Calling: {}", tool_name),
-                    tool_name: Some(tool_name.clone()),
-                    tool_input: Some(tool_args.clone()),
-                    tool_output: None,
-                });
+            // Models occasionally repeat an identical call within one turn; run it
+            // once and answer every id with the same result.
+            let mut unique_calls: Vec<(&str, &str)> = Vec::new();
+            let call_slots: Vec<usize> = tool_calls
+                .iter()
+                .map(|tc| {
+                    let call = (tc.function.name.as_str(), tc.function.arguments.as_str());
+                    unique_calls
+                        .iter()
+                        .position(|c| *c == call)
+                        .unwrap_or_else(|| {
+                            unique_calls.push(call);
+                            unique_calls.len() - 1
+                        })
+                })
+                .collect();
+
+            let mut servers = Vec::with_capacity(unique_calls.len());
+            for &(tool_name, tool_args) in &unique_calls {
+                let server = self.mcp_server_for(tool_name).await;
+                steps
+                    .push(AgentStep::tool_call(tool_name, tool_args).with_server(server.clone()))
+                    .await;
+                servers.push(server);
+            }
 
-                let (step_type, result) = match self.execute_tool(tool_name, tool_args).await {
-                    Ok(text) => (StepType::ToolResult, text),
-                    Err(e) => {
-                        warn!("Tool execution failed: {}", e);
-                        (StepType::Error, format!("Error: {}", e))
+            // Scratchpad calls share the run's notes and finish instantly, so
+            // they run in order here; the rest run concurrently, at most
+            // `max_parallel_tools` at a time, with results kept in call order.
+            let mut outcomes: Vec<Option<(StepType, String)>> = unique_calls
+                .iter()
+                .map(|&(tool_name, tool_args)| {
+                    Scratchpad::handles(tool_name)
+                        .then(|| Self::tool_outcome(scratchpad.execute(tool_name, tool_args)))
+                })
+                .collect();
+            let pending: Vec<usize> = (0..unique_calls.len())
+                .filter(|&i| outcomes[i].is_none())
+                .collect();
+            let executed: Vec<(usize, (StepType, String))> = stream::iter(pending)
+                .map(|i| {
+                    let (tool_name, tool_args) = unique_calls[i];
+                    let (offered_tools, options) = (&offered_tools, &options);
+                    async move {
+                        let outcome = self
+                            .run_tool(tool_name, tool_args, offered_tools, options)
+                            .await;
+                        (i, outcome)
                     }
-                };
-                steps.push(AgentStep {
-                    step_type,
-                    content: result.clone(),
-                    tool_name: Some(tool_name.clone()),
-                    tool_input: None,
-                    tool_output: Some(result.clone()),
-                });
-                messages.push(Message::tool_result(&tool_call.id, result));
+                })
+                .buffered(self.config.max_parallel_tools)
+                .collect()
+                .await;
+            for (i, outcome) in executed {
+                outcomes[i] = Some(outcome);
+            }
+
+            let mut results = Vec::with_capacity(unique_calls.len());
+            // Later chunks of split results; they go after every tool message
+            // of the turn, since tool messages must follow the assistant call.
+            let mut continuations = Vec::new();
+
+            for ((&(tool_name, _), outcome), server) in
+                unique_calls.iter().zip(outcomes).zip(servers)
+            {
+                let (step_type, result) = outcome.expect("every tool call has run");
+                let failed = step_type == StepType::Error;
+                steps
+                    .push(AgentStep::tool_output(step_type, tool_name, &result).with_server(server))
+                    .await;
+                if failed && abort_on_tool_error {
+                    self.metrics.record_run_failed();
+                    let final_answer = format!(
+                        "Run stopped: tool '{}' failed: {}",
+                        tool_name,
+                        result.trim_start_matches("Error: ")
+                    );
+                    steps
+                        .push(AgentStep::new(StepType::Error, &final_answer))
+                        .await;
+                    let truncated = steps.truncated();
+                    let (steps, warnings) = steps.finish();
+                    return Ok(AgentResponse {
+                        steps,
+                        final_answer,
+                        iterations,
+                        generation_ids,
+                        pending_confirmation: None,
+                        reasoning: None,
+                        warnings,
+                        truncated,
+                        finish_reason: "tool_error",
+                        usage,
+                        model: responding_model,
+                        prompt,
+                    });
+                }
+
+                let chunks = self.chunk_tool_result(tool_name, &result);
+                if chunks.len() > 1 {
+                    steps.warn(format!(
+                        "Result of {} was split into {} parts for the model",
+                        tool_name,
+                        chunks.len()
+                    ));
+                }
+                let mut chunks = chunks.into_iter();
+                results.push(chunks.next().unwrap_or_default());
+                continuations.extend(chunks.map(Message::user));
+            }
+
+            for (i, (tool_call, &slot)) in tool_calls.iter().zip(&call_slots).enumerate() {
+                if call_slots[..i].contains(&slot) {
+                    steps.warn(format!(
+                        "Duplicate call to {} ({}) reused the earlier result",
+                        tool_call.function.name, tool_call.id
+                    ));
+                }
+                messages.push(Message::tool_result(&tool_call.id, results[slot].clone()));
             }
+            messages.extend(continuations);
         }
         let final_answer = steps
+            .steps
             .iter()
             .rev()
             .find(|s| matches!(s.step_type, StepType::Thinking | StepType::ToolResult))
             .map(|s| s.content.clone())
-            .unwrap_or_else(|| "Task incomplete: iteration limit reached.".to_string());
+            .unwrap_or_else(|| {
+                let limit = if step_limit_reached {
+                    "step"
+                } else {
+                    "iteration"
+                };
+                format!("Task incomplete: {} limit reached.", limit)
+            });
+        steps
+            .push(AgentStep::new(StepType::FinalAnswer, &final_answer))
+            .await;
 
+        let truncated = step_limit_reached || steps.truncated();
+        let (steps, warnings) = steps.finish();
         Ok(AgentResponse {
             steps,
             final_answer,
             iterations,
+            generation_ids,
+            pending_confirmation: None,
+            reasoning: None,
+            warnings,
+            truncated,
+            finish_reason: if step_limit_reached {
+                "step_limit"
+            } else {
+                "iteration_limit"
+            },
+            usage,
+            model: responding_model,
+            prompt,
         })
     }
 
-    fn create_final_response(
+    /// Splits a result larger than `tool_result_chunk_size` into parts the
+    /// model reads in order, each headed with its position. Splits prefer
+    /// line breaks and never cut through a UTF-8 character.
+    fn chunk_tool_result(&self, tool_name: &str, result: &str) -> Vec<String> {
+        let Some(chunk_size) = self.config.tool_result_chunk_size else {
+            return vec![result.to_string()];
+        };
+        if result.len() <= chunk_size {
+            return vec![result.to_string()];
+        }
+
+        let mut parts = Vec::new();
+        let mut rest = result;
+        while !rest.is_empty() {
+            let mut end = chunk_size.min(rest.len());
+            while !rest.is_char_boundary(end) {
+                end -= 1;
+            }
+            if end < rest.len() {
+                if let Some(newline) = rest[..end].rfind('\n') {
+                    end = newline + 1;
+                }
+            }
+            // A chunk size smaller than one character still has to progress.
+            if end == 0 {
+                end = rest.chars().next().map_or(rest.len(), char::len_utf8);
+            }
+            parts.push(&rest[..end]);
+            rest = &rest[end..];
+        }
+
+        let total = parts.len();
+        debug!("Split {} result into {} chunks", tool_name, total);
+        parts
+            .into_iter()
+            .enumerate()
+            .map(|(i, part)| {
+                format!(
+                    "[{} result, part {} of {}]\n{}",
+                    tool_name,
+                    i + 1,
+                    total,
+                    part
+                )
+            })
+            .collect()
+    }
+
+    async fn create_final_response(
         &self,
-        mut steps: Vec<AgentStep>,
+        mut steps: StepLog,
         content: &Option<String>,
         iterations: usize,
+        generation_ids: Vec<String>,
+        usage: Option<UsageInfo>,
+        model: String,
     ) -> AgentResponse {
-        let final_answer = content.clone().unwrap_or_default();
+        let (answer, reasoning) = match content {
+            Some(text) => split_reasoning(text, &self.config.reasoning_tags),
+            None => (String::new(), None),
+        };
+        let final_answer = if answer.trim().is_empty() {
+            steps.warn("Model returned an empty answer; using the fallback answer");
+            self.config.empty_answer_fallback.clone()
+        } else {
+            answer
+        };
 
-        steps.push(AgentStep {
-            step_type: StepType::FinalAnswer,
-            content: final_answer.clone(),
-            tool_name: None,
-            tool_input: None,
-            tool_output: None,
-        });
+        steps
+            .push(AgentStep::new(StepType::FinalAnswer, &final_answer))
+            .await;
 
+        let truncated = steps.truncated();
+        let (steps, warnings) = steps.finish();
         AgentResponse {
             steps,
             final_answer,
             iterations,
+            generation_ids,
+            pending_confirmation: None,
+            reasoning,
+            warnings,
+            truncated,
+            finish_reason: "stop",
+            usage,
+            model,
+            prompt: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{completion, serve, test_config, tool_calls, Upstream};
+    use axum::{http::StatusCode, routing::post, Router};
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Serves `/chat/completions` with a 502 every time, counting requests.
+    async fn failing_upstream() -> (String, Arc<AtomicUsize>) {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = hits.clone();
+        let app = Router::new().route(
+            "/chat/completions",
+            post(move || async move {
+                counter.fetch_add(1, Ordering::SeqCst);
+                (
+                    StatusCode::BAD_GATEWAY,
+                    r#"{"error":{"message":"upstream down"}}"#,
+                )
+            }),
+        );
+        (serve(app).await, hits)
+    }
+
+    #[tokio::test]
+    async fn transient_failures_exhaust_the_run_retry_budget() {
+        let (url, hits) = failing_upstream().await;
+        let mut config = test_config(url);
+        config.run_retry_budget = 2;
+        config.openrouter_max_retries = 5;
+        let agent = Agent::new(config, None);
+
+        let result = agent.run("hello", Vec::new(), RunOptions::default()).await;
+
+        match result {
+            Err(AgentError::RetryBudgetExhausted { budget, last_error }) => {
+                assert_eq!(budget, 2);
+                assert!(last_error.contains("upstream down"), "{}", last_error);
+            }
+            other => panic!("expected an exhausted retry budget, got {:?}", other.err()),
+        }
+        // The first attempt plus one per retry in the budget, though the
+        // client alone would have retried five times.
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+        let metrics = agent.metrics.snapshot();
+        assert_eq!(metrics.runs_retry_budget_exhausted, 1);
+        assert_eq!(metrics.runs_failed, 1);
+    }
+
+    fn abort_options(abort: bool) -> RunOptions {
+        RunOptions {
+            abort_on_tool_error: Some(abort),
+            ..RunOptions::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_tool_call_is_reported_to_the_model_by_default() {
+        let upstream = Upstream::ok(vec![
+            tool_calls(&[("calculator", json!({"expression": "2 +"}))]),
+            completion("The expression is incomplete."),
+        ])
+        .await;
+        let agent = Agent::new(test_config(upstream.url.clone()), None);
+
+        let response = agent
+            .run("What is 2 +?", Vec::new(), abort_options(false))
+            .await
+            .unwrap();
+
+        assert_eq!(response.finish_reason, "stop");
+        assert_eq!(response.final_answer, "The expression is incomplete.");
+        let requests = upstream.requests();
+        assert_eq!(requests.len(), 2);
+        let tool_message = requests[1]["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|m| m["role"] == "tool")
+            .expect("tool result sent to the model");
+        assert!(tool_message["content"]
+            .as_str()
+            .unwrap()
+            .starts_with("Error:"));
+    }
+
+    #[tokio::test]
+    async fn failed_tool_call_ends_the_run_with_its_steps_when_aborting() {
+        let upstream = Upstream::ok(vec![
+            tool_calls(&[("calculator", json!({"expression": "2 +"}))]),
+            completion("unreachable"),
+        ])
+        .await;
+        let agent = Agent::new(test_config(upstream.url.clone()), None);
+
+        let response = agent
+            .run("What is 2 +?", Vec::new(), abort_options(true))
+            .await
+            .unwrap();
+
+        assert_eq!(response.finish_reason, "tool_error");
+        assert_eq!(upstream.requests().len(), 1);
+        let types: Vec<&StepType> = response.steps.iter().map(|s| &s.step_type).collect();
+        assert_eq!(
+            types,
+            [&StepType::ToolCall, &StepType::Error, &StepType::Error]
+        );
+        let last = response.steps.last().unwrap();
+        assert_eq!(last.content, response.final_answer);
+        assert!(
+            response.final_answer.contains("'calculator' failed"),
+            "{}",
+            response.final_answer
+        );
+        assert_eq!(agent.metrics.snapshot().runs_failed, 1);
+    }
+
+    #[tokio::test]
+    async fn calculator_result_is_added_to_the_history() {
+        let upstream = Upstream::ok(vec![
+            tool_calls(&[("calculator", json!({"expression": "(2 + 3) * 4"}))]),
+            completion("It is 20."),
+        ])
+        .await;
+        let agent = Agent::new(test_config(upstream.url.clone()), None);
+
+        let response = agent
+            .run("What is (2 + 3) * 4?", Vec::new(), RunOptions::default())
+            .await
+            .unwrap();
+
+        assert_eq!(response.final_answer, "It is 20.");
+        let result = response
+            .steps
+            .iter()
+            .find(|s| s.step_type == StepType::ToolResult)
+            .expect("tool result step");
+        assert_eq!(result.tool_name.as_deref(), Some("calculator"));
+        assert_eq!(result.content, "20");
+
+        let requests = upstream.requests();
+        let history = requests[1]["messages"].as_array().unwrap();
+        let roles: Vec<&str> = history
+            .iter()
+            .map(|m| m["role"].as_str().unwrap())
+            .collect();
+        assert_eq!(roles, ["system", "user", "assistant", "tool"]);
+        assert_eq!(
+            history[2]["tool_calls"][0]["function"]["name"],
+            "calculator"
+        );
+        assert_eq!(history[3]["tool_call_id"], "call_0");
+        assert_eq!(history[3]["content"], "20");
+    }
+
+    /// The messages of the `index`th request the upstream received.
+    fn sent_messages(upstream: &Upstream, index: usize) -> Vec<serde_json::Value> {
+        upstream.requests()[index]["messages"]
+            .as_array()
+            .unwrap()
+            .clone()
+    }
+
+    #[tokio::test]
+    async fn duplicate_call_in_a_turn_runs_once() {
+        let call = ("calculator", json!({"expression": "6 * 7"}));
+        let upstream =
+            Upstream::ok(vec![tool_calls(&[call.clone(), call]), completion("42")]).await;
+        let agent = Agent::new(test_config(upstream.url.clone()), None);
+
+        let response = agent
+            .run("What is 6 * 7?", Vec::new(), RunOptions::default())
+            .await
+            .unwrap();
+
+        let count = |step_type: StepType| {
+            response
+                .steps
+                .iter()
+                .filter(|s| s.step_type == step_type)
+                .count()
+        };
+        assert_eq!(count(StepType::ToolCall), 1);
+        assert_eq!(count(StepType::ToolResult), 1);
+        assert!(
+            response
+                .warnings
+                .iter()
+                .any(|w| w.contains("Duplicate call")),
+            "{:?}",
+            response.warnings
+        );
+
+        let results: Vec<(String, String)> = sent_messages(&upstream, 1)
+            .iter()
+            .filter(|m| m["role"] == "tool")
+            .map(|m| {
+                (
+                    m["tool_call_id"].as_str().unwrap().to_string(),
+                    m["content"].as_str().unwrap().to_string(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            results,
+            [
+                ("call_0".to_string(), "42".to_string()),
+                ("call_1".to_string(), "42".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn long_tool_result_is_sent_in_ordered_chunks() {
+        let upstream = Upstream::ok(vec![
+            tool_calls(&[(
+                "encode_decode",
+                json!({"operation": "encode", "encoding": "hex", "input": "a".repeat(100)}),
+            )]),
+            completion("done"),
+        ])
+        .await;
+        let mut config = test_config(upstream.url.clone());
+        config.tool_result_chunk_size = Some(64);
+        let agent = Agent::new(config, None);
+
+        agent
+            .run("Hex-encode it", Vec::new(), RunOptions::default())
+            .await
+            .unwrap();
+
+        // The first part answers the call; the rest follow in order.
+        let history = sent_messages(&upstream, 1);
+        let parts: Vec<&str> = history[3..]
+            .iter()
+            .map(|m| m["content"].as_str().unwrap())
+            .collect();
+        assert_eq!(history[3]["role"], "tool");
+        assert!(history[4..].iter().all(|m| m["role"] == "user"));
+        assert_eq!(parts.len(), 4);
+
+        let mut rebuilt = String::new();
+        for (i, part) in parts.iter().enumerate() {
+            let header = format!("[encode_decode result, part {} of 4]\n", i + 1);
+            let body = part.strip_prefix(&header).expect(part);
+            assert!(body.len() <= 64);
+            rebuilt.push_str(body);
+        }
+        assert_eq!(rebuilt, "61".repeat(100));
+    }
+
+    /// An agent with `manager`, once its servers are connected.
+    #[cfg(unix)]
+    async fn mcp_agent(upstream: &Upstream, manager: McpManager) -> Agent {
+        manager.connect_all().await.unwrap();
+        Agent::new(test_config(upstream.url.clone()), Some(Arc::new(manager)))
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn dotted_and_spaced_mcp_names_round_trip() {
+        use crate::mcp::stub::{manager, named_tool_server};
+
+        // Both names are sanitized for the model, which must not stop the
+        // call from reaching the original tool.
+        let offered = "mcp_9_my_server_get_weather";
+        let upstream = Upstream::ok(vec![
+            tool_calls(&[(offered, json!({}))]),
+            completion("It is sunny."),
+        ])
+        .await;
+        let agent = mcp_agent(
+            &upstream,
+            manager(vec![(
+                "my server",
+                named_tool_server("get.weather", "sunny"),
+            )]),
+        )
+        .await;
+
+        let names: Vec<String> = agent
+            .get_tools()
+            .await
+            .into_iter()
+            .map(|t| t.function.name)
+            .collect();
+        assert!(names.iter().any(|n| n == offered), "{:?}", names);
+
+        let response = agent
+            .run("Weather?", Vec::new(), RunOptions::default())
+            .await
+            .unwrap();
+        let result = response
+            .steps
+            .iter()
+            .find(|s| s.step_type == StepType::ToolResult)
+            .expect("tool result step");
+        assert_eq!(result.tool_name.as_deref(), Some(offered));
+        assert_eq!(result.content, "sunny");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn mcp_steps_name_their_server() {
+        use crate::mcp::stub::{manager, named_tool_server};
+
+        let upstream = Upstream::ok(vec![
+            tool_calls(&[
+                ("mcp_7_weather_forecast", json!({})),
+                ("calculator", json!({"expression": "1 + 1"})),
+            ]),
+            completion("Rain, and 2."),
+        ])
+        .await;
+        let agent = mcp_agent(
+            &upstream,
+            manager(vec![("weather", named_tool_server("forecast", "rain"))]),
+        )
+        .await;
+
+        let response = agent
+            .run("Forecast?", Vec::new(), RunOptions::default())
+            .await
+            .unwrap();
+
+        let servers: Vec<(&str, Option<&str>)> = response
+            .steps
+            .iter()
+            .filter(|s| matches!(s.step_type, StepType::ToolCall | StepType::ToolResult))
+            .map(|s| (s.tool_name.as_deref().unwrap(), s.server.as_deref()))
+            .collect();
+        assert_eq!(
+            servers,
+            [
+                ("mcp_7_weather_forecast", Some("weather")),
+                ("calculator", None),
+                ("mcp_7_weather_forecast", Some("weather")),
+                ("calculator", None),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn long_steps_are_truncated_for_the_client_only() {
+        let upstream = Upstream::ok(vec![
+            tool_calls(&[(
+                "encode_decode",
+                json!({"operation": "encode", "encoding": "hex", "input": "a".repeat(100)}),
+            )]),
+            completion("done"),
+        ])
+        .await;
+        let mut config = test_config(upstream.url.clone());
+        config.step_content_max_chars = Some(50);
+        let agent = Agent::new(config, None);
+
+        let response = agent
+            .run("Hex-encode it", Vec::new(), RunOptions::default())
+            .await
+            .unwrap();
+
+        let encoded = "61".repeat(100);
+        let result = response
+            .steps
+            .iter()
+            .find(|s| s.step_type == StepType::ToolResult)
+            .expect("tool result step");
+        assert_eq!(
+            result.content,
+            format!("{}... [truncated 150 chars]", &encoded[..50])
+        );
+        assert!(response.warnings.iter().any(|w| w.contains("truncated")));
+
+        let history = sent_messages(&upstream, 1);
+        assert_eq!(history[3]["content"], encoded.as_str());
+    }
+
+    #[tokio::test]
+    async fn step_cap_stops_the_run_with_a_final_answer() {
+        // The model never stops calling tools.
+        let upstream = Upstream::ok(vec![tool_calls(&[(
+            "calculator",
+            json!({"expression": "1 + 1"}),
+        )])])
+        .await;
+        let mut config = test_config(upstream.url.clone());
+        config.max_steps = Some(3);
+        let agent = Agent::new(config, None);
+
+        let response = agent
+            .run("Keep going", Vec::new(), RunOptions::default())
+            .await
+            .unwrap();
+
+        assert!(response.truncated);
+        assert_eq!(response.finish_reason, "step_limit");
+        assert_eq!(upstream.requests().len(), 2);
+        let types: Vec<&StepType> = response.steps.iter().map(|s| &s.step_type).collect();
+        assert_eq!(
+            types,
+            [
+                &StepType::ToolCall,
+                &StepType::ToolResult,
+                &StepType::ToolCall,
+                &StepType::FinalAnswer,
+            ]
+        );
+        assert_eq!(response.steps[3].content, response.final_answer);
+        assert_eq!(agent.metrics.snapshot().runs_step_limit, 1);
+    }
+
+    #[test]
+    fn malformed_tool_schemas_are_normalized() {
+        let normalized = |schema: Value| Agent::normalize_tool_schema("tool", schema);
+
+        assert_eq!(
+            normalized(json!({"properties": {"q": {"type": "string"}}})),
+            json!({"type": "object", "properties": {"q": {"type": "string"}}})
+        );
+        assert_eq!(
+            normalized(json!({"type": "object", "properties": null})),
+            json!({"type": "object", "properties": {}})
+        );
+        for schema in [json!(null), json!(true), json!("object")] {
+            assert_eq!(
+                normalized(schema),
+                json!({"type": "object", "properties": {}})
+            );
         }
+        let valid = json!({
+            "type": "object",
+            "properties": {"q": {"type": "string"}},
+            "required": ["q"]
+        });
+        assert_eq!(normalized(valid.clone()), valid);
     }
 }
@@ -0,0 +1,149 @@
+use futures::future::BoxFuture;
+use serde::Deserialize;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+use super::{Agent, AgentResponse, AgentStep, RunOptions, StepType};
+use crate::error::AgentError;
+use crate::models::{GenerationParams, Message, UsageInfo};
+
+const PLAN_PROMPT: &str = "Before doing anything, write a short numbered plan for this request: \
+the steps you will take and the tools you expect to use. Reply with the plan only.";
+
+const EXECUTE_PROMPT: &str = "Carry out your plan step by step, using tools where needed. \
+When every step is done, answer the original request from what you found.";
+
+/// The orchestration an agent run uses, chosen per request.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StrategyKind {
+    /// Reasoning and tool calls interleave until the model answers.
+    #[default]
+    React,
+    /// The model writes a plan first, then carries it out with tools and
+    /// answers from the results.
+    PlanExecute,
+}
+
+impl StrategyKind {
+    pub(super) fn strategy(self) -> &'static dyn AgentStrategy {
+        match self {
+            Self::React => &ReactStrategy,
+            Self::PlanExecute => &PlanExecuteStrategy,
+        }
+    }
+}
+
+/// Drives the model and tools through one agent run.
+pub trait AgentStrategy: Send + Sync {
+    fn run<'a>(
+        &'a self,
+        agent: &'a Agent,
+        user_message: &'a str,
+        conversation_history: Vec<Message>,
+        options: RunOptions,
+        steps_tx: Option<mpsc::Sender<AgentStep>>,
+        stream_deltas: bool,
+    ) -> BoxFuture<'a, Result<AgentResponse, AgentError>>;
+}
+
+pub struct ReactStrategy;
+
+impl AgentStrategy for ReactStrategy {
+    fn run<'a>(
+        &'a self,
+        agent: &'a Agent,
+        user_message: &'a str,
+        conversation_history: Vec<Message>,
+        options: RunOptions,
+        steps_tx: Option<mpsc::Sender<AgentStep>>,
+        stream_deltas: bool,
+    ) -> BoxFuture<'a, Result<AgentResponse, AgentError>> {
+        Box::pin(async move {
+            let steps = agent.step_log(steps_tx, stream_deltas);
+            agent
+                .run_loop(user_message, conversation_history, options, steps)
+                .await
+        })
+    }
+}
+
+/// Asks for a plan without offering tools, records it as a `plan` step,
+/// then runs the ReAct loop with the plan in the conversation. The loop's
+/// final answer is the synthesis of what the plan's steps found.
+pub struct PlanExecuteStrategy;
+
+impl AgentStrategy for PlanExecuteStrategy {
+    fn run<'a>(
+        &'a self,
+        agent: &'a Agent,
+        user_message: &'a str,
+        conversation_history: Vec<Message>,
+        options: RunOptions,
+        steps_tx: Option<mpsc::Sender<AgentStep>>,
+        stream_deltas: bool,
+    ) -> BoxFuture<'a, Result<AgentResponse, AgentError>> {
+        Box::pin(async move {
+            let mut steps = agent.step_log(steps_tx, stream_deltas);
+            let system_prompt = options
+                .system_prompt
+                .clone()
+                .unwrap_or_else(|| agent.config.system_prompt.clone());
+            let model = options
+                .model
+                .clone()
+                .unwrap_or_else(|| agent.config.default_agent_model.clone());
+
+            let mut messages = vec![Message::system(&system_prompt)];
+            messages.extend(conversation_history.iter().cloned());
+            messages.push(Message::user(format!(
+                "{}\n\n{}",
+                user_message, PLAN_PROMPT
+            )));
+
+            // The plan is prose even when the answer must match a format.
+            let plan_options = RunOptions {
+                generation: GenerationParams {
+                    response_format: None,
+                    ..options.generation.clone()
+                },
+                retry_budget: options.retry_budget.clone(),
+                ..RunOptions::default()
+            };
+            info!("Asking {} for a plan", model);
+            let plan_call = agent.call_model(&steps, messages, &model, None, None, &plan_options);
+            let response = tokio::select! {
+                _ = options.cancel.cancelled() => return Err(AgentError::Cancelled),
+                response = plan_call => response?,
+            };
+            let plan = response
+                .choices
+                .first()
+                .and_then(|choice| choice.message.content.as_deref())
+                .map(str::trim)
+                .unwrap_or_default()
+                .to_string();
+
+            let (prompt, history) = if plan.is_empty() {
+                warn!("Model returned an empty plan; running without one");
+                (user_message, conversation_history)
+            } else {
+                steps.push(AgentStep::new(StepType::Plan, &plan)).await;
+                let mut history = conversation_history;
+                history.push(Message::user(user_message));
+                history.push(Message::assistant(plan));
+                (EXECUTE_PROMPT, history)
+            };
+
+            let mut result = agent.run_loop(prompt, history, options, steps).await?;
+            result.generation_ids.insert(0, response.id);
+            if let Some(ref plan_usage) = response.usage {
+                result
+                    .usage
+                    .get_or_insert_with(UsageInfo::default)
+                    .add(plan_usage);
+            }
+            Ok(result)
+        })
+    }
+}
@@ -1,27 +1,128 @@
+use std::collections::BTreeMap;
 use std::env;
 
 const DEFAULT_SYSTEM_PROMPT: &str = "\
 You are a helpful AI assistant with access to MCP tools. \
 Use tools when needed, explain your reasoning, and provide helpful responses.";
 
+const DEFAULT_EMPTY_ANSWER_FALLBACK: &str = "The model did not return an answer.";
+
+const DEFAULT_MAX_RESPONSE_BYTES: usize = 10 * 1024 * 1024;
+
+const DEFAULT_RUN_RETRY_BUDGET: usize = 3;
+
+const DEFAULT_OPENROUTER_MAX_RETRIES: u32 = 2;
+
+const DEFAULT_OPENROUTER_RETRY_BASE_MS: u64 = 250;
+
+const DEFAULT_MAX_TOOL_ARGUMENT_BYTES: usize = 256 * 1024;
+
+const DEFAULT_SEARCH_RESULT_COUNT: usize = 5;
+
+const DEFAULT_MAX_PARALLEL_TOOLS: usize = 4;
+
+const DEFAULT_AGENT_TEMPERATURE: f32 = 0.7;
+
+const DEFAULT_AGENT_MAX_TOKENS: u32 = 4096;
+
+const DEFAULT_SHUTDOWN_DRAIN_TIMEOUT_MS: u64 = 10_000;
+
 #[derive(Clone, Debug)]
 pub struct Config {
     pub openrouter_api_key: String,
     pub openrouter_base_url: String,
     pub default_model: String,
+    pub default_chat_model: String,
+    pub default_agent_model: String,
+    pub agent_temperature: f32,
+    pub agent_max_tokens: u32,
+    pub model_fallbacks: Vec<String>,
     pub server_host: String,
     pub server_port: u16,
     pub system_prompt: String,
+    pub max_response_bytes: usize,
+    pub preflight_check: bool,
+    pub fail_on_bad_key: bool,
+    pub empty_answer_fallback: String,
+    pub retry_empty_answer: bool,
+    pub tools_config_path: String,
+    pub sanitize_stream_content: bool,
+    pub destructive_tools: Vec<String>,
+    pub mcp_tool_prefix: String,
+    pub tool_result_chunk_size: Option<usize>,
+    pub step_content_max_chars: Option<usize>,
+    pub max_steps: Option<usize>,
+    pub abort_on_tool_error: bool,
+    pub max_parallel_tools: usize,
+    pub max_tool_argument_bytes: usize,
+    pub session_tool_cache_ttl_secs: u64,
+    pub response_headers: BTreeMap<String, String>,
+    pub run_retry_budget: usize,
+    pub agent_run_timeout_secs: Option<u64>,
+    /// How long shutdown waits for open requests before stopping anyway.
+    pub shutdown_drain_timeout_ms: u64,
+    pub openrouter_max_retries: u32,
+    pub openrouter_retry_base_ms: u64,
+    pub iteration_delay_ms: u64,
+    pub iteration_jitter_ms: u64,
+    pub selftest_token: Option<String>,
+    pub selftest_model: String,
+    pub prompt_audit_token: Option<String>,
+    /// Bearer tokens accepted on `/v1/*` routes. Empty leaves them open.
+    pub api_tokens: Vec<String>,
+    /// Origins allowed to call the API from a browser; empty or `*` allows any.
+    pub cors_allowed_origins: Vec<String>,
+    pub reasoning_tags: Vec<String>,
+    pub search_provider: Option<String>,
+    pub search_api_key: Option<String>,
+    pub search_url: Option<String>,
+    pub search_result_count: usize,
+    pub notes_db_path: String,
 }
 
 impl Config {
+    /// Worker thread count for the Tokio runtime. Read separately from
+    /// `from_env` because the runtime has to exist before config is loaded.
+    pub fn tokio_worker_threads() -> Option<usize> {
+        env::var("TOKIO_WORKER_THREADS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|&n| n > 0)
+    }
+
+    /// `User-Agent` for every outbound HTTP client. Read separately from
+    /// `from_env` so clients built without a `Config`, like the MCP one,
+    /// can use it too.
+    pub fn user_agent() -> String {
+        env::var("USER_AGENT")
+            .ok()
+            .filter(|v| !v.is_empty())
+            .unwrap_or_else(|| format!("llm-agent/{}", env!("CARGO_PKG_VERSION")))
+    }
+
     pub fn from_env() -> Result<Self, env::VarError> {
+        let default_model =
+            env::var("DEFAULT_MODEL").unwrap_or_else(|_| "anthropic/claude-3.5-sonnet".to_string());
+
         Ok(Self {
             openrouter_api_key: env::var("OPENROUTER_API_KEY")?,
             openrouter_base_url: env::var("OPENROUTER_BASE_URL")
                 .unwrap_or_else(|_| "https://openrouter.ai/api/v1".to_string()),
-            default_model: env::var("DEFAULT_MODEL")
-                .unwrap_or_else(|_| "anthropic/claude-3.5-sonnet".to_string()),
+            default_chat_model: env::var("DEFAULT_CHAT_MODEL")
+                .unwrap_or_else(|_| default_model.clone()),
+            default_agent_model: env::var("DEFAULT_AGENT_MODEL")
+                .unwrap_or_else(|_| default_model.clone()),
+            agent_temperature: env::var("AGENT_TEMPERATURE")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_AGENT_TEMPERATURE),
+            agent_max_tokens: env::var("AGENT_MAX_TOKENS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|&n| n > 0)
+                .unwrap_or(DEFAULT_AGENT_MAX_TOKENS),
+            model_fallbacks: env_list("MODEL_FALLBACKS"),
+            default_model,
             server_host: env::var("SERVER_HOST").unwrap_or_else(|_| "0.0.0.0".to_string()),
             server_port: env::var("SERVER_PORT")
                 .unwrap_or_else(|_| "3000".to_string())
@@ -29,6 +130,114 @@ impl Config {
                 .unwrap_or(3000),
             system_prompt: env::var("SYSTEM_PROMPT")
                 .unwrap_or_else(|_| DEFAULT_SYSTEM_PROMPT.to_string()),
+            max_response_bytes: env::var("MAX_RESPONSE_BYTES")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_MAX_RESPONSE_BYTES),
+            preflight_check: env_flag("PREFLIGHT_CHECK"),
+            fail_on_bad_key: env_flag("FAIL_ON_BAD_KEY"),
+            empty_answer_fallback: env::var("EMPTY_ANSWER_FALLBACK")
+                .unwrap_or_else(|_| DEFAULT_EMPTY_ANSWER_FALLBACK.to_string()),
+            retry_empty_answer: env_flag("RETRY_EMPTY_ANSWER"),
+            tools_config_path: env::var("TOOLS_CONFIG_PATH")
+                .unwrap_or_else(|_| "tools_config.json".to_string()),
+            sanitize_stream_content: env_flag("SANITIZE_STREAM_CONTENT"),
+            destructive_tools: env_list("DESTRUCTIVE_TOOLS"),
+            mcp_tool_prefix: env::var("MCP_TOOL_PREFIX").unwrap_or_else(|_| "mcp_".to_string()),
+            tool_result_chunk_size: env::var("TOOL_RESULT_CHUNK_SIZE")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|&n| n > 0),
+            step_content_max_chars: env::var("STEP_CONTENT_MAX_CHARS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|&n| n > 0),
+            max_steps: env::var("MAX_STEPS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|&n| n > 0),
+            abort_on_tool_error: env_flag("ABORT_ON_TOOL_ERROR"),
+            max_parallel_tools: env::var("MAX_PARALLEL_TOOLS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|&n| n > 0)
+                .unwrap_or(DEFAULT_MAX_PARALLEL_TOOLS),
+            max_tool_argument_bytes: env::var("MAX_TOOL_ARGUMENT_BYTES")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_MAX_TOOL_ARGUMENT_BYTES),
+            session_tool_cache_ttl_secs: env::var("SESSION_TOOL_CACHE_TTL_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(0),
+            response_headers: env::var("RESPONSE_HEADERS")
+                .ok()
+                .and_then(|v| serde_json::from_str(&v).ok())
+                .unwrap_or_default(),
+            run_retry_budget: env::var("RUN_RETRY_BUDGET")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_RUN_RETRY_BUDGET),
+            agent_run_timeout_secs: env::var("AGENT_RUN_TIMEOUT")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|&n| n > 0),
+            shutdown_drain_timeout_ms: env::var("SHUTDOWN_DRAIN_TIMEOUT_MS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_SHUTDOWN_DRAIN_TIMEOUT_MS),
+            openrouter_max_retries: env::var("OPENROUTER_MAX_RETRIES")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_OPENROUTER_MAX_RETRIES),
+            openrouter_retry_base_ms: env::var("OPENROUTER_RETRY_BASE_MS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_OPENROUTER_RETRY_BASE_MS),
+            iteration_delay_ms: env::var("ITERATION_DELAY_MS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(0),
+            iteration_jitter_ms: env::var("ITERATION_JITTER_MS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(0),
+            selftest_token: env::var("SELFTEST_TOKEN").ok().filter(|t| !t.is_empty()),
+            selftest_model: env::var("SELFTEST_MODEL")
+                .unwrap_or_else(|_| "openai/gpt-4o-mini".to_string()),
+            prompt_audit_token: env::var("PROMPT_AUDIT_TOKEN")
+                .ok()
+                .filter(|t| !t.is_empty()),
+            api_tokens: env_list("API_TOKENS"),
+            cors_allowed_origins: env_list("CORS_ALLOWED_ORIGINS"),
+            reasoning_tags: env_list("REASONING_TAGS"),
+            search_provider: env::var("SEARCH_PROVIDER").ok().filter(|v| !v.is_empty()),
+            search_api_key: env::var("SEARCH_API_KEY").ok().filter(|v| !v.is_empty()),
+            search_url: env::var("SEARCH_URL").ok().filter(|v| !v.is_empty()),
+            search_result_count: env::var("SEARCH_RESULT_COUNT")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|&n| n > 0)
+                .unwrap_or(DEFAULT_SEARCH_RESULT_COUNT),
+            notes_db_path: env::var("NOTES_DB_PATH").unwrap_or_else(|_| "notes.db".to_string()),
         })
     }
 }
+
+/// Comma-separated list; unset or empty means an empty list.
+fn env_list(name: &str) -> Vec<String> {
+    env::var(name)
+        .map(|v| {
+            v.split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn env_flag(name: &str) -> bool {
+    env::var(name)
+        .map(|v| matches!(v.to_ascii_lowercase().as_str(), "1" | "true" | "yes"))
+        .unwrap_or(false)
+}
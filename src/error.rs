@@ -3,19 +3,22 @@ use axum::{
     response::{IntoResponse, Response},
     Json,
 };
-use serde_json::json;
+use serde_json::{json, Value};
 use thiserror::Error;
 
+use crate::agent::AgentStep;
+
 #[derive(Error, Debug)]
 pub enum AgentError {
-    #[error("Configuration error: {0}")]
-    ConfigError(String),
-
     #[error("Request failed: {0}")]
     RequestFailed(String),
 
     #[error("API error (status {status}): {message}")]
-    ApiError { status: u16, message: String },
+    ApiError {
+        status: u16,
+        message: String,
+        metadata: Option<Value>,
+    },
 
     #[error("Parse error: {0}")]
     ParseError(String),
@@ -31,18 +34,48 @@ pub enum AgentError {
 
     #[error("Tool error: {0}")]
     ToolError(String),
+
+    #[error("Response exceeded the {limit} byte limit")]
+    ResponseTooLarge { limit: usize },
+
+    #[error("Retry budget of {budget} exhausted: {last_error}")]
+    RetryBudgetExhausted { budget: usize, last_error: String },
+
+    #[error("Run cancelled")]
+    Cancelled,
+
+    #[error("Run exceeded the {limit_secs}s timeout")]
+    RunTimedOut {
+        limit_secs: u64,
+        steps: Vec<AgentStep>,
+    },
+
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+}
+
+impl AgentError {
+    /// Errors worth retrying: network failures, rate limits and upstream
+    /// server errors.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            AgentError::RequestFailed(_)
+                | AgentError::ApiError {
+                    status: 429 | 500..=599,
+                    ..
+                }
+        )
+    }
 }
 
 impl IntoResponse for AgentError {
     fn into_response(self) -> Response {
         let (status, code, message) = match &self {
-            AgentError::ConfigError(msg) => {
-                (StatusCode::INTERNAL_SERVER_ERROR, "CONFIG_ERROR", msg.clone())
-            }
             AgentError::RequestFailed(msg) => {
                 (StatusCode::BAD_GATEWAY, "REQUEST_FAILED", msg.clone())
             }
-            AgentError::ApiError { status, message } => {
+            AgentError::ApiError { status, message, .. } => {
                 let status_code = StatusCode::from_u16(*status).unwrap_or(StatusCode::BAD_GATEWAY);
                 (status_code, "API_ERROR", message.clone())
             }
@@ -61,13 +94,40 @@ impl IntoResponse for AgentError {
             AgentError::ToolError(msg) => {
                 (StatusCode::INTERNAL_SERVER_ERROR, "TOOL_ERROR", msg.clone())
             }
+            AgentError::ResponseTooLarge { .. } => {
+                (StatusCode::BAD_GATEWAY, "RESPONSE_TOO_LARGE", self.to_string())
+            }
+            AgentError::RetryBudgetExhausted { .. } => {
+                (StatusCode::BAD_GATEWAY, "RETRY_BUDGET_EXHAUSTED", self.to_string())
+            }
+            AgentError::Unauthorized(msg) => {
+                (StatusCode::UNAUTHORIZED, "UNAUTHORIZED", msg.clone())
+            }
+            AgentError::Cancelled => {
+                // 499 Client Closed Request, as used by nginx.
+                let status = StatusCode::from_u16(499).unwrap_or(StatusCode::REQUEST_TIMEOUT);
+                (status, "CANCELLED", self.to_string())
+            }
+            AgentError::RunTimedOut { .. } => {
+                (StatusCode::GATEWAY_TIMEOUT, "RUN_TIMEOUT", self.to_string())
+            }
         };
 
-        let body = Json(json!({
+        let mut body = json!({
             "error": message,
             "code": code,
-        }));
+        });
+        match self {
+            AgentError::ApiError {
+                metadata: Some(metadata),
+                ..
+            } => body["metadata"] = metadata,
+            AgentError::RunTimedOut { steps, .. } => {
+                body["steps"] = serde_json::to_value(steps).unwrap_or_default();
+            }
+            _ => {}
+        }
 
-        (status, body).into_response()
+        (status, Json(body)).into_response()
     }
 }
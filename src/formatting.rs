@@ -0,0 +1,121 @@
+use regex::Regex;
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ResponseFormatHint {
+    Markdown,
+    Plain,
+}
+
+impl ResponseFormatHint {
+    fn instruction(self) -> &'static str {
+        match self {
+            Self::Markdown => "Format your final answer using Markdown.",
+            Self::Plain => {
+                "Format your final answer as plain text. Do not use Markdown syntax such as \
+                 headers, bold, lists with asterisks, or code fences."
+            }
+        }
+    }
+
+    pub fn apply_to_prompt(self, system_prompt: &str) -> String {
+        format!("{}\n\n{}", system_prompt, self.instruction())
+    }
+
+    /// Models don't always follow the plain-text instruction, so plain answers
+    /// also get a markdown-to-text pass.
+    pub fn apply_to_answer(self, answer: String) -> String {
+        match self {
+            Self::Markdown => answer,
+            Self::Plain => strip_markdown(&answer),
+        }
+    }
+}
+
+/// Removes control characters other than newline, which some models emit in
+/// streamed output and which break client-side rendering.
+pub fn strip_control_chars(text: &str) -> String {
+    text.chars()
+        .filter(|c| *c == '\n' || !c.is_control())
+        .collect()
+}
+
+/// A BCP 47-style language tag such as `ru-RU`. Only letters, digits, `-`
+/// and `_` are accepted, since the value is inserted into the system prompt.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Locale(String);
+
+impl TryFrom<String> for Locale {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let valid = !value.is_empty()
+            && value.len() <= 35
+            && value
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if valid {
+            Ok(Self(value))
+        } else {
+            Err(format!("Invalid locale: {:?}", value))
+        }
+    }
+}
+
+impl Locale {
+    pub fn instruction(&self) -> String {
+        format!(
+            "Respond in the language and conventions of the {} locale.",
+            self.0
+        )
+    }
+
+    pub fn apply_to_prompt(&self, system_prompt: &str) -> String {
+        format!("{}\n\n{}", system_prompt, self.instruction())
+    }
+}
+
+/// Moves `<tag>...</tag>` blocks for each of `tags` out of `answer`,
+/// returning the cleaned answer and the removed contents, if any.
+pub fn split_reasoning(answer: &str, tags: &[String]) -> (String, Option<String>) {
+    let mut answer = answer.to_string();
+    let mut reasoning = Vec::new();
+
+    for tag in tags {
+        let tag = regex::escape(tag);
+        let re = Regex::new(&format!(r"(?s)<{tag}>(.*?)</{tag}>")).unwrap();
+        reasoning.extend(re.captures_iter(&answer).map(|c| c[1].trim().to_string()));
+        answer = re.replace_all(&answer, "").into_owned();
+    }
+
+    if reasoning.is_empty() {
+        (answer, None)
+    } else {
+        (answer.trim().to_string(), Some(reasoning.join("\n\n")))
+    }
+}
+
+pub fn strip_markdown(text: &str) -> String {
+    let rules = [
+        (r"(?m)^\s*```.*$\n?", ""),
+        (r"(?m)^\s{0,3}#{1,6}\s+", ""),
+        (r"(?m)^\s{0,3}>\s?", ""),
+        (r"(?m)^\s{0,3}([-*_]\s*){3,}$", ""),
+        (r"!\[([^\]]*)\]\([^)]*\)", "$1"),
+        (r"\[([^\]]+)\]\(([^)]+)\)", "$1 ($2)"),
+        (r"\*\*(.+?)\*\*", "$1"),
+        (r"__(.+?)__", "$1"),
+        (r"\*([^*\n]+)\*", "$1"),
+        (r"`([^`\n]+)`", "$1"),
+    ];
+
+    let mut result = text.to_string();
+    for (pattern, replacement) in rules {
+        let re = Regex::new(pattern).unwrap();
+        result = re.replace_all(&result, replacement).into_owned();
+    }
+
+    result.trim().to_string()
+}
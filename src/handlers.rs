@@ -1,77 +1,115 @@
 use axum::{
-    extract::State,
+    extract::{Path, State},
+    http::{header, HeaderMap, HeaderName, StatusCode},
     response::{
         sse::{Event, Sse},
-        IntoResponse,
+        IntoResponse, Response,
     },
     Json,
 };
+use futures::future::join_all;
 use futures::stream::Stream;
 use serde_json::Value;
+use std::collections::HashMap;
 use std::convert::Infallible;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
 use tokio_stream::wrappers::ReceiverStream;
 use tokio_stream::StreamExt;
+use tokio_util::sync::CancellationToken;
 use tracing::info;
 use uuid::Uuid;
 
-use crate::agent::Agent;
+use crate::agent::{Agent, AgentStep, PendingToolCall, RunOptions, StepType, StrategyKind};
 use crate::config::Config;
 use crate::error::AgentError;
-use crate::mcp::McpManager;
-use crate::models::{AgentRequest, AgentResponse, Message, UsageInfo};
+use crate::formatting::{strip_control_chars, Locale, ResponseFormatHint};
+use crate::mcp::{full_tool_name, McpManager};
+use crate::middleware::token_matches;
+use crate::models::{
+    AgentRequest, AgentResponse, GenerationParams, Message, ToolCallAssembler, ToolChoice,
+    UsageInfo,
+};
 use crate::openrouter::OpenRouterClient;
+use crate::partial_json::parse_partial;
 
 pub struct AppState {
+    pub config: Config,
     pub client: OpenRouterClient,
     pub agent: Agent,
     pub mcp: Option<Arc<McpManager>>,
+    /// Cancelled when the server starts shutting down. Open SSE streams end
+    /// on it, since graceful shutdown otherwise waits for them to finish.
+    pub shutdown: CancellationToken,
 }
 
 impl AppState {
     pub fn new(config: Config, mcp: Option<Arc<McpManager>>) -> Arc<Self> {
         Arc::new(Self {
             client: OpenRouterClient::new(config.clone()),
-            agent: Agent::new(config, mcp.clone()),
+            agent: Agent::new(config.clone(), mcp.clone()),
+            config,
             mcp,
+            shutdown: CancellationToken::new(),
         })
     }
 }
 
 pub async fn health_check(State(state): State<Arc<AppState>>) -> impl IntoResponse {
-    let mcp_connected = if let Some(ref mcp) = state.mcp {
-        mcp.connected_servers().await
+    let (mcp_connected, mcp_resources) = if let Some(ref mcp) = state.mcp {
+        (
+            mcp.connected_servers().await,
+            mcp.list_resources().await.len(),
+        )
     } else {
-        vec![]
+        (vec![], 0)
     };
 
     Json(serde_json::json!({
         "status": "ok",
         "service": "llm-agent",
         "capabilities": ["chat", "agent", "tools", "mcp"],
-        "mcp_servers": mcp_connected
+        "mcp_servers": mcp_connected,
+        "mcp_resources": mcp_resources
     }))
 }
 
 pub async fn chat_completion(
     State(state): State<Arc<AppState>>,
     Json(request): Json<AgentRequest>,
-) -> Result<Json<AgentResponse>, AgentError> {
+) -> Result<Response, AgentError> {
+    if request.stream == Some(true) {
+        return chat_completion_stream(State(state), Json(request))
+            .await
+            .map(IntoResponse::into_response);
+    }
+
     info!("Received chat completion request");
 
     let mut messages = request.messages;
 
-    if let Some(system_prompt) = request.system_prompt {
+    let system_prompt = match (request.system_prompt, &request.locale) {
+        (Some(prompt), Some(locale)) => Some(locale.apply_to_prompt(&prompt)),
+        (None, Some(locale)) => Some(locale.instruction()),
+        (prompt, None) => prompt,
+    };
+    if let Some(system_prompt) = system_prompt {
         messages.insert(0, Message::system(system_prompt));
     }
 
+    let response_format = request
+        .generation
+        .response_format
+        .clone()
+        .filter(|_| request.validate_response_format);
+
     let response = state
         .client
         .chat_completion(
             messages,
-            request.model,
-            request.temperature,
-            request.max_tokens,
+            chat_model(&state.config, request.model),
+            request.generation,
         )
         .await?;
 
@@ -81,6 +119,9 @@ pub async fn chat_completion(
         .ok_or_else(|| AgentError::ParseError("No choices in response".to_string()))?;
 
     let content = choice.message.content.clone().unwrap_or_default();
+    if let Some(format) = response_format {
+        format.validate(&content)?;
+    }
 
     let usage = response.usage.map(|u| UsageInfo {
         prompt_tokens: u.prompt_tokens,
@@ -94,18 +135,121 @@ pub async fn chat_completion(
         model: response.model,
         usage,
         finish_reason: choice.finish_reason.clone(),
+    })
+    .into_response())
+}
+
+const MAX_ENSEMBLE_MODELS: usize = 8;
+
+/// Sends the same prompt to several models at once and returns every
+/// answer, optionally picking one of them.
+pub async fn chat_ensemble(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<EnsembleRequest>,
+) -> Result<Json<EnsembleResponse>, AgentError> {
+    if request.models.is_empty() {
+        return Err(AgentError::InvalidRequest(
+            "models must list at least one model".to_string(),
+        ));
+    }
+    if request.models.len() > MAX_ENSEMBLE_MODELS {
+        return Err(AgentError::InvalidRequest(format!(
+            "At most {} models can be ensembled",
+            MAX_ENSEMBLE_MODELS
+        )));
+    }
+
+    info!(
+        "Received ensemble request for {} models",
+        request.models.len()
+    );
+
+    let mut messages = request.messages;
+    if let Some(system_prompt) = request.system_prompt {
+        messages.insert(0, Message::system(system_prompt));
+    }
+
+    let results = join_all(request.models.iter().map(|entry| {
+        state.client.chat_completion(
+            messages.clone(),
+            Some(entry.model().to_string()),
+            GenerationParams {
+                temperature: request.temperature,
+                max_tokens: request.max_tokens,
+                ..GenerationParams::default()
+            },
+        )
+    }))
+    .await;
+
+    let answers: Vec<EnsembleAnswer> = request
+        .models
+        .iter()
+        .zip(results)
+        .map(|(entry, result)| match result {
+            Ok(response) => EnsembleAnswer {
+                model: entry.model().to_string(),
+                weight: entry.weight(),
+                generation_id: Some(response.id),
+                content: response
+                    .choices
+                    .into_iter()
+                    .next()
+                    .and_then(|choice| choice.message.content),
+                usage: response.usage.map(|u| UsageInfo {
+                    prompt_tokens: u.prompt_tokens,
+                    completion_tokens: u.completion_tokens,
+                    total_tokens: u.total_tokens,
+                }),
+                error: None,
+            },
+            Err(e) => EnsembleAnswer {
+                model: entry.model().to_string(),
+                weight: entry.weight(),
+                generation_id: None,
+                content: None,
+                usage: None,
+                error: Some(e.to_string()),
+            },
+        })
+        .collect();
+
+    if answers.iter().all(|a| a.error.is_some()) {
+        return Err(AgentError::RequestFailed(format!(
+            "All {} ensemble models failed",
+            answers.len()
+        )));
+    }
+
+    let selected = request
+        .strategy
+        .unwrap_or_default()
+        .select(&answers)
+        .map(|i| answers[i].model.clone());
+
+    Ok(Json(EnsembleResponse {
+        id: Uuid::new_v4().to_string(),
+        answers,
+        selected,
     }))
 }
 
 pub async fn chat_completion_stream(
     State(state): State<Arc<AppState>>,
     Json(request): Json<AgentRequest>,
-) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, AgentError> {
+) -> Result<impl IntoResponse, AgentError> {
     info!("Received streaming chat completion request");
 
+    let include_timing = request.include_timing;
+    let partial_json = request.partial_json;
     let mut messages = request.messages;
 
-    if let Some(system_prompt) = request.system_prompt {
+    let system_prompt = match (request.system_prompt, &request.locale) {
+        (Some(prompt), Some(locale)) => Some(locale.apply_to_prompt(&prompt)),
+        (None, Some(locale)) => Some(locale.instruction()),
+        (prompt, None) => prompt,
+    };
+    if let Some(system_prompt) = system_prompt {
         messages.insert(0, Message::system(system_prompt));
     }
 
@@ -113,38 +257,91 @@ pub async fn chat_completion_stream(
         .client
         .chat_completion_stream(
             messages,
-            request.model,
-            request.temperature,
-            request.max_tokens,
+            chat_model(&state.config, request.model),
+            request.generation,
+            request.tools,
         )
         .await?;
 
-    let stream = ReceiverStream::new(rx).map(|result| {
-        let event = match result {
+    let sanitize = state.config.sanitize_stream_content;
+    let started = Instant::now();
+    let mut json_buffer = String::new();
+    let mut tool_calls = ToolCallAssembler::default();
+    let stream = ReceiverStream::new(rx).map(move |result| {
+        let mut events = Vec::new();
+        match result {
             Ok(chunk) => {
-                let content = chunk
+                if let Some(choice) = chunk.choices.first() {
+                    if let Some(ref deltas) = choice.delta.tool_calls {
+                        tool_calls.push(deltas);
+                    }
+                    let completed = match choice.finish_reason {
+                        Some(_) => tool_calls.finish(),
+                        None => Vec::new(),
+                    };
+                    events.extend(completed.iter().map(|call| {
+                        Event::default()
+                            .event("tool_call")
+                            .data(serde_json::to_string(call).unwrap_or_default())
+                    }));
+                }
+
+                let mut content = chunk
                     .choices
                     .first()
                     .and_then(|c| c.delta.content.clone())
                     .unwrap_or_default();
+                if sanitize {
+                    content = strip_control_chars(&content);
+                }
 
-                Event::default().data(
-                    serde_json::json!({
-                        "id": chunk.id,
-                        "content": content,
-                        "finish_reason": chunk.choices.first().and_then(|c| c.finish_reason.clone()),
-                    })
-                    .to_string(),
-                )
+                let mut payload = serde_json::json!({
+                    "id": chunk.id,
+                    "content": content,
+                    "finish_reason": chunk.choices.first().and_then(|c| c.finish_reason.clone()),
+                });
+                if include_timing {
+                    payload["t_ms"] = serde_json::json!(started.elapsed().as_millis() as u64);
+                }
+                if partial_json {
+                    json_buffer.push_str(&content);
+                    if let Some(partial) = parse_partial(&json_buffer) {
+                        payload["partial"] = partial;
+                    }
+                }
+
+                events.push(Event::default().data(payload.to_string()));
             }
-            Err(e) => Event::default()
-                .event("error")
-                .data(serde_json::json!({ "error": e.to_string() }).to_string()),
-        };
-        Ok::<_, Infallible>(event)
+            Err(e) => events.push(
+                Event::default()
+                    .event("error")
+                    .data(serde_json::json!({ "error": e.to_string() }).to_string()),
+            ),
+        }
+        futures::stream::iter(events.into_iter().map(Ok::<_, Infallible>))
     });
 
-    Ok(Sse::new(stream))
+    Ok(sse_response(
+        futures::StreamExt::flatten(stream),
+        &state.shutdown,
+    ))
+}
+
+/// Wraps an event stream with the headers proxies need to pass SSE through
+/// unbuffered and uncached. The stream ends early when `shutdown` is
+/// cancelled.
+fn sse_response(
+    stream: impl Stream<Item = Result<Event, Infallible>> + Send + 'static,
+    shutdown: &CancellationToken,
+) -> impl IntoResponse {
+    let stream = futures::StreamExt::take_until(stream, shutdown.clone().cancelled_owned());
+    (
+        [
+            (header::CACHE_CONTROL, "no-cache"),
+            (HeaderName::from_static("x-accel-buffering"), "no"),
+        ],
+        Sse::new(stream),
+    )
 }
 
 pub async fn list_models(
@@ -155,6 +352,116 @@ pub async fn list_models(
     Ok(Json(models))
 }
 
+pub async fn get_generation(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Json<serde_json::Value>, AgentError> {
+    info!("Fetching generation stats for {}", id);
+    let generation = state.client.get_generation(&id).await?;
+    Ok(Json(generation))
+}
+
+pub async fn get_metrics(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    Json(serde_json::json!({
+        "agent": state.agent.metrics().snapshot()
+    }))
+}
+
+const SELFTEST_PROMPT: &str = "Use the encode_decode tool to base64-encode the text 'selftest'. \
+Reply with only the encoded value.";
+const SELFTEST_EXPECTED: &str = "c2VsZnRlc3Q=";
+
+/// Header that carries a feature token, since `Authorization` holds the API
+/// token when `API_TOKENS` is set.
+pub const FEATURE_TOKEN_HEADER: &str = "x-feature-token";
+
+/// Checks an operator-configured feature token, read from `X-Feature-Token`
+/// or else the bearer token. This is on top of the API token check, never
+/// instead of it. A feature gated this way is disabled while its token is
+/// unset.
+fn require_feature_token(
+    headers: &HeaderMap,
+    token: Option<&str>,
+    feature: &str,
+) -> Result<(), AgentError> {
+    let Some(token) = token else {
+        return Err(AgentError::Unauthorized(format!("{} is disabled", feature)));
+    };
+    let provided = headers
+        .get(FEATURE_TOKEN_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .or_else(|| {
+            headers
+                .get(header::AUTHORIZATION)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.strip_prefix("Bearer "))
+        });
+    if !provided.is_some_and(|provided| token_matches(provided, token)) {
+        return Err(AgentError::Unauthorized(format!(
+            "Invalid {} token",
+            feature.to_lowercase()
+        )));
+    }
+    Ok(())
+}
+
+/// Runs a fixed scenario through the agent (model call, built-in tool call,
+/// final answer) to validate a deployment. Disabled unless `SELFTEST_TOKEN`
+/// is set, and then requires it as a feature token.
+pub async fn selftest(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, AgentError> {
+    require_feature_token(
+        &headers,
+        state.config.selftest_token.as_deref(),
+        "Self-test",
+    )?;
+
+    info!(
+        "Running self-test with model {}",
+        state.config.selftest_model
+    );
+    let started = Instant::now();
+    let options = RunOptions {
+        model: Some(state.config.selftest_model.clone()),
+        ..RunOptions::default()
+    };
+    let result = state.agent.run(SELFTEST_PROMPT, Vec::new(), options).await;
+    let duration_ms = started.elapsed().as_millis() as u64;
+
+    let (passed, mut body) = match result {
+        Ok(response) => {
+            let tool_used = response.steps.iter().any(|s| {
+                s.step_type == StepType::ToolResult
+                    && s.tool_name.as_deref() == Some("encode_decode")
+            });
+            let answer_correct = response.final_answer.contains(SELFTEST_EXPECTED);
+            (
+                tool_used && answer_correct,
+                serde_json::json!({
+                    "tool_used": tool_used,
+                    "answer_correct": answer_correct,
+                    "final_answer": response.final_answer,
+                    "iterations": response.iterations,
+                }),
+            )
+        }
+        Err(e) => (false, serde_json::json!({ "error": e.to_string() })),
+    };
+
+    body["passed"] = passed.into();
+    body["duration_ms"] = duration_ms.into();
+    body["model"] = state.config.selftest_model.clone().into();
+
+    let status = if passed {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    Ok((status, Json(body)))
+}
+
 pub async fn get_tools(State(state): State<Arc<AppState>>) -> impl IntoResponse {
     let tools = state.agent.get_tools().await;
     Json(serde_json::json!({
@@ -168,6 +475,12 @@ pub async fn get_tools(State(state): State<Arc<AppState>>) -> impl IntoResponse
     }))
 }
 
+/// The agent's tools as a bare OpenAI `tools` array, ready to paste into a
+/// chat completions request.
+pub async fn get_openai_tools(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    Json(state.agent.get_tools().await)
+}
+
 pub async fn get_mcp_tools(State(state): State<Arc<AppState>>) -> impl IntoResponse {
     if let Some(ref mcp) = state.mcp {
         let tools = mcp.get_all_tools().await;
@@ -177,7 +490,7 @@ pub async fn get_mcp_tools(State(state): State<Arc<AppState>>) -> impl IntoRespo
                 serde_json::json!({
                     "server": server,
                     "name": tool.name,
-                    "full_name": format!("{}_{}", server, tool.name),
+                    "full_name": full_tool_name(&server, &tool.name),
                     "description": tool.description,
                     "input_schema": tool.input_schema
                 })
@@ -218,6 +531,55 @@ pub async fn mcp_call_tool(
     })))
 }
 
+pub async fn get_mcp_resources(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    if let Some(ref mcp) = state.mcp {
+        let resources: Vec<_> = mcp
+            .list_resources()
+            .await
+            .into_iter()
+            .map(|(server, resource)| {
+                serde_json::json!({
+                    "server": server,
+                    "uri": resource.uri,
+                    "name": resource.name,
+                    "description": resource.description,
+                    "mime_type": resource.mime_type
+                })
+            })
+            .collect();
+
+        Json(serde_json::json!({
+            "mcp_enabled": true,
+            "resources": resources
+        }))
+    } else {
+        Json(serde_json::json!({
+            "mcp_enabled": false,
+            "resources": []
+        }))
+    }
+}
+
+pub async fn mcp_read_resource(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<McpResourceReadRequest>,
+) -> Result<Json<serde_json::Value>, AgentError> {
+    let mcp = state
+        .mcp
+        .as_ref()
+        .ok_or_else(|| AgentError::Internal("MCP not configured".to_string()))?;
+
+    let contents = mcp
+        .read_resource(&request.server_name, &request.uri)
+        .await
+        .map_err(|e| AgentError::Internal(format!("MCP resource read failed: {}", e)))?;
+
+    Ok(Json(serde_json::json!({
+        "success": true,
+        "contents": contents
+    })))
+}
+
 pub async fn get_mcp_servers(State(state): State<Arc<AppState>>) -> impl IntoResponse {
     if let Some(ref mcp) = state.mcp {
         let servers = mcp.get_servers_status().await;
@@ -283,9 +645,15 @@ pub async fn agent_chat(
 ) -> Result<Json<AgentChatResponse>, AgentError> {
     info!("Received agent chat request");
 
-    let system_prompt = request.system_prompt.unwrap_or_else(|| {
+    let mut system_prompt = request.system_prompt.unwrap_or_else(|| {
         "You are a helpful AI assistant. Be concise and helpful in your responses.".to_string()
     });
+    if let Some(hint) = request.response_format_hint {
+        system_prompt = hint.apply_to_prompt(&system_prompt);
+    }
+    if let Some(ref locale) = request.locale {
+        system_prompt = locale.apply_to_prompt(&system_prompt);
+    }
 
     let mut messages = vec![Message::system(&system_prompt)];
     messages.extend(request.conversation);
@@ -293,7 +661,15 @@ pub async fn agent_chat(
 
     let response = state
         .client
-        .chat_completion(messages, request.model, request.temperature, request.max_tokens)
+        .chat_completion(
+            messages,
+            chat_model(&state.config, request.model),
+            GenerationParams {
+                temperature: request.temperature,
+                max_tokens: request.max_tokens,
+                ..GenerationParams::default()
+            },
+        )
         .await?;
 
     let choice = response
@@ -301,10 +677,14 @@ pub async fn agent_chat(
         .first()
         .ok_or_else(|| AgentError::ParseError("No choices in response".to_string()))?;
 
-    let assistant_message = choice.message.content.clone().unwrap_or_default();
+    let mut assistant_message = choice.message.content.clone().unwrap_or_default();
+    if let Some(hint) = request.response_format_hint {
+        assistant_message = hint.apply_to_answer(assistant_message);
+    }
 
     Ok(Json(AgentChatResponse {
         id: Uuid::new_v4().to_string(),
+        generation_id: response.id,
         message: assistant_message,
         model: response.model,
         usage: response.usage.map(|u| UsageInfo {
@@ -318,28 +698,189 @@ pub async fn agent_chat(
 
 pub async fn agent_run(
     State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
     Json(request): Json<AgentRunRequest>,
 ) -> Result<Json<AgentRunResponse>, AgentError> {
     info!("Received agent run request with tools");
 
-    let response = state
-        .agent
-        .run(
-            &request.message,
-            request.conversation,
-            request.system_prompt,
-            request.model,
-        )
-        .await?;
+    if request.include_prompt {
+        require_feature_token(
+            &headers,
+            state.config.prompt_audit_token.as_deref(),
+            "Prompt audit",
+        )?;
+    }
+    let options = RunOptions {
+        capture_prompt: request.include_prompt,
+        ..run_options(&state.config, &request)
+    };
+
+    let response = match state.config.agent_run_timeout_secs {
+        Some(secs) => {
+            state
+                .agent
+                .run_with_timeout(
+                    &request.message,
+                    request.conversation,
+                    options,
+                    Duration::from_secs(secs),
+                )
+                .await?
+        }
+        None => {
+            state
+                .agent
+                .run(&request.message, request.conversation, options)
+                .await?
+        }
+    };
+
+    if let (Some(format), None, true) = (
+        &request.generation.response_format,
+        &response.pending_confirmation,
+        request.validate_response_format,
+    ) {
+        format.validate(&response.final_answer)?;
+    }
+
+    let final_answer = match request.response_format_hint {
+        Some(hint) => hint.apply_to_answer(response.final_answer),
+        None => response.final_answer,
+    };
 
     Ok(Json(AgentRunResponse {
         id: Uuid::new_v4().to_string(),
-        final_answer: response.final_answer,
+        final_answer,
         steps: response.steps,
         iterations: response.iterations,
+        generation_ids: response.generation_ids,
+        pending_confirmation: response.pending_confirmation,
+        reasoning: response.reasoning,
+        warnings: response.warnings,
+        truncated: response.truncated,
+        finish_reason: response.finish_reason,
+        usage: response.usage,
+        model: response.model,
+        prompt: response.prompt,
     }))
 }
 
+pub async fn agent_run_stream(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<AgentRunRequest>,
+) -> impl IntoResponse {
+    info!("Received streaming agent run request");
+
+    // A child of the shutdown token, so shutting down also cancels the run.
+    let cancel = state.shutdown.child_token();
+    let shutdown = state.shutdown.clone();
+    let options = RunOptions {
+        cancel: cancel.clone(),
+        ..run_options(&state.config, &request)
+    };
+    let hint = request.response_format_hint;
+    let (tx, rx) = mpsc::channel(32);
+    let (done_tx, done_rx) = oneshot::channel();
+
+    tokio::spawn(async move {
+        let agent = &state.agent;
+        let run = agent.run_streaming(&request.message, request.conversation, options, tx.clone());
+        tokio::pin!(run);
+
+        // The receiver is dropped when the client disconnects; cancel the run
+        // and let it wind down, which also cancels an in-flight MCP call.
+        let result = tokio::select! {
+            result = &mut run => result,
+            _ = tx.closed() => {
+                info!("Client disconnected, cancelling agent run");
+                cancel.cancel();
+                run.await
+            }
+        };
+
+        match result {
+            Ok(response) => {
+                let _ = done_tx.send(serde_json::json!({
+                    "iterations": response.iterations,
+                    "generation_ids": response.generation_ids,
+                    "pending_confirmation": response.pending_confirmation,
+                    "reasoning": response.reasoning,
+                    "warnings": response.warnings,
+                    "truncated": response.truncated,
+                    "finish_reason": response.finish_reason,
+                    "usage": response.usage,
+                    "model": response.model,
+                }));
+            }
+            Err(e) => {
+                let _ = tx
+                    .send(AgentStep::new(StepType::Error, e.to_string()))
+                    .await;
+            }
+        }
+    });
+
+    let stream = ReceiverStream::new(rx).map(move |mut step: AgentStep| {
+        if let (StepType::FinalAnswer, Some(hint)) = (&step.step_type, hint) {
+            step.content = hint.apply_to_answer(step.content);
+        }
+
+        let event = Event::default()
+            .event(step.step_type.event_name())
+            .data(serde_json::to_string(&step).unwrap_or_default());
+        Ok::<_, Infallible>(event)
+    });
+
+    // A closing `done` event carries the rest of the run summary once every
+    // step has been sent. Failed runs end with their `error` step instead.
+    let done = futures::stream::once(done_rx).filter_map(|summary| {
+        summary
+            .ok()
+            .map(|summary| Ok(Event::default().event("done").data(summary.to_string())))
+    });
+
+    sse_response(stream.chain(done), &shutdown)
+}
+
+/// Chat endpoints fall back to the chat default rather than the client's
+/// general default when the request names no model.
+fn chat_model(config: &Config, requested: Option<String>) -> Option<String> {
+    requested.or_else(|| Some(config.default_chat_model.clone()))
+}
+
+fn run_options(config: &Config, request: &AgentRunRequest) -> RunOptions {
+    RunOptions {
+        system_prompt: agent_system_prompt(config, request),
+        model: request.model.clone(),
+        confirmed_tools: request.confirmed_tools.clone(),
+        abort_on_tool_error: request.abort_on_tool_error,
+        session_id: request.session_id.clone(),
+        parallel_tool_calls: request.parallel_tool_calls,
+        generation: request.generation.clone(),
+        tool_choice: request.tool_choice.clone(),
+        strategy: request.strategy,
+        ..RunOptions::default()
+    }
+}
+
+fn agent_system_prompt(config: &Config, request: &AgentRunRequest) -> Option<String> {
+    if request.response_format_hint.is_none() && request.locale.is_none() {
+        return request.system_prompt.clone();
+    }
+
+    let mut system_prompt = request
+        .system_prompt
+        .clone()
+        .unwrap_or_else(|| config.system_prompt.clone());
+    if let Some(hint) = request.response_format_hint {
+        system_prompt = hint.apply_to_prompt(&system_prompt);
+    }
+    if let Some(ref locale) = request.locale {
+        system_prompt = locale.apply_to_prompt(&system_prompt);
+    }
+    Some(system_prompt)
+}
+
 #[derive(Debug, serde::Deserialize)]
 pub struct AgentChatRequest {
     pub message: String,
@@ -353,11 +894,16 @@ pub struct AgentChatRequest {
     pub temperature: Option<f32>,
     #[serde(default)]
     pub max_tokens: Option<u32>,
+    #[serde(default)]
+    pub response_format_hint: Option<ResponseFormatHint>,
+    #[serde(default)]
+    pub locale: Option<Locale>,
 }
 
 #[derive(Debug, serde::Serialize)]
 pub struct AgentChatResponse {
     pub id: String,
+    pub generation_id: String,
     pub message: String,
     pub model: String,
     pub usage: Option<UsageInfo>,
@@ -374,6 +920,44 @@ pub struct AgentRunRequest {
     pub system_prompt: Option<String>,
     #[serde(default)]
     pub model: Option<String>,
+    #[serde(default)]
+    pub response_format_hint: Option<ResponseFormatHint>,
+    #[serde(default)]
+    pub locale: Option<Locale>,
+    /// Destructive tools approved for this run, used to resume a run that
+    /// stopped with a confirmation request.
+    #[serde(default)]
+    pub confirmed_tools: Vec<String>,
+    /// Overrides `ABORT_ON_TOOL_ERROR` for this run.
+    #[serde(default)]
+    pub abort_on_tool_error: Option<bool>,
+    /// Groups runs of one conversation for the session tool cache.
+    #[serde(default)]
+    pub session_id: Option<String>,
+    /// Set to false to have the model call one tool per turn.
+    #[serde(default)]
+    pub parallel_tool_calls: Option<bool>,
+    /// `"auto"`, `"none"`, `"required"` or a named function; see
+    /// `RunOptions::tool_choice`.
+    #[serde(default)]
+    pub tool_choice: Option<ToolChoice>,
+    /// Passed through to the model: `temperature` and `max_tokens` (the
+    /// agent defaults when omitted), `response_format` (e.g.
+    /// `{"type": "json_object"}`), sampling settings, and OpenRouter's `models`.
+    #[serde(flatten)]
+    pub generation: GenerationParams,
+    /// Checks the final answer against `response_format` and fails with a
+    /// parse error when it does not match.
+    #[serde(default)]
+    pub validate_response_format: bool,
+    /// Returns the messages of the last model request as `prompt`. Requires
+    /// `PROMPT_AUDIT_TOKEN` as a feature token; ignored by the stream endpoint.
+    #[serde(default)]
+    pub include_prompt: bool,
+    /// `"react"` (the default) or `"plan_execute"`, which starts the run
+    /// with a `plan` step.
+    #[serde(default)]
+    pub strategy: StrategyKind,
 }
 
 #[derive(Debug, serde::Serialize)]
@@ -382,6 +966,23 @@ pub struct AgentRunResponse {
     pub final_answer: String,
     pub steps: Vec<crate::agent::AgentStep>,
     pub iterations: usize,
+    pub generation_ids: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pending_confirmation: Option<PendingToolCall>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reasoning: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+    /// The run stopped at `MAX_STEPS`; `steps` is incomplete.
+    pub truncated: bool,
+    pub finish_reason: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<UsageInfo>,
+    pub model: String,
+    /// Every message sent to the model on its last call, including the
+    /// system prompt with any injected instructions.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt: Option<Vec<Message>>,
 }
 
 #[derive(Debug, serde::Deserialize)]
@@ -395,3 +996,109 @@ pub struct McpToolCallRequest {
 pub struct McpServerToggleRequest {
     pub server_name: String,
 }
+
+#[derive(Debug, serde::Deserialize)]
+pub struct McpResourceReadRequest {
+    pub server_name: String,
+    pub uri: String,
+}
+
+/// A model in an ensemble, either a bare model id or a model with a vote
+/// weight for the `majority` strategy.
+#[derive(Debug, serde::Deserialize)]
+#[serde(untagged)]
+pub enum EnsembleModel {
+    Name(String),
+    Weighted { model: String, weight: f64 },
+}
+
+impl EnsembleModel {
+    fn model(&self) -> &str {
+        match self {
+            Self::Name(model) | Self::Weighted { model, .. } => model,
+        }
+    }
+
+    fn weight(&self) -> f64 {
+        match self {
+            Self::Name(_) => 1.0,
+            Self::Weighted { weight, .. } => *weight,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EnsembleStrategy {
+    /// Return every answer without picking one.
+    #[default]
+    All,
+    /// Pick the answer with the most total weight behind it, comparing
+    /// answers after trimming and lowercasing.
+    Majority,
+    /// Pick the longest answer.
+    Longest,
+}
+
+impl EnsembleStrategy {
+    fn select(self, answers: &[EnsembleAnswer]) -> Option<usize> {
+        let candidates = answers
+            .iter()
+            .enumerate()
+            .filter_map(|(i, a)| a.content.as_deref().map(|c| (i, c.trim())))
+            .filter(|(_, content)| !content.is_empty());
+
+        match self {
+            Self::All => None,
+            Self::Longest => candidates
+                .max_by_key(|(_, content)| content.chars().count())
+                .map(|(i, _)| i),
+            Self::Majority => {
+                let mut votes: HashMap<String, (usize, f64)> = HashMap::new();
+                for (i, content) in candidates {
+                    let entry = votes.entry(content.to_lowercase()).or_insert((i, 0.0));
+                    entry.1 += answers[i].weight;
+                }
+                votes
+                    .into_values()
+                    .max_by(|a, b| a.1.total_cmp(&b.1).then(b.0.cmp(&a.0)))
+                    .map(|(i, _)| i)
+            }
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct EnsembleRequest {
+    pub messages: Vec<Message>,
+    pub models: Vec<EnsembleModel>,
+    #[serde(default)]
+    pub system_prompt: Option<String>,
+    #[serde(default)]
+    pub temperature: Option<f32>,
+    #[serde(default)]
+    pub max_tokens: Option<u32>,
+    #[serde(default)]
+    pub strategy: Option<EnsembleStrategy>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct EnsembleAnswer {
+    pub model: String,
+    pub weight: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub generation_id: Option<String>,
+    pub content: Option<String>,
+    pub usage: Option<UsageInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct EnsembleResponse {
+    pub id: String,
+    pub answers: Vec<EnsembleAnswer>,
+    /// Model whose answer the strategy picked.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub selected: Option<String>,
+}
@@ -1,31 +1,61 @@
 mod agent;   
 mod config;     
 mod error;     
+mod formatting;
 mod handlers;    
 mod mcp;         
+mod metrics;
+mod middleware;
 mod models;      
+mod notes;
 mod openrouter;  
+mod partial_json;
+mod retry;
+mod scratchpad;
+mod search;
+mod sse;
+#[cfg(test)]
+mod test_support;
+mod tools;
 
 use axum::{
+    http::{header, HeaderName, HeaderValue, Method},
+    middleware::{from_fn, from_fn_with_state},
     routing::{get, post},
     Router,
 };
+use std::future::IntoFuture;
 use std::sync::Arc;
-use tower_http::cors::{Any, CorsLayer};
+use std::time::Duration;
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+use tower_http::set_header::SetResponseHeaderLayer;
 use tower_http::trace::TraceLayer;
-use tracing::{info, warn};
+use tracing::{error, info, warn};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 use crate::config::Config;
 use crate::handlers::{
-    agent_chat, agent_run, chat_completion, chat_completion_stream, disable_mcp_server,
-    enable_mcp_server, get_mcp_servers, get_mcp_tools, get_tools, health_check, list_models,
-    mcp_call_tool, AppState,
+    agent_chat, agent_run, agent_run_stream, chat_completion, chat_completion_stream,
+    chat_ensemble, disable_mcp_server, enable_mcp_server, get_generation, get_mcp_resources,
+    get_mcp_servers, get_mcp_tools, get_metrics, get_openai_tools, get_tools, health_check,
+    list_models, mcp_call_tool, mcp_read_resource, selftest, AppState, FEATURE_TOKEN_HEADER,
 };
 use crate::mcp::McpManager;
+use crate::openrouter::OpenRouterClient;
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
+fn main() -> anyhow::Result<()> {
+    dotenvy::dotenv().ok();
+
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime.enable_all();
+    if let Some(threads) = Config::tokio_worker_threads() {
+        runtime.worker_threads(threads);
+    }
+
+    runtime.build()?.block_on(run())
+}
+
+async fn run() -> anyhow::Result<()> {
     tracing_subscriber::registry()
         .with(
             tracing_subscriber::EnvFilter::try_from_default_env()
@@ -34,7 +64,6 @@ async fn main() -> anyhow::Result<()> {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    dotenvy::dotenv().ok();
     let config = Config::from_env().map_err(|e| {
         anyhow::anyhow!(
             "Failed to load configuration. Make sure OPENROUTER_API_KEY is set. Error: {}",
@@ -43,7 +72,16 @@ async fn main() -> anyhow::Result<()> {
     })?;
 
     info!("Starting LLM Agent server");
-    info!("Using model: {}", config.default_model);
+    info!(
+        "Using models: chat {}, agent {}",
+        config.default_chat_model, config.default_agent_model
+    );
+    info!(
+        "Tokio worker threads: {}",
+        tokio::runtime::Handle::current().metrics().num_workers()
+    );
+
+    preflight(&config).await?;
 
     let mcp_manager = match McpManager::load_config("mcp_config.json") {
         Ok(mcp_config) => {
@@ -67,35 +105,810 @@ async fn main() -> anyhow::Result<()> {
         }
     };
 
-    let state = AppState::new(config.clone(), mcp_manager);
+    let state = AppState::new(config.clone(), mcp_manager.clone());
+    let shutdown = state.shutdown.clone();
+    let app = router(&config, state);
+
+    let addr = format!("{}:{}", config.server_host, config.server_port);
+    info!("Server listening on {}", addr);
+
+    let listener = tokio::net::TcpListener::bind(&addr).await?;
+    let server = axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown.clone().cancelled_owned())
+        .into_future();
+    tokio::pin!(server);
 
-    let cors = CorsLayer::new()
-        .allow_origin(Any)
-        .allow_methods(Any)
-        .allow_headers(Any);
+    // The signal cancels the shutdown token, which stops the server
+    // accepting requests and ends open SSE streams. Requests still running
+    // after the drain timeout are dropped so the MCP servers are always
+    // shut down.
+    tokio::select! {
+        result = &mut server => result?,
+        _ = shutdown_signal() => {
+            shutdown.cancel();
+            info!("Server stopped accepting requests");
+            let drain = Duration::from_millis(config.shutdown_drain_timeout_ms);
+            match tokio::time::timeout(drain, &mut server).await {
+                Ok(result) => result?,
+                Err(_) => warn!("Requests still open after {:?}; stopping anyway", drain),
+            }
+        }
+    }
 
-    let app = Router::new()
+    if let Some(manager) = mcp_manager {
+        manager.shutdown().await;
+    }
+    info!("Shutdown complete");
+
+    Ok(())
+}
+
+/// Checks the OpenRouter API key when `PREFLIGHT_CHECK` or
+/// `FAIL_ON_BAD_KEY` is set. A rejected key is logged, and only stops
+/// startup with `FAIL_ON_BAD_KEY`.
+async fn preflight(config: &Config) -> anyhow::Result<()> {
+    if !config.preflight_check && !config.fail_on_bad_key {
+        return Ok(());
+    }
+    match OpenRouterClient::new(config.clone()).verify_api_key().await {
+        Ok(()) => info!("OpenRouter API key verified"),
+        Err(e) if config.fail_on_bad_key => {
+            anyhow::bail!("OpenRouter API key preflight failed: {}", e)
+        }
+        Err(e) => error!("OpenRouter API key preflight failed: {}", e),
+    }
+    Ok(())
+}
+
+/// The API routes with their middleware: configured response headers,
+/// API token authentication, pretty-printing, CORS and tracing.
+fn router(config: &Config, state: Arc<AppState>) -> Router {
+    let cors = cors_layer(&config.cors_allowed_origins);
+
+    let mut app = Router::new()
         .route("/health", get(health_check))
         .route("/v1/chat/completions", post(chat_completion))
         .route("/v1/chat/completions/stream", post(chat_completion_stream))
+        .route("/v1/chat/ensemble", post(chat_ensemble))
         .route("/v1/agent/chat", post(agent_chat))
         .route("/v1/agent/run", post(agent_run))
+        .route("/v1/agent/run/stream", post(agent_run_stream))
         .route("/v1/agent/tools", get(get_tools))
+        .route("/v1/agent/tools/openai", get(get_openai_tools))
         .route("/v1/mcp/servers", get(get_mcp_servers))
         .route("/v1/mcp/servers/enable", post(enable_mcp_server))
         .route("/v1/mcp/servers/disable", post(disable_mcp_server))
         .route("/v1/mcp/tools", get(get_mcp_tools))
         .route("/v1/mcp/call", post(mcp_call_tool))
+        .route("/v1/mcp/resources", get(get_mcp_resources))
+        .route("/v1/mcp/resources/read", post(mcp_read_resource))
         .route("/v1/models", get(list_models))
+        .route("/v1/generation/:id", get(get_generation))
+        .route("/v1/metrics", get(get_metrics))
+        .route("/v1/selftest", post(selftest))
+        .with_state(state);
+
+    // Configured headers fill in defaults only; headers a handler sets itself
+    // (such as the SSE cache headers) take precedence.
+    for (name, value) in &config.response_headers {
+        match (
+            HeaderName::try_from(name.as_str()),
+            HeaderValue::try_from(value.as_str()),
+        ) {
+            (Ok(name), Ok(value)) => {
+                app = app.layer(SetResponseHeaderLayer::if_not_present(name, value));
+            }
+            _ => warn!("Ignoring invalid response header {}: {}", name, value),
+        }
+    }
+
+    // Only API tokens open the routes. The self-test and prompt audit tokens
+    // are checked by their handlers on top of this.
+    if !config.api_tokens.is_empty() {
+        info!("API token authentication enabled");
+        app = app.layer(from_fn_with_state(
+            Arc::new(config.api_tokens.clone()),
+            middleware::require_api_token,
+        ));
+    }
+
+    app.layer(from_fn(middleware::pretty_json))
         .layer(cors)
         .layer(TraceLayer::new_for_http())
-        .with_state(state);
+}
 
-    let addr = format!("{}:{}", config.server_host, config.server_port);
-    info!("Server listening on {}", addr);
+/// Allows any origin when `origins` is empty or contains `*`. Otherwise
+/// only those exact origins may call the API, with credentials allowed,
+/// which is why the wildcard can't be combined with them.
+fn cors_layer(origins: &[String]) -> CorsLayer {
+    let cors = CorsLayer::new().allow_methods([Method::GET, Method::POST]);
 
-    let listener = tokio::net::TcpListener::bind(&addr).await?;
-    axum::serve(listener, app).await?;
+    if origins.is_empty() || origins.iter().any(|o| o == "*") {
+        return cors.allow_origin(Any).allow_headers(Any);
+    }
 
-    Ok(())
+    let origins: Vec<HeaderValue> = origins
+        .iter()
+        .filter_map(|origin| match HeaderValue::try_from(origin.as_str()) {
+            Ok(value) => Some(value),
+            Err(_) => {
+                warn!("Ignoring invalid CORS origin {}", origin);
+                None
+            }
+        })
+        .collect();
+    info!("CORS restricted to {} origins", origins.len());
+
+    cors.allow_origin(AllowOrigin::list(origins))
+        .allow_headers([
+            header::AUTHORIZATION,
+            header::CONTENT_TYPE,
+            header::ACCEPT,
+            HeaderName::from_static(FEATURE_TOKEN_HEADER),
+        ])
+        .allow_credentials(true)
+}
+
+/// Resolves on Ctrl-C or, on Unix, SIGTERM, so in-flight requests can
+/// finish before the server exits.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Failed to listen for Ctrl-C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => info!("Received Ctrl-C, shutting down"),
+        _ = terminate => info!("Received SIGTERM, shutting down"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{
+        completion, content_chunk, serve, streaming_upstream, test_config, tool_calls, Upstream,
+    };
+    use axum::{body::Body, http::Request, http::StatusCode, Json};
+    use serde_json::json;
+    use tower::ServiceExt;
+
+    /// An app with API token auth on and a self-test token set, backed by
+    /// `upstream`.
+    fn app(upstream: &Upstream) -> Router {
+        app_with(upstream.url.clone(), |_| {})
+    }
+
+    /// Like [`app`], for an OpenRouter stand-in at `base_url` and with
+    /// `configure` applied to the config.
+    fn app_with(base_url: String, configure: impl FnOnce(&mut Config)) -> Router {
+        let mut config = test_config(base_url);
+        config.api_tokens = vec!["api-token".to_string()];
+        config.selftest_token = Some("selftest-token".to_string());
+        configure(&mut config);
+        let state = AppState::new(config.clone(), None);
+        router(&config, state)
+    }
+
+    fn post(path: &str, headers: &[(&str, &str)], body: serde_json::Value) -> Request<Body> {
+        let mut request = Request::post(path).header(header::CONTENT_TYPE, "application/json");
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        request.body(Body::from(body.to_string())).unwrap()
+    }
+
+    fn get_with_token(path: &str) -> Request<Body> {
+        Request::get(path)
+            .header(header::AUTHORIZATION, "Bearer api-token")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    async fn json_body(response: axum::response::Response) -> serde_json::Value {
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    /// The data of each event in an SSE response, parsed as JSON.
+    async fn sse_events(response: axum::response::Response) -> Vec<serde_json::Value> {
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        crate::sse::parse_events(&String::from_utf8_lossy(&body))
+            .iter()
+            .map(|data| serde_json::from_str(data).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn feature_token_does_not_open_other_routes() {
+        let upstream = Upstream::ok(vec![completion("hi")]).await;
+        let request = post(
+            "/v1/chat/completions",
+            &[("authorization", "Bearer selftest-token")],
+            json!({"messages": [{"role": "user", "content": "hi"}]}),
+        );
+
+        let response = app(&upstream).oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(upstream.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn selftest_requires_the_feature_token_on_top_of_an_api_token() {
+        let upstream = Upstream::ok(vec![
+            tool_calls(&[(
+                "encode_decode",
+                json!({"operation": "encode", "encoding": "base64", "input": "selftest"}),
+            )]),
+            completion("c2VsZnRlc3Q="),
+        ])
+        .await;
+        let app = app(&upstream);
+
+        let api_only = post(
+            "/v1/selftest",
+            &[("authorization", "Bearer api-token")],
+            json!({}),
+        );
+        let response = app.clone().oneshot(api_only).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let feature_only = post(
+            "/v1/selftest",
+            &[(FEATURE_TOKEN_HEADER, "selftest-token")],
+            json!({}),
+        );
+        let response = app.clone().oneshot(feature_only).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(upstream.requests().is_empty());
+
+        let response = app.oneshot(selftest_request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(upstream.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn chat_reports_the_model_that_answered() {
+        let mut answer = completion("hi");
+        answer["model"] = json!("backup/model");
+        let upstream = Upstream::ok(vec![answer]).await;
+        let request = post(
+            "/v1/chat/completions",
+            &[("authorization", "Bearer api-token")],
+            json!({
+                "messages": [{"role": "user", "content": "hi"}],
+                "model": "primary/model",
+                "models": ["primary/model", "backup/model"]
+            }),
+        );
+
+        let response = app(&upstream).oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(json_body(response).await["model"], "backup/model");
+        assert_eq!(
+            upstream.requests()[0]["models"],
+            json!(["primary/model", "backup/model"])
+        );
+    }
+
+    #[tokio::test]
+    async fn preflight_reports_a_rejected_key() {
+        let openrouter = Router::new().route(
+            "/key",
+            get(|| async {
+                (
+                    StatusCode::UNAUTHORIZED,
+                    Json(json!({"error": {"message": "No auth credentials found"}})),
+                )
+            }),
+        );
+        let mut config = test_config(serve(openrouter).await);
+
+        config.preflight_check = true;
+        assert!(preflight(&config).await.is_ok());
+
+        config.fail_on_bad_key = true;
+        let error = preflight(&config).await.unwrap_err().to_string();
+        assert!(error.contains("status 401"), "{}", error);
+        assert!(error.contains("No auth credentials found"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn iteration_cap_is_counted_in_the_metrics() {
+        // The model never stops calling tools.
+        let upstream = Upstream::ok(vec![tool_calls(&[(
+            "calculator",
+            json!({"expression": "1 + 1"}),
+        )])])
+        .await;
+        let app = app(&upstream);
+        let run = post(
+            "/v1/agent/run",
+            &[("authorization", "Bearer api-token")],
+            json!({"message": "Keep going"}),
+        );
+
+        let response = app.clone().oneshot(run).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let metrics = app.oneshot(get_with_token("/v1/metrics")).await.unwrap();
+
+        let agent = &json_body(metrics).await["agent"];
+        assert_eq!(agent["runs_started"], 1);
+        assert_eq!(agent["runs_iteration_limit"], 1);
+    }
+
+    #[tokio::test]
+    async fn control_characters_are_stripped_from_streamed_content() {
+        let url = streaming_upstream(vec![content_chunk("he\u{7}l\u{1b}lo\n")]).await;
+        let app = app_with(url, |config| config.sanitize_stream_content = true);
+        let request = post(
+            "/v1/chat/completions/stream",
+            &[("authorization", "Bearer api-token")],
+            json!({"messages": [{"role": "user", "content": "hi"}]}),
+        );
+
+        let response = app.oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let events = sse_events(response).await;
+        assert_eq!(events[0]["content"], "hello\n");
+    }
+
+    #[tokio::test]
+    async fn stream_flag_returns_an_event_stream() {
+        let url = streaming_upstream(vec![content_chunk("hello")]).await;
+        let request = post(
+            "/v1/chat/completions",
+            &[("authorization", "Bearer api-token")],
+            json!({"messages": [{"role": "user", "content": "hi"}], "stream": true}),
+        );
+
+        let response = app_with(url, |_| {}).oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "text/event-stream"
+        );
+        assert_eq!(sse_events(response).await[0]["content"], "hello");
+    }
+
+    #[tokio::test]
+    async fn stream_timings_increase() {
+        // Sends each chunk 20ms after the previous one.
+        let openrouter = Router::new().route(
+            "/chat/completions",
+            axum::routing::post(|| async {
+                let events = ["a", "b", "c"]
+                    .into_iter()
+                    .map(|c| format!("data: {}\n\n", content_chunk(c)))
+                    .chain(std::iter::once("data: [DONE]\n\n".to_string()));
+                let body = futures::StreamExt::then(futures::stream::iter(events), |event| async {
+                    tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+                    Ok::<_, std::convert::Infallible>(event)
+                });
+                (
+                    [(header::CONTENT_TYPE, "text/event-stream")],
+                    Body::from_stream(body),
+                )
+            }),
+        );
+        let app = app_with(serve(openrouter).await, |_| {});
+        let request = post(
+            "/v1/chat/completions/stream",
+            &[("authorization", "Bearer api-token")],
+            json!({
+                "messages": [{"role": "user", "content": "hi"}],
+                "include_timing": true
+            }),
+        );
+
+        let response = app.oneshot(request).await.unwrap();
+
+        let timings: Vec<u64> = sse_events(response)
+            .await
+            .iter()
+            .map(|event| event["t_ms"].as_u64().unwrap())
+            .collect();
+        assert_eq!(timings.len(), 3);
+        assert!(timings.windows(2).all(|w| w[0] <= w[1]), "{:?}", timings);
+        assert!(timings[2] > timings[0], "{:?}", timings);
+    }
+
+    #[tokio::test]
+    async fn stream_responses_are_not_buffered_by_proxies() {
+        let url = streaming_upstream(vec![content_chunk("hello")]).await;
+        let app = app_with(url, |config| {
+            config
+                .response_headers
+                .insert("Cache-Control".to_string(), "no-store".to_string());
+        });
+        let request = post(
+            "/v1/chat/completions/stream",
+            &[("authorization", "Bearer api-token")],
+            json!({"messages": [{"role": "user", "content": "hi"}]}),
+        );
+
+        let response = app.oneshot(request).await.unwrap();
+
+        assert_eq!(response.headers()["x-accel-buffering"], "no");
+        // The handler's own cache header wins over the configured default.
+        assert_eq!(response.headers()[header::CACHE_CONTROL], "no-cache");
+    }
+
+    #[tokio::test]
+    async fn chat_and_agent_endpoints_have_their_own_default_model() {
+        let upstream = Upstream::ok(vec![completion("hi")]).await;
+        let app = app_with(upstream.url.clone(), |config| {
+            config.default_chat_model = "cheap/chat".to_string();
+            config.default_agent_model = "capable/agent".to_string();
+        });
+        let auth = [("authorization", "Bearer api-token")];
+
+        let chat = post(
+            "/v1/chat/completions",
+            &auth,
+            json!({"messages": [{"role": "user", "content": "hi"}]}),
+        );
+        assert_eq!(
+            app.clone().oneshot(chat).await.unwrap().status(),
+            StatusCode::OK
+        );
+        let run = post("/v1/agent/run", &auth, json!({"message": "hi"}));
+        assert_eq!(app.oneshot(run).await.unwrap().status(), StatusCode::OK);
+
+        let models: Vec<serde_json::Value> = upstream
+            .requests()
+            .iter()
+            .map(|request| request["model"].clone())
+            .collect();
+        assert_eq!(models, [json!("cheap/chat"), json!("capable/agent")]);
+    }
+
+    fn selftest_request() -> Request<Body> {
+        post(
+            "/v1/selftest",
+            &[
+                ("authorization", "Bearer api-token"),
+                (FEATURE_TOKEN_HEADER, "selftest-token"),
+            ],
+            json!({}),
+        )
+    }
+
+    #[tokio::test]
+    async fn selftest_passes_when_the_model_uses_the_tool() {
+        let upstream = Upstream::ok(vec![
+            tool_calls(&[(
+                "encode_decode",
+                json!({"operation": "encode", "encoding": "base64", "input": "selftest"}),
+            )]),
+            completion("c2VsZnRlc3Q="),
+        ])
+        .await;
+
+        let response = app(&upstream).oneshot(selftest_request()).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = json_body(response).await;
+        assert_eq!(body["passed"], true);
+        assert_eq!(body["tool_used"], true);
+        assert_eq!(body["answer_correct"], true);
+        assert_eq!(body["iterations"], 2);
+    }
+
+    #[tokio::test]
+    async fn selftest_fails_when_the_model_skips_the_tool() {
+        let upstream = Upstream::ok(vec![completion("c2VsZnRlc3Q=")]).await;
+
+        let response = app(&upstream).oneshot(selftest_request()).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = json_body(response).await;
+        assert_eq!(body["passed"], false);
+        assert_eq!(body["tool_used"], false);
+    }
+
+    #[tokio::test]
+    async fn locale_instruction_is_added_to_the_system_message() {
+        let upstream = Upstream::ok(vec![completion("Привет")]).await;
+        let app = app(&upstream);
+        let auth = [("authorization", "Bearer api-token")];
+        let instruction = "Respond in the language and conventions of the ru-RU locale.";
+
+        for body in [
+            json!({"messages": [{"role": "user", "content": "hi"}], "locale": "ru-RU"}),
+            json!({
+                "messages": [{"role": "user", "content": "hi"}],
+                "system_prompt": "Be brief.",
+                "locale": "ru-RU"
+            }),
+        ] {
+            let request = post("/v1/chat/completions", &auth, body);
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        let system: Vec<serde_json::Value> = upstream
+            .requests()
+            .iter()
+            .map(|request| request["messages"][0].clone())
+            .collect();
+        assert_eq!(system[0]["role"], "system");
+        assert_eq!(system[0]["content"], instruction);
+        assert_eq!(
+            system[1]["content"],
+            format!("Be brief.\n\n{}", instruction)
+        );
+    }
+
+    #[tokio::test]
+    async fn tools_are_listed_in_the_openai_shape() {
+        let upstream = Upstream::ok(vec![completion("hi")]).await;
+
+        let response = app(&upstream)
+            .oneshot(get_with_token("/v1/agent/tools/openai"))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let tools = json_body(response).await;
+        let tools = tools.as_array().expect("a bare array");
+        assert!(!tools.is_empty());
+        for tool in tools {
+            let mut keys: Vec<&String> = tool.as_object().unwrap().keys().collect();
+            keys.sort();
+            assert_eq!(keys, ["function", "type"]);
+            assert_eq!(tool["type"], "function");
+            let function = &tool["function"];
+            assert!(function["name"].is_string(), "{}", tool);
+            assert!(function["description"].is_string(), "{}", tool);
+            assert_eq!(function["parameters"]["type"], "object", "{}", tool);
+        }
+        assert!(tools
+            .iter()
+            .any(|tool| tool["function"]["name"] == "calculator"));
+    }
+
+    #[tokio::test]
+    async fn ensemble_attributes_each_answer_to_its_model() {
+        // Each model gives its own answer, whatever order the calls arrive in.
+        let openrouter = Router::new().route(
+            "/chat/completions",
+            axum::routing::post(|Json(request): Json<serde_json::Value>| async move {
+                let answer = match request["model"].as_str() {
+                    Some("short/model") => "Paris",
+                    _ => "The capital of France is Paris.",
+                };
+                let mut reply = completion(answer);
+                reply["model"] = request["model"].clone();
+                Json(reply)
+            }),
+        );
+        let app = app_with(serve(openrouter).await, |_| {});
+        let request = post(
+            "/v1/chat/ensemble",
+            &[("authorization", "Bearer api-token")],
+            json!({
+                "messages": [{"role": "user", "content": "Capital of France?"}],
+                "models": ["short/model", "long/model"],
+                "strategy": "longest"
+            }),
+        );
+
+        let response = app.oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = json_body(response).await;
+        let answers: Vec<(&str, &str)> = body["answers"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|a| (a["model"].as_str().unwrap(), a["content"].as_str().unwrap()))
+            .collect();
+        assert_eq!(
+            answers,
+            [
+                ("short/model", "Paris"),
+                ("long/model", "The capital of France is Paris."),
+            ]
+        );
+        assert_eq!(body["selected"], "long/model");
+    }
+
+    #[tokio::test]
+    async fn slow_run_times_out_with_its_steps_so_far() {
+        // The first turn calls a tool; the second never arrives in time.
+        let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let openrouter = Router::new().route(
+            "/chat/completions",
+            axum::routing::post(move || async move {
+                if calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst) > 0 {
+                    tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+                }
+                Json(tool_calls(&[(
+                    "calculator",
+                    json!({"expression": "6 * 7"}),
+                )]))
+            }),
+        );
+        let app = app_with(serve(openrouter).await, |config| {
+            config.agent_run_timeout_secs = Some(1)
+        });
+        let request = post(
+            "/v1/agent/run",
+            &[("authorization", "Bearer api-token")],
+            json!({"message": "What is 6 * 7?"}),
+        );
+
+        let started = std::time::Instant::now();
+        let response = app.oneshot(request).await.unwrap();
+
+        assert!(started.elapsed() < std::time::Duration::from_secs(3));
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        let body = json_body(response).await;
+        assert_eq!(body["code"], "RUN_TIMEOUT");
+        let steps: Vec<&str> = body["steps"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|step| step["step_type"].as_str().unwrap())
+            .collect();
+        assert_eq!(steps, ["tool_call", "tool_result"]);
+    }
+
+    #[tokio::test]
+    async fn prompt_audit_shows_the_injected_instructions() {
+        let upstream = Upstream::ok(vec![completion("Коротко.")]).await;
+        let app = app_with(upstream.url.clone(), |config| {
+            config.prompt_audit_token = Some("audit-token".to_string())
+        });
+        let body = json!({
+            "message": "hi",
+            "system_prompt": "Be brief.",
+            "locale": "ru-RU",
+            "response_format_hint": "plain",
+            "include_prompt": true
+        });
+
+        let without_token = post(
+            "/v1/agent/run",
+            &[("authorization", "Bearer api-token")],
+            body.clone(),
+        );
+        let response = app.clone().oneshot(without_token).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let request = post(
+            "/v1/agent/run",
+            &[
+                ("authorization", "Bearer api-token"),
+                (FEATURE_TOKEN_HEADER, "audit-token"),
+            ],
+            body,
+        );
+        let response = app.oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let prompt = json_body(response).await["prompt"].clone();
+        assert_eq!(prompt, upstream.requests()[0]["messages"]);
+        let system = prompt[0]["content"].as_str().unwrap();
+        assert_eq!(prompt[0]["role"], "system");
+        assert!(system.starts_with("Be brief."), "{}", system);
+        assert!(system.contains("plain text"), "{}", system);
+        assert!(system.contains("ru-RU locale"), "{}", system);
+    }
+
+    #[tokio::test]
+    async fn only_allowed_origins_get_cors_headers() {
+        let upstream = Upstream::ok(vec![completion("hi")]).await;
+        let app = app_with(upstream.url.clone(), |config| {
+            config.cors_allowed_origins = vec!["https://app.example.com".to_string()]
+        });
+        let preflight = |origin: &str| {
+            Request::options("/v1/chat/completions")
+                .header(header::ORIGIN, origin)
+                .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+                .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "authorization")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let allowed = app
+            .clone()
+            .oneshot(preflight("https://app.example.com"))
+            .await
+            .unwrap();
+        let headers = allowed.headers();
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://app.example.com"
+        );
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+
+        let other = app
+            .clone()
+            .oneshot(preflight("https://evil.example.com"))
+            .await
+            .unwrap();
+        assert!(other
+            .headers()
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .is_none());
+
+        let request = post(
+            "/v1/chat/completions",
+            &[
+                ("authorization", "Bearer api-token"),
+                ("origin", "https://evil.example.com"),
+            ],
+            json!({"messages": [{"role": "user", "content": "hi"}]}),
+        );
+        let response = app.oneshot(request).await.unwrap();
+        assert!(response
+            .headers()
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn shutdown_ends_open_streams() {
+        // Sends one chunk, then keeps the stream open.
+        let openrouter = Router::new().route(
+            "/chat/completions",
+            axum::routing::post(|| async {
+                let first = futures::stream::once(async {
+                    Ok::<_, std::convert::Infallible>(format!(
+                        "data: {}\n\n",
+                        content_chunk("hello")
+                    ))
+                });
+                let body = futures::StreamExt::chain(first, futures::stream::pending());
+                (
+                    [(header::CONTENT_TYPE, "text/event-stream")],
+                    Body::from_stream(body),
+                )
+            }),
+        );
+        let config = test_config(serve(openrouter).await);
+        let state = AppState::new(config.clone(), None);
+        let shutdown = state.shutdown.clone();
+        let request = post(
+            "/v1/chat/completions/stream",
+            &[],
+            json!({"messages": [{"role": "user", "content": "hi"}]}),
+        );
+
+        let response = router(&config, state).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        shutdown.cancel();
+
+        let events = tokio::time::timeout(std::time::Duration::from_secs(2), sse_events(response))
+            .await
+            .expect("the stream ended");
+        assert!(events.len() <= 1, "{:?}", events);
+    }
 }
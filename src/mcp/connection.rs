@@ -1,27 +1,434 @@
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
+use futures::future::{self, join_all};
+use futures::stream::{self, BoxStream, SplitSink, SplitStream};
+use futures::{SinkExt, StreamExt};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION};
 use reqwest::Client as HttpClient;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::process::{Child, ChildStdin, ChildStdout, Command};
-use tracing::debug;
+use tokio::net::TcpStream;
+use tokio::process::{Child, ChildStderr, ChildStdin, ChildStdout, Command};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use tracing::{debug, info, warn};
 
-use crate::mcp::protocol::{parse_sse_response, JsonRpcRequest, JsonRpcResponse};
+use crate::mcp::protocol::{
+    JsonRpcIncoming, JsonRpcNotification, JsonRpcRequest, JsonRpcResponse, JsonRpcServerMessage,
+};
+use crate::sse::{self, SseDecoder};
 
+/// Lines of stderr kept from a stdio server for error messages.
+const STDERR_TAIL_LINES: usize = 20;
+
+/// How long an error waits for the stderr reader to catch up, so the output
+/// of a server that just exited makes it into the message.
+const STDERR_SETTLE_TIME: Duration = Duration::from_millis(200);
+
+/// How long a failed stdio request waits for the server's process to be
+/// reaped, as a server that closed its output may not have exited yet.
+const EXIT_SETTLE_TIME: Duration = Duration::from_millis(200);
+
+/// Responses with an id nobody is waiting for that a stdio read skips before
+/// giving up, so a confused server fails the call instead of stalling it.
+const MAX_UNEXPECTED_RESPONSES: usize = 16;
+
+/// Header a streamable-HTTP server assigns its session id in.
+const SESSION_HEADER: &str = "Mcp-Session-Id";
+
+/// Pause before reopening an HTTP server's notification stream after it
+/// ends, which the client's request timeout makes happen regularly.
+const LISTEN_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Reads a stdio server's stderr in the background, keeping the most recent
+/// lines to attach to transport errors.
+pub struct StderrCapture {
+    lines: Arc<Mutex<VecDeque<String>>>,
+    reader: JoinHandle<()>,
+}
+
+impl StderrCapture {
+    fn spawn(name: &str, stderr: ChildStderr) -> Self {
+        let lines = Arc::new(Mutex::new(VecDeque::with_capacity(STDERR_TAIL_LINES)));
+        let tail = lines.clone();
+        let name = name.to_string();
+        let reader = tokio::spawn(async move {
+            let mut stderr = BufReader::new(stderr).lines();
+            while let Ok(Some(line)) = stderr.next_line().await {
+                debug!("[{}] stderr: {}", name, line);
+                if let Ok(mut tail) = tail.lock() {
+                    if tail.len() == STDERR_TAIL_LINES {
+                        tail.pop_front();
+                    }
+                    tail.push_back(line);
+                }
+            }
+        });
+
+        Self { lines, reader }
+    }
+
+    fn tail(&self) -> Vec<String> {
+        self.lines
+            .lock()
+            .map(|lines| lines.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Appends the captured stderr to an error, if there is any.
+    async fn annotate<T>(&mut self, result: Result<T>) -> Result<T> {
+        let Err(error) = result else {
+            return result;
+        };
+
+        if !self.reader.is_finished() {
+            let _ = tokio::time::timeout(STDERR_SETTLE_TIME, &mut self.reader).await;
+        }
+        let lines = self.tail();
+        if lines.is_empty() {
+            return Err(error);
+        }
+        Err(anyhow!("{:#}\nServer stderr:\n{}", error, lines.join("\n")))
+    }
+}
+
+/// Lists a server announced as changed with a `list_changed` notification
+/// since they were last taken.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ListChanges {
+    pub tools: bool,
+    pub resources: bool,
+}
+
+impl ListChanges {
+    /// Handles a message the server sent on its own. Only `list_changed`
+    /// notifications are acted on; anything else is logged.
+    fn record(&mut self, message: &JsonRpcServerMessage) {
+        match (&message.id, message.method.as_str()) {
+            (Some(id), method) => debug!("Ignored unsupported server request {} ({})", method, id),
+            (None, "notifications/tools/list_changed") => self.tools = true,
+            (None, "notifications/resources/list_changed") => self.resources = true,
+            (None, method) => debug!("Received notification {}", method),
+        }
+    }
+
+    fn take(changes: &Mutex<ListChanges>) -> Self {
+        changes
+            .lock()
+            .map(|mut changes| std::mem::take(&mut *changes))
+            .unwrap_or_default()
+    }
+}
+
+/// Reads a stdio or WebSocket server's messages in the background, so
+/// notifications it sends between requests are recorded as they arrive
+/// rather than on the next request. Responses are queued for the request
+/// waiting on them.
+pub struct MessageReader {
+    responses: mpsc::UnboundedReceiver<Result<JsonRpcResponse>>,
+    changes: Arc<Mutex<ListChanges>>,
+    reader: JoinHandle<()>,
+    /// Why reading stops when the server ends the connection.
+    closed: &'static str,
+}
+
+impl MessageReader {
+    fn spawn(
+        label: &'static str,
+        closed: &'static str,
+        mut messages: BoxStream<'static, Result<String>>,
+    ) -> Self {
+        let (sender, responses) = mpsc::unbounded_channel();
+        let changes = Arc::new(Mutex::new(ListChanges::default()));
+        let recorded = changes.clone();
+        let reader = tokio::spawn(async move {
+            while let Some(message) = messages.next().await {
+                let message = match message {
+                    Ok(message) if message.trim().is_empty() => continue,
+                    Ok(message) => message,
+                    Err(e) => {
+                        let _ = sender.send(Err(e));
+                        return;
+                    }
+                };
+                match serde_json::from_str::<JsonRpcIncoming>(message.trim()) {
+                    Ok(JsonRpcIncoming::Response(response)) => {
+                        debug!("{} received: {}", label, message.trim());
+                        if sender.send(Ok(response)).is_err() {
+                            return;
+                        }
+                    }
+                    Ok(JsonRpcIncoming::Server(message)) => {
+                        if let Ok(mut changes) = recorded.lock() {
+                            changes.record(&message);
+                        }
+                    }
+                    Err(_) => debug!("{} ignored non-JSON-RPC: {}", label, message.trim()),
+                }
+            }
+        });
+
+        Self {
+            responses,
+            changes,
+            reader,
+            closed,
+        }
+    }
+
+    /// Reads a stdio server's stdout line by line.
+    fn stdio(stdout: ChildStdout) -> Self {
+        let lines = stream::unfold(BufReader::new(stdout).lines(), |mut lines| async move {
+            let line = lines.next_line().await.transpose()?;
+            Some((line.map_err(anyhow::Error::from), lines))
+        });
+        Self::spawn("Stdio", "Server closed stdout unexpectedly", lines.boxed())
+    }
+
+    /// Reads text frames from a WebSocket, skipping control frames.
+    fn websocket(socket: SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>) -> Self {
+        let frames = socket
+            .take_while(|frame| future::ready(!matches!(frame, Ok(WsMessage::Close(_)))))
+            .filter_map(|frame| async move {
+                match frame {
+                    Ok(WsMessage::Text(text)) => Some(Ok(text)),
+                    Ok(WsMessage::Binary(bytes)) => {
+                        Some(String::from_utf8(bytes).map_err(Into::into))
+                    }
+                    Ok(_) => None,
+                    Err(e) => Some(Err(e.into())),
+                }
+            });
+        Self::spawn("WebSocket", "Server closed the WebSocket", frames.boxed())
+    }
+
+    async fn next_response(&mut self) -> Result<JsonRpcResponse> {
+        self.responses
+            .recv()
+            .await
+            .unwrap_or_else(|| Err(anyhow!(self.closed)))
+    }
+}
+
+impl Drop for MessageReader {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+/// A streamable-HTTP session: the id the server assigned on initialize,
+/// sent back on every request along with the configured headers, and the
+/// GET stream it pushes messages on.
+pub struct HttpSession {
+    headers: HeaderMap,
+    id: Mutex<Option<String>>,
+    changes: Arc<Mutex<ListChanges>>,
+    listener: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl HttpSession {
+    fn new(headers: HeaderMap) -> Self {
+        Self {
+            headers,
+            id: Mutex::new(None),
+            changes: Arc::default(),
+            listener: Mutex::new(None),
+        }
+    }
+
+    /// The configured headers plus the session id, once assigned.
+    fn headers(&self) -> HeaderMap {
+        let mut headers = self.headers.clone();
+        let id = self.id.lock().ok().and_then(|id| id.clone());
+        if let Some(value) = id.and_then(|id| HeaderValue::from_str(&id).ok()) {
+            headers.insert(SESSION_HEADER, value);
+        }
+        headers
+    }
+
+    fn record(&self, message: &JsonRpcServerMessage) {
+        if let Ok(mut changes) = self.changes.lock() {
+            changes.record(message);
+        }
+    }
+
+    /// Adopts the session id from a response, opening the notification
+    /// stream the first time one is assigned.
+    fn start(&self, client: &HttpClient, url: &str, session_id: String) {
+        let Ok(mut id) = self.id.lock() else {
+            return;
+        };
+        if id.as_deref() == Some(session_id.as_str()) {
+            return;
+        }
+        debug!("HTTP session {} started with {}", session_id, url);
+        *id = Some(session_id);
+        drop(id);
+
+        if let Ok(mut listener) = self.listener.lock() {
+            if let Some(previous) = listener.take() {
+                previous.abort();
+            }
+            *listener = Some(Self::listen(
+                client.clone(),
+                url.to_string(),
+                self.headers(),
+                self.changes.clone(),
+            ));
+        }
+    }
+
+    /// Reads server-initiated messages from the session's GET stream. A
+    /// server that doesn't offer one answers with an error status, which
+    /// ends the listener.
+    fn listen(
+        client: HttpClient,
+        url: String,
+        headers: HeaderMap,
+        changes: Arc<Mutex<ListChanges>>,
+    ) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                let response = client
+                    .get(&url)
+                    .header("Accept", "text/event-stream")
+                    .headers(headers.clone())
+                    .send()
+                    .await;
+                let response = match response {
+                    Ok(response) if response.status().is_success() => response,
+                    Ok(response) => {
+                        debug!("{} has no notification stream ({})", url, response.status());
+                        return;
+                    }
+                    Err(e) => {
+                        debug!("Notification stream from {} failed: {}", url, e);
+                        tokio::time::sleep(LISTEN_RETRY_DELAY).await;
+                        continue;
+                    }
+                };
+
+                let mut stream = response.bytes_stream();
+                let mut decoder = SseDecoder::default();
+                while let Some(Ok(bytes)) = stream.next().await {
+                    for data in decoder.push(&bytes) {
+                        match serde_json::from_str::<JsonRpcIncoming>(&data) {
+                            Ok(JsonRpcIncoming::Server(message)) => {
+                                if let Ok(mut changes) = changes.lock() {
+                                    changes.record(&message);
+                                }
+                            }
+                            _ => debug!("Notification stream ignored: {}", data),
+                        }
+                    }
+                }
+                tokio::time::sleep(LISTEN_RETRY_DELAY).await;
+            }
+        })
+    }
+}
+
+impl Drop for HttpSession {
+    fn drop(&mut self) {
+        if let Some(listener) = self.listener.get_mut().ok().and_then(Option::take) {
+            listener.abort();
+        }
+    }
+}
+
+#[allow(clippy::large_enum_variant)]
 pub enum McpTransport {
     Stdio {
         process: Child,
         stdin: ChildStdin,
-        stdout: BufReader<ChildStdout>,
+        stdout: MessageReader,
+        stderr: StderrCapture,
     },
     Http {
         client: HttpClient,
         url: String,
+        session: HttpSession,
+    },
+    WebSocket {
+        socket: WebSocketSink,
+        messages: MessageReader,
     },
 }
 
+type WebSocketSink = SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, WsMessage>;
+
+/// The connection a stdio or WebSocket server exchanges JSON-RPC messages
+/// over, one message per line or frame.
+enum Channel<'a> {
+    Stdio {
+        stdin: &'a mut ChildStdin,
+        stdout: &'a mut MessageReader,
+    },
+    WebSocket {
+        socket: &'a mut WebSocketSink,
+        messages: &'a mut MessageReader,
+    },
+}
+
+impl Channel<'_> {
+    fn label(&self) -> &'static str {
+        match self {
+            Self::Stdio { .. } => "Stdio",
+            Self::WebSocket { .. } => "WebSocket",
+        }
+    }
+
+    async fn write(&mut self, message: String) -> Result<()> {
+        debug!("{} sending: {}", self.label(), message);
+        match self {
+            Self::Stdio { stdin, .. } => {
+                stdin.write_all(format!("{}\n", message).as_bytes()).await?;
+                stdin.flush().await?;
+            }
+            Self::WebSocket { socket, .. } => socket.send(WsMessage::Text(message)).await?,
+        }
+        Ok(())
+    }
+
+    /// Waits for the next response. Messages the server sends on its own
+    /// are handled by the reader and never taken for a reply.
+    async fn read_response(&mut self) -> Result<JsonRpcResponse> {
+        match self {
+            Self::Stdio { stdout, .. } => stdout.next_response().await,
+            Self::WebSocket { messages, .. } => messages.next_response().await,
+        }
+    }
+}
+
+/// Builds the configured headers for a remote server, adding
+/// `Authorization: Bearer` when an `auth_token` is given.
+fn header_map(headers: &HashMap<String, String>, auth_token: Option<&str>) -> Result<HeaderMap> {
+    let mut header_map = HeaderMap::new();
+    for (name, value) in headers {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|e| anyhow!("Invalid header name '{}': {}", name, e))?;
+        let mut value = HeaderValue::from_str(value)
+            .map_err(|e| anyhow!("Invalid value for header '{}': {}", name, e))?;
+        value.set_sensitive(true);
+        header_map.insert(name, value);
+    }
+    if let Some(token) = auth_token {
+        let mut value = HeaderValue::from_str(&format!("Bearer {}", token))
+            .map_err(|e| anyhow!("Invalid auth_token: {}", e))?;
+        value.set_sensitive(true);
+        header_map.insert(AUTHORIZATION, value);
+    }
+    Ok(header_map)
+}
+
 impl McpTransport {
     pub fn spawn_stdio(
+        name: &str,
         command: &str,
         args: &[String],
         env: &HashMap<String, String>,
@@ -43,6 +450,7 @@ impl McpTransport {
         process_cmd.stdin(Stdio::piped());
         process_cmd.stdout(Stdio::piped());
         process_cmd.stderr(Stdio::piped());
+        process_cmd.kill_on_drop(true);
 
         for (key, value) in env {
             if !value.is_empty() {
@@ -56,67 +464,266 @@ impl McpTransport {
 
         let stdin = process.stdin.take().context("Failed to get stdin")?;
         let stdout = process.stdout.take().context("Failed to get stdout")?;
+        let stderr = process.stderr.take().context("Failed to get stderr")?;
 
         Ok(Self::Stdio {
             process,
             stdin,
-            stdout: BufReader::new(stdout),
+            stdout: MessageReader::stdio(stdout),
+            stderr: StderrCapture::spawn(name, stderr),
         })
     }
 
-    pub fn http(client: HttpClient, url: String) -> Self {
-        Self::Http { client, url }
+    /// Creates an HTTP transport that sends `headers` on every request,
+    /// plus `Authorization: Bearer` when an `auth_token` is given.
+    pub fn http(
+        client: HttpClient,
+        url: String,
+        headers: &HashMap<String, String>,
+        auth_token: Option<&str>,
+    ) -> Result<Self> {
+        Ok(Self::Http {
+            client,
+            url,
+            session: HttpSession::new(header_map(headers, auth_token)?),
+        })
+    }
+
+    /// Opens a WebSocket to the server, sending the same configured
+    /// headers an HTTP server would get with the handshake.
+    pub async fn websocket(
+        url: &str,
+        headers: &HashMap<String, String>,
+        auth_token: Option<&str>,
+    ) -> Result<Self> {
+        let mut request = url.into_client_request()?;
+        request
+            .headers_mut()
+            .extend(header_map(headers, auth_token)?);
+        let (socket, _) = tokio_tungstenite::connect_async(request).await?;
+        let (socket, messages) = socket.split();
+        Ok(Self::WebSocket {
+            socket,
+            messages: MessageReader::websocket(messages),
+        })
+    }
+
+    /// Takes the list changes announced since the last call.
+    pub fn take_list_changes(&mut self) -> ListChanges {
+        match self {
+            Self::Stdio { stdout, .. } => ListChanges::take(&stdout.changes),
+            Self::Http { session, .. } => ListChanges::take(&session.changes),
+            Self::WebSocket { messages, .. } => ListChanges::take(&messages.changes),
+        }
+    }
+
+    /// The last lines a stdio server wrote to stderr.
+    pub fn stderr_tail(&self) -> Vec<String> {
+        match self {
+            Self::Stdio { stderr, .. } => stderr.tail(),
+            Self::Http { .. } | Self::WebSocket { .. } => Vec::new(),
+        }
+    }
+
+    /// Whether a stdio server's process has exited, waiting briefly for it
+    /// to finish exiting. Remote servers have no process to check.
+    pub async fn has_exited(&mut self) -> bool {
+        match self {
+            Self::Stdio { process, .. } => matches!(
+                tokio::time::timeout(EXIT_SETTLE_TIME, process.wait()).await,
+                Ok(Ok(_))
+            ),
+            Self::Http { .. } | Self::WebSocket { .. } => false,
+        }
     }
 
     pub async fn send(&mut self, request: &JsonRpcRequest) -> Result<JsonRpcResponse> {
         match self {
-            Self::Stdio { stdin, stdout, .. } => {
-                Self::send_stdio(stdin, stdout, request).await
+            Self::Stdio {
+                stdin,
+                stdout,
+                stderr,
+                ..
+            } => {
+                let mut channel = Channel::Stdio { stdin, stdout };
+                let result = Self::send_framed(&mut channel, request).await;
+                stderr.annotate(result).await
             }
-            Self::Http { client, url } => {
-                Self::send_http(client, url, request).await
+            Self::Http {
+                client,
+                url,
+                session,
+            } => Self::send_http(client, url, session, request).await,
+            Self::WebSocket { socket, messages } => {
+                Self::send_framed(&mut Channel::WebSocket { socket, messages }, request).await
             }
         }
     }
 
-    async fn send_stdio(
-        stdin: &mut ChildStdin,
-        stdout: &mut BufReader<ChildStdout>,
-        request: &JsonRpcRequest,
-    ) -> Result<JsonRpcResponse> {
-        let request_str = serde_json::to_string(request)?;
-        debug!("Stdio sending: {}", request_str);
+    /// Sends several requests at once and returns their responses in request
+    /// order. HTTP requests run concurrently; stdio and WebSocket requests
+    /// are all written before responses are read back and matched by id. A
+    /// failed call only fails its own entry, except when the connection
+    /// itself breaks.
+    pub async fn send_batch(
+        &mut self,
+        requests: &[JsonRpcRequest],
+    ) -> Vec<Result<JsonRpcResponse>> {
+        match self {
+            Self::Stdio {
+                stdin,
+                stdout,
+                stderr,
+                ..
+            } => {
+                let mut channel = Channel::Stdio { stdin, stdout };
+                let mut results = Vec::with_capacity(requests.len());
+                for result in Self::send_framed_batch(&mut channel, requests).await {
+                    results.push(stderr.annotate(result).await);
+                }
+                results
+            }
+            Self::Http {
+                client,
+                url,
+                session,
+            } => {
+                join_all(
+                    requests
+                        .iter()
+                        .map(|r| Self::send_http(client, url, session, r)),
+                )
+                .await
+            }
+            Self::WebSocket { socket, messages } => {
+                let mut channel = Channel::WebSocket { socket, messages };
+                Self::send_framed_batch(&mut channel, requests).await
+            }
+        }
+    }
 
-        stdin
-            .write_all(format!("{}\n", request_str).as_bytes())
-            .await?;
-        stdin.flush().await?;
+    async fn send_framed_batch(
+        channel: &mut Channel<'_>,
+        requests: &[JsonRpcRequest],
+    ) -> Vec<Result<JsonRpcResponse>> {
+        let mut responses: HashMap<u64, JsonRpcResponse> = HashMap::new();
+        let mut failure = None;
+        let mut unexpected = 0;
 
-        loop {
-            let mut line = String::new();
-            let bytes_read = stdout.read_line(&mut line).await?;
+        for request in requests {
+            if let Err(e) = Self::write_request(channel, request).await {
+                failure = Some(e.to_string());
+                break;
+            }
+        }
 
-            if bytes_read == 0 {
-                anyhow::bail!("Server closed stdout unexpectedly");
+        while failure.is_none() && responses.len() < requests.len() {
+            match channel.read_response().await {
+                Ok(response) => match response.id {
+                    Some(id) if requests.iter().any(|r| r.id() == id) => {
+                        responses.insert(id, response);
+                    }
+                    _ => {
+                        debug!(
+                            "{} ignored response with unexpected id {:?}",
+                            channel.label(),
+                            response.id
+                        );
+                        unexpected += 1;
+                        if unexpected > MAX_UNEXPECTED_RESPONSES {
+                            failure = Some(format!("{} responses with unexpected ids", unexpected));
+                        }
+                    }
+                },
+                Err(e) => failure = Some(e.to_string()),
             }
+        }
+
+        requests
+            .iter()
+            .map(|request| {
+                responses.remove(&request.id()).ok_or_else(|| {
+                    anyhow!(
+                        "No response to request {}: {}",
+                        request.id(),
+                        failure.as_deref().unwrap_or("missing")
+                    )
+                })
+            })
+            .collect()
+    }
 
-            let trimmed = line.trim();
-            if trimmed.is_empty() {
-                continue;
+    pub async fn notify(&mut self, notification: &JsonRpcNotification) -> Result<()> {
+        match self {
+            Self::Stdio {
+                stdin,
+                stdout,
+                stderr,
+                ..
+            } => {
+                let notification_str = serde_json::to_string(notification)?;
+                let result = Channel::Stdio { stdin, stdout }
+                    .write(notification_str)
+                    .await;
+                stderr.annotate(result).await?;
+            }
+            Self::WebSocket { socket, messages } => {
+                let notification_str = serde_json::to_string(notification)?;
+                Channel::WebSocket { socket, messages }
+                    .write(notification_str)
+                    .await?;
+            }
+            Self::Http {
+                client,
+                url,
+                session,
+            } => {
+                client
+                    .post(url.as_str())
+                    .header("Accept", "application/json, text/event-stream")
+                    .headers(session.headers())
+                    .json(notification)
+                    .send()
+                    .await?;
             }
+        }
+        Ok(())
+    }
 
-            if let Ok(response) = serde_json::from_str::<JsonRpcResponse>(trimmed) {
-                debug!("Stdio received: {}", trimmed);
+    async fn send_framed(
+        channel: &mut Channel<'_>,
+        request: &JsonRpcRequest,
+    ) -> Result<JsonRpcResponse> {
+        Self::write_request(channel, request).await?;
+        for _ in 0..=MAX_UNEXPECTED_RESPONSES {
+            let response = channel.read_response().await?;
+            if response.id == Some(request.id()) {
                 return Ok(response);
             }
-
-            debug!("Stdio ignored non-JSON-RPC: {}", trimmed);
+            debug!(
+                "{} ignored response with unexpected id {:?}",
+                channel.label(),
+                response.id
+            );
         }
+        anyhow::bail!(
+            "No response to request {} after {} responses with unexpected ids",
+            request.id(),
+            MAX_UNEXPECTED_RESPONSES + 1
+        )
+    }
+
+    async fn write_request(channel: &mut Channel<'_>, request: &JsonRpcRequest) -> Result<()> {
+        channel.write(serde_json::to_string(request)?).await
     }
 
+    /// Posts a request within the server's session. An SSE reply may carry
+    /// notifications ahead of the response, which are recorded like those
+    /// on the session's own stream.
     async fn send_http(
         client: &HttpClient,
         url: &str,
+        session: &HttpSession,
         request: &JsonRpcRequest,
     ) -> Result<JsonRpcResponse> {
         debug!("HTTP request to {}: {:?}", url, request);
@@ -124,10 +731,19 @@ impl McpTransport {
         let http_response = client
             .post(url)
             .header("Accept", "application/json, text/event-stream")
+            .headers(session.headers())
             .json(request)
             .send()
             .await?;
 
+        if let Some(id) = http_response
+            .headers()
+            .get(SESSION_HEADER)
+            .and_then(|v| v.to_str().ok())
+        {
+            session.start(client, url, id.to_string());
+        }
+
         let content_type = http_response
             .headers()
             .get("content-type")
@@ -136,25 +752,76 @@ impl McpTransport {
             .to_string();
 
         let body = http_response.text().await?;
-        debug!("HTTP response ({}): {}", content_type, &body[..body.len().min(500)]);
+        let snippet: String = body.chars().take(500).collect();
+        debug!("HTTP response ({}): {}", content_type, snippet);
 
-        let json_str = if content_type.contains("text/event-stream") {
-            parse_sse_response(&body)
-        } else {
-            body
-        };
+        if !content_type.contains("text/event-stream") {
+            return serde_json::from_str(&body)
+                .context(format!("Failed to parse JSON-RPC response: {}", snippet));
+        }
 
-        serde_json::from_str(&json_str).context(format!(
-            "Failed to parse JSON-RPC response: {}",
-            &json_str[..json_str.len().min(200)]
-        ))
+        let mut response = None;
+        for data in sse::parse_events(&body) {
+            match serde_json::from_str::<JsonRpcIncoming>(&data) {
+                Ok(JsonRpcIncoming::Response(r)) if r.id == Some(request.id()) => {
+                    response = Some(r);
+                }
+                Ok(JsonRpcIncoming::Response(r)) => {
+                    debug!("HTTP ignored response with unexpected id {:?}", r.id)
+                }
+                Ok(JsonRpcIncoming::Server(message)) => session.record(&message),
+                Err(_) => debug!("HTTP ignored non-JSON-RPC event: {}", data),
+            }
+        }
+        response.ok_or_else(|| anyhow!("No JSON-RPC response in event stream: {}", snippet))
     }
 }
 
-impl Drop for McpTransport {
-    fn drop(&mut self) {
-        if let Self::Stdio { process, .. } = self {
-            let _ = process.start_kill();
+impl McpTransport {
+    /// Ends the connection cleanly. A stdio server has its stdin closed,
+    /// which tells it to exit, and is killed if it hasn't within `grace`;
+    /// an HTTP server is asked to end its session; a WebSocket is closed.
+    pub async fn close(self, name: &str, grace: Duration) {
+        match self {
+            Self::Stdio {
+                mut process, stdin, ..
+            } => {
+                drop(stdin);
+                match tokio::time::timeout(grace, process.wait()).await {
+                    Ok(Ok(status)) => info!("MCP server {} exited ({})", name, status),
+                    Ok(Err(e)) => warn!("Failed to wait for MCP server {}: {}", name, e),
+                    Err(_) => {
+                        warn!(
+                            "MCP server {} did not exit within {:?}; killing it",
+                            name, grace
+                        );
+                        if let Err(e) = process.kill().await {
+                            warn!("Failed to kill MCP server {}: {}", name, e);
+                        }
+                    }
+                }
+            }
+            Self::Http {
+                client,
+                url,
+                session,
+            } => {
+                if session.id.lock().is_ok_and(|id| id.is_some()) {
+                    let request = client.delete(&url).headers(session.headers()).send();
+                    match tokio::time::timeout(grace, request).await {
+                        Ok(Ok(_)) => info!("Ended HTTP session with MCP server {}", name),
+                        Ok(Err(e)) => debug!("Failed to end session with {}: {}", name, e),
+                        Err(_) => debug!("Ending session with {} timed out", name),
+                    }
+                }
+            }
+            Self::WebSocket { mut socket, .. } => {
+                match tokio::time::timeout(grace, socket.close()).await {
+                    Ok(Ok(())) => info!("Closed WebSocket to MCP server {}", name),
+                    Ok(Err(e)) => debug!("Failed to close WebSocket to {}: {}", name, e),
+                    Err(_) => debug!("Closing WebSocket to {} timed out", name),
+                }
+            }
         }
     }
 }
@@ -1,113 +1,479 @@
 use anyhow::{Context, Result};
+use chrono::{DateTime, Duration, Utc};
+use futures::future::join_all;
 use reqwest::Client as HttpClient;
+use serde::de::DeserializeOwned;
 use serde_json::Value;
 use std::collections::{HashMap, HashSet};
 use std::env;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::sync::RwLock;
-use tracing::{debug, error, info};
+use std::time::Duration as StdDuration;
+use tokio::sync::{Mutex, OwnedMutexGuard, RwLock};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
 
+use crate::config::Config;
 use crate::mcp::connection::McpTransport;
-use crate::mcp::protocol::{create_init_params, JsonRpcRequest};
-use crate::mcp::types::{McpConfig, McpResource, McpServerConfig, McpServerInfo, McpTool};
+use crate::mcp::protocol::{create_init_params, JsonRpcNotification, JsonRpcRequest};
+use crate::mcp::types::{
+    McpConfig, McpContent, McpPrompt, McpResource, McpResourceContents, McpServerConfig,
+    McpServerInfo, McpServerState, McpTool,
+};
+use crate::retry::RetryBudget;
+
+const DEFAULT_RECONNECT_INTERVAL_MS: u64 = 30_000;
+const DEFAULT_MAX_TOOLS_PER_SERVER: usize = 128;
+const DEFAULT_HTTP_CONNECT_TIMEOUT_MS: u64 = 10_000;
+const DEFAULT_HTTP_TIMEOUT_MS: u64 = 60_000;
+const DEFAULT_REQUEST_TIMEOUT_MS: u64 = 30_000;
+const DEFAULT_RESTART_ATTEMPTS: u32 = 3;
+const DEFAULT_RESTART_BACKOFF_MS: u64 = 500;
+const MAX_RESTART_BACKOFF_MS: u64 = 30_000;
+const DEFAULT_ENV_MAX_DEPTH: usize = 8;
+const DEFAULT_SHUTDOWN_TIMEOUT_MS: u64 = 5_000;
+
+/// The name clients and the agent use to address an MCP tool: the length of
+/// the server name, the server name and the tool name, joined with `_`.
+/// Either name may contain underscores, and the length says where the
+/// server name ends, so `10_github_api_create_issue` can only be tool
+/// `create_issue` of server `github_api`.
+pub fn full_tool_name(server: &str, tool: &str) -> String {
+    format!("{}_{}_{}", server.chars().count(), server, tool)
+}
+
+/// Splits a name built by [`full_tool_name`] into server and tool.
+pub fn split_full_tool_name(full_name: &str) -> Option<(&str, &str)> {
+    let (len, rest) = full_name.split_once('_')?;
+    if len.is_empty() || !len.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let end = rest.char_indices().nth(len.parse().ok()?)?.0;
+    let (server, tool) = rest.split_at(end);
+    let tool = tool.strip_prefix('_')?;
+    (!server.is_empty() && !tool.is_empty()).then_some((server, tool))
+}
 
 struct McpServerInstance {
     name: String,
     transport: McpTransport,
     request_id: u64,
+    timeout: StdDuration,
+    /// Set when a request timed out or the process exited. A stdio server
+    /// may still answer a timed-out request later, so the connection can't
+    /// be trusted and is replaced.
+    needs_reconnect: bool,
+    max_tools: usize,
+    /// What the server declared it supports in its initialize result.
+    capabilities: Option<Value>,
     tools: Vec<McpTool>,
-    #[allow(dead_code)]
     resources: Vec<McpResource>,
+    prompts: Vec<McpPrompt>,
 }
 
 impl McpServerInstance {
-    fn new(name: String, transport: McpTransport) -> Self {
+    fn new(name: String, transport: McpTransport, timeout: StdDuration) -> Self {
         Self {
             name,
             transport,
             request_id: 0,
+            timeout,
+            needs_reconnect: false,
+            max_tools: DEFAULT_MAX_TOOLS_PER_SERVER,
+            capabilities: None,
             tools: Vec::new(),
             resources: Vec::new(),
+            prompts: Vec::new(),
         }
     }
 
+    /// Sends independent requests together; each result is reported
+    /// separately so one failing call doesn't affect the others.
+    async fn send_requests(&mut self, methods: &[&str]) -> Vec<Result<Value>> {
+        let requests: Vec<JsonRpcRequest> = methods
+            .iter()
+            .map(|method| {
+                self.request_id += 1;
+                JsonRpcRequest::new(self.request_id, method, None)
+            })
+            .collect();
+
+        let Ok(responses) =
+            tokio::time::timeout(self.timeout, self.transport.send_batch(&requests)).await
+        else {
+            let error = self.timed_out();
+            return methods
+                .iter()
+                .map(|_| Err(anyhow::anyhow!("{}", error)))
+                .collect();
+        };
+
+        responses
+            .into_iter()
+            .map(|response| {
+                response?
+                    .into_result()
+                    .context(format!("MCP server '{}'", self.name))
+            })
+            .collect()
+    }
+
     async fn send_request(&mut self, method: &str, params: Option<Value>) -> Result<Value> {
         self.request_id += 1;
         let request = JsonRpcRequest::new(self.request_id, method, params);
 
-        let response = self.transport.send(&request).await?;
-        response
+        let Ok(response) = tokio::time::timeout(self.timeout, self.transport.send(&request)).await
+        else {
+            return Err(self.timed_out());
+        };
+        self.refresh_changed_lists().await;
+        response?
             .into_result()
             .context(format!("MCP server '{}'", self.name))
     }
 
-    async fn initialize(&mut self) -> Result<()> {
-        let init_params = create_init_params();
+    /// Re-lists tools or resources the server announced as changed since
+    /// they were last listed.
+    async fn refresh_changed_lists(&mut self) {
+        let changes = self.transport.take_list_changes();
+        let methods: Vec<&str> = [
+            (changes.tools, "tools/list"),
+            (changes.resources, "resources/list"),
+        ]
+        .into_iter()
+        .filter_map(|(changed, method)| changed.then_some(method))
+        .collect();
+        if methods.is_empty() {
+            return;
+        }
+
+        info!(
+            "[{}] Server lists changed, refreshing {:?}",
+            self.name, methods
+        );
+        let results = self.send_requests(&methods).await;
+        for (method, result) in methods.into_iter().zip(results) {
+            match (method, result) {
+                ("tools/list", Ok(result)) => self.set_tools(&result),
+                (_, Ok(result)) => self.set_resources(&result),
+                (_, Err(e)) => warn!("[{}] Failed to refresh {}: {:#}", self.name, method, e),
+            }
+        }
+    }
+
+    fn set_tools(&mut self, tools_result: &Value) {
+        let Some(tools) = tools_result.get("tools") else {
+            return;
+        };
+        self.tools = self.parse_list("tool", tools);
+
+        if self.tools.len() > self.max_tools {
+            warn!(
+                "[{}] Server listed {} tools, keeping the first {}",
+                self.name,
+                self.tools.len(),
+                self.max_tools
+            );
+            self.tools.truncate(self.max_tools);
+        }
+        info!(
+            "[{}] Discovered {} tools: {:?}",
+            self.name,
+            self.tools.len(),
+            self.tools.iter().map(|t| &t.name).collect::<Vec<_>>()
+        );
+    }
+
+    fn set_resources(&mut self, resources_result: &Value) {
+        if let Some(resources) = resources_result.get("resources") {
+            self.resources = self.parse_list("resource", resources);
+            info!(
+                "[{}] Discovered {} resources",
+                self.name,
+                self.resources.len()
+            );
+        }
+    }
+
+    fn timed_out(&mut self) -> anyhow::Error {
+        warn!(
+            "[{}] No response within {:?}, marking for reconnection",
+            self.name, self.timeout
+        );
+        self.needs_reconnect = true;
+        anyhow::anyhow!(
+            "MCP server '{}' did not respond within {:?}",
+            self.name,
+            self.timeout
+        )
+    }
+
+    async fn notify(&mut self, method: &str, params: Option<Value>) -> Result<()> {
+        self.transport
+            .notify(&JsonRpcNotification::new(method, params))
+            .await
+    }
+
+    async fn initialize(&mut self, config: &McpServerConfig) -> Result<()> {
+        self.max_tools = config.max_tools.unwrap_or(DEFAULT_MAX_TOOLS_PER_SERVER);
+        let init_params =
+            create_init_params(config.capabilities.as_ref(), config.client_info.as_ref());
         let init_result = self.send_request("initialize", Some(init_params)).await?;
         debug!("[{}] Initialize result: {:?}", self.name, init_result);
+        self.capabilities = init_result.get("capabilities").cloned();
 
-        let _ = self
-            .send_request("notifications/initialized", None)
-            .await;
+        self.notify("notifications/initialized", None).await?;
 
-        if let Ok(tools_result) = self.send_request("tools/list", None).await {
-            if let Some(tools) = tools_result.get("tools") {
-                self.tools = serde_json::from_value(tools.clone()).unwrap_or_default();
-                info!(
-                    "[{}] Discovered {} tools: {:?}",
-                    self.name,
-                    self.tools.len(),
-                    self.tools.iter().map(|t| &t.name).collect::<Vec<_>>()
-                );
-            }
+        // Discovery calls are independent, so they are sent together.
+        let mut results = self
+            .send_requests(&["tools/list", "resources/list", "prompts/list"])
+            .await
+            .into_iter();
+        let mut next = || {
+            results
+                .next()
+                .unwrap_or_else(|| Err(anyhow::anyhow!("No response")))
+        };
+        let (tools_result, resources_result, prompts_result) = (next(), next(), next());
+
+        if let Ok(tools_result) = tools_result {
+            self.set_tools(&tools_result);
+        }
+
+        if let Ok(resources_result) = resources_result {
+            self.set_resources(&resources_result);
         }
 
-        // Discover resources
-        if let Ok(resources_result) = self.send_request("resources/list", None).await {
-            if let Some(resources) = resources_result.get("resources") {
-                self.resources = serde_json::from_value(resources.clone()).unwrap_or_default();
-                info!("[{}] Discovered {} resources", self.name, self.resources.len());
+        if let Ok(prompts_result) = prompts_result {
+            if let Some(prompts) = prompts_result.get("prompts") {
+                self.prompts = self.parse_list("prompt", prompts);
+                info!("[{}] Discovered {} prompts", self.name, self.prompts.len());
             }
         }
 
         Ok(())
     }
+
+    /// Parses a discovery list entry by entry, so one malformed entry (e.g. a
+    /// tool without a name) is skipped instead of emptying the whole list.
+    fn parse_list<T: DeserializeOwned>(&self, kind: &str, items: &Value) -> Vec<T> {
+        let Some(items) = items.as_array() else {
+            warn!(
+                "[{}] Expected a list of {}s, got {}",
+                self.name, kind, items
+            );
+            return Vec::new();
+        };
+
+        items
+            .iter()
+            .filter_map(|item| match serde_json::from_value(item.clone()) {
+                Ok(parsed) => Some(parsed),
+                Err(e) => {
+                    warn!("[{}] Skipping invalid {} {}: {}", self.name, kind, item, e);
+                    None
+                }
+            })
+            .collect()
+    }
 }
 
+/// A connected server. Each has its own lock, so requests to different
+/// servers run concurrently while requests to one server take turns.
+type SharedServer = Arc<Mutex<McpServerInstance>>;
+
 pub struct McpManager {
-    servers: Arc<RwLock<HashMap<String, McpServerInstance>>>,
+    servers: Arc<RwLock<HashMap<String, SharedServer>>>,
     config: Arc<RwLock<McpConfig>>,
     enabled_servers: Arc<RwLock<HashSet<String>>>,
+    next_reconnect_at: Arc<RwLock<HashMap<String, DateTime<Utc>>>>,
+    allowed_commands: Option<HashSet<String>>,
+    /// Where enable/disable toggles are saved; `None` keeps them in memory.
+    state_path: Option<PathBuf>,
     http_client: HttpClient,
+    request_timeout: StdDuration,
+    restarts: Arc<RwLock<HashMap<String, u32>>>,
+    last_stderr: Arc<RwLock<HashMap<String, Vec<String>>>>,
+    restart_attempts: u32,
+    restart_backoff: StdDuration,
+    shutdown_timeout: StdDuration,
+}
+
+/// How a tool call ended, separating a server that died from one that
+/// answered with an error.
+enum CallOutcome {
+    Done(Result<Value>),
+    Crashed(anyhow::Error),
 }
 
 impl McpManager {
     pub fn load_config<P: AsRef<Path>>(path: P) -> Result<McpConfig> {
         let content = std::fs::read_to_string(path)?;
-        let content = Self::expand_env_vars(&content);
+        let content = Self::expand_env_vars(&content)?;
         let config: McpConfig = serde_json::from_str(&content)?;
         Ok(config)
     }
 
-    fn expand_env_vars(content: &str) -> String {
-        let mut result = content.to_string();
+    /// Replaces `${VAR}` references with their environment values. Values
+    /// are expanded in turn, up to `MCP_ENV_MAX_DEPTH` levels, so a variable
+    /// may refer to others; a cycle is an error.
+    fn expand_env_vars(content: &str) -> Result<String> {
+        let max_depth = env::var("MCP_ENV_MAX_DEPTH")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_ENV_MAX_DEPTH);
         let re = regex::Regex::new(r"\$\{(\w+)\}").unwrap();
+        Self::expand_nested(&re, content, &mut Vec::new(), max_depth)
+    }
 
-        for cap in re.captures_iter(content) {
+    /// `expanding` holds the variables whose values are being expanded,
+    /// outermost first.
+    fn expand_nested(
+        re: &regex::Regex,
+        text: &str,
+        expanding: &mut Vec<String>,
+        max_depth: usize,
+    ) -> Result<String> {
+        let mut result = String::with_capacity(text.len());
+        let mut last = 0;
+
+        for cap in re.captures_iter(text) {
+            let reference = cap.get(0).unwrap();
             let var_name = &cap[1];
+            result.push_str(&text[last..reference.start()]);
+            last = reference.end();
+
+            if expanding.iter().any(|name| name == var_name) {
+                anyhow::bail!(
+                    "Cycle in MCP config variables: {} -> {}",
+                    expanding.join(" -> "),
+                    var_name
+                );
+            }
+            if expanding.len() >= max_depth {
+                anyhow::bail!(
+                    "MCP config variable {} is nested more than {} levels deep",
+                    var_name,
+                    max_depth
+                );
+            }
+
+            expanding.push(var_name.to_string());
             let var_value = env::var(var_name).unwrap_or_default();
-            result = result.replace(&cap[0], &var_value);
+            result.push_str(&Self::expand_nested(re, &var_value, expanding, max_depth)?);
+            expanding.pop();
+        }
+
+        result.push_str(&text[last..]);
+        Ok(result)
+    }
+
+    /// Reads `MCP_ALLOWED_COMMANDS`, a comma-separated list of executables
+    /// stdio servers may be started with. Unset means no restriction.
+    fn allowed_commands_from_env() -> Option<HashSet<String>> {
+        let value = env::var("MCP_ALLOWED_COMMANDS").ok()?;
+        Some(
+            value
+                .split(',')
+                .map(|c| c.trim().to_string())
+                .filter(|c| !c.is_empty())
+                .collect(),
+        )
+    }
+
+    /// An executable is allowed if either its full path or its file name is
+    /// on the allowlist, so `npx` also matches `/usr/bin/npx`.
+    fn check_command_allowed(&self, command: &str) -> Result<()> {
+        let Some(allowed) = &self.allowed_commands else {
+            return Ok(());
+        };
+
+        let executable = command.split_whitespace().next().unwrap_or_default();
+        let file_name = Path::new(executable)
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or(executable);
+
+        if allowed.contains(executable) || allowed.contains(file_name) {
+            Ok(())
+        } else {
+            anyhow::bail!("Command '{}' is not in MCP_ALLOWED_COMMANDS", executable)
+        }
+    }
+
+    fn env_millis(name: &str, default: u64) -> StdDuration {
+        let millis = env::var(name)
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(default);
+        StdDuration::from_millis(millis)
+    }
+
+    /// Client for HTTP-transport servers, with its own connect and request
+    /// timeouts (`MCP_HTTP_CONNECT_TIMEOUT_MS`, `MCP_HTTP_TIMEOUT_MS`) so a
+    /// slow server fails the call instead of hanging it.
+    fn build_http_client() -> HttpClient {
+        HttpClient::builder()
+            .user_agent(Config::user_agent())
+            .connect_timeout(Self::env_millis(
+                "MCP_HTTP_CONNECT_TIMEOUT_MS",
+                DEFAULT_HTTP_CONNECT_TIMEOUT_MS,
+            ))
+            .timeout(Self::env_millis(
+                "MCP_HTTP_TIMEOUT_MS",
+                DEFAULT_HTTP_TIMEOUT_MS,
+            ))
+            .build()
+            .expect("Failed to create MCP HTTP client")
+    }
+
+    fn load_state(path: &Path) -> McpServerState {
+        match std::fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+                warn!("Ignoring invalid MCP state file {}: {}", path.display(), e);
+                McpServerState::default()
+            }),
+            Err(_) => McpServerState::default(),
         }
+    }
+
+    /// Records an enable/disable toggle in the state file, when there is
+    /// one, so it survives restarts. Failures are logged; the in-memory
+    /// change still applies.
+    fn persist_enabled(&self, name: &str, enabled: bool) {
+        let Some(ref state_path) = self.state_path else {
+            return;
+        };
+        let mut state = Self::load_state(state_path);
+        state.enabled.insert(name.to_string(), enabled);
 
-        result
+        let result = serde_json::to_string_pretty(&state)
+            .map_err(anyhow::Error::from)
+            .and_then(|json| Ok(std::fs::write(state_path, json)?));
+        if let Err(e) = result {
+            warn!(
+                "Failed to save MCP state to {}: {}",
+                state_path.display(),
+                e
+            );
+        }
     }
 
+    /// Toggles are only persisted when `MCP_STATE_PATH` names a state file.
     pub fn new(config: McpConfig) -> Self {
+        Self::with_state_path(config, env::var("MCP_STATE_PATH").ok().map(PathBuf::from))
+    }
+
+    pub(super) fn with_state_path(config: McpConfig, state_path: Option<PathBuf>) -> Self {
+        let state = state_path
+            .as_deref()
+            .map(Self::load_state)
+            .unwrap_or_default();
+
         let enabled: HashSet<String> = config
             .mcp_servers
             .iter()
-            .filter(|(_, cfg)| !cfg.disabled)
+            .filter(|(name, cfg)| state.enabled.get(*name).copied().unwrap_or(!cfg.disabled))
             .map(|(name, _)| name.clone())
             .collect();
 
@@ -115,7 +481,22 @@ impl McpManager {
             servers: Arc::new(RwLock::new(HashMap::new())),
             config: Arc::new(RwLock::new(config)),
             enabled_servers: Arc::new(RwLock::new(enabled)),
-            http_client: HttpClient::new(),
+            next_reconnect_at: Arc::new(RwLock::new(HashMap::new())),
+            allowed_commands: Self::allowed_commands_from_env(),
+            state_path,
+            http_client: Self::build_http_client(),
+            request_timeout: Self::env_millis("MCP_REQUEST_TIMEOUT_MS", DEFAULT_REQUEST_TIMEOUT_MS),
+            restarts: Arc::new(RwLock::new(HashMap::new())),
+            last_stderr: Arc::new(RwLock::new(HashMap::new())),
+            restart_attempts: env::var("MCP_RESTART_ATTEMPTS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_RESTART_ATTEMPTS),
+            restart_backoff: Self::env_millis("MCP_RESTART_BACKOFF_MS", DEFAULT_RESTART_BACKOFF_MS),
+            shutdown_timeout: Self::env_millis(
+                "MCP_SHUTDOWN_TIMEOUT_MS",
+                DEFAULT_SHUTDOWN_TIMEOUT_MS,
+            ),
         }
     }
 
@@ -147,25 +528,155 @@ impl McpManager {
                     .as_ref()
                     .context("HTTP transport requires 'url' field")?;
                 info!("Connecting to MCP HTTP server: {} at {}", name, url);
-                McpTransport::http(self.http_client.clone(), url.clone())
+                McpTransport::http(
+                    self.http_client.clone(),
+                    url.clone(),
+                    &config.headers,
+                    config.auth_token.as_deref(),
+                )?
+            }
+            "websocket" | "ws" => {
+                let url = config
+                    .url
+                    .as_ref()
+                    .context("WebSocket transport requires 'url' field")?;
+                info!("Connecting to MCP WebSocket server: {} at {}", name, url);
+                McpTransport::websocket(url, &config.headers, config.auth_token.as_deref()).await?
             }
             _ => {
                 let command = config
                     .command
                     .as_ref()
                     .context("Stdio transport requires 'command' field")?;
+                self.check_command_allowed(command)?;
                 info!("Starting MCP server: {} ({})", name, command);
-                McpTransport::spawn_stdio(command, &config.args, &config.env)?
+                McpTransport::spawn_stdio(name, command, &config.args, &config.env)?
             }
         };
 
-        let mut instance = McpServerInstance::new(name.to_string(), transport);
-        instance.initialize().await?;
+        let timeout = config
+            .timeout_ms
+            .map_or(self.request_timeout, StdDuration::from_millis);
+        let mut instance = McpServerInstance::new(name.to_string(), transport, timeout);
+        if let Err(e) = instance.initialize(config).await {
+            self.keep_stderr(name, &instance.transport).await;
+            return Err(e);
+        }
 
-        self.servers.write().await.insert(name.to_string(), instance);
+        self.servers
+            .write()
+            .await
+            .insert(name.to_string(), Arc::new(Mutex::new(instance)));
+        self.next_reconnect_at.write().await.remove(name);
         Ok(())
     }
 
+    /// Keeps a server's stderr past the end of its connection, so a server
+    /// that won't start or keeps crashing can be diagnosed from its status.
+    async fn keep_stderr(&self, name: &str, transport: &McpTransport) {
+        self.last_stderr
+            .write()
+            .await
+            .insert(name.to_string(), transport.stderr_tail());
+    }
+
+    /// Reconnects an enabled server that is not currently connected. Attempts
+    /// are spaced by the server's minimum reconnect interval so a dead server
+    /// isn't respawned on every tool call.
+    async fn try_reconnect(&self, name: &str) -> Result<()> {
+        let server_config = self
+            .config
+            .read()
+            .await
+            .mcp_servers
+            .get(name)
+            .context(format!("Server {} not found in config", name))?
+            .clone();
+
+        {
+            let now = Utc::now();
+            let mut next_reconnect_at = self.next_reconnect_at.write().await;
+            if let Some(next) = next_reconnect_at.get(name) {
+                if *next > now {
+                    anyhow::bail!(
+                        "Server {} not connected; next reconnect attempt at {}",
+                        name,
+                        next.to_rfc3339()
+                    );
+                }
+            }
+
+            let interval = server_config
+                .reconnect_interval_ms
+                .unwrap_or(DEFAULT_RECONNECT_INTERVAL_MS);
+            next_reconnect_at.insert(
+                name.to_string(),
+                now + Duration::milliseconds(interval as i64),
+            );
+        }
+
+        info!("Reconnecting to MCP server: {}", name);
+        self.connect_server(name, &server_config)
+            .await
+            .inspect_err(|e| warn!("Reconnect to MCP server {} failed: {}", name, e))
+    }
+
+    /// Respawns a server whose process exited, making up to
+    /// `MCP_RESTART_ATTEMPTS` attempts with exponential backoff from
+    /// `MCP_RESTART_BACKOFF_MS`. Each attempt is a retry of the tool call,
+    /// so it is taken from the run's retry budget when there is one.
+    async fn restart_server(&self, name: &str, retry_budget: Option<&RetryBudget>) -> Result<()> {
+        let server_config = self
+            .config
+            .read()
+            .await
+            .mcp_servers
+            .get(name)
+            .context(format!("Server {} not found in config", name))?
+            .clone();
+
+        let mut attempt = 0;
+        loop {
+            if retry_budget.is_some_and(|b| !b.try_consume()) {
+                anyhow::bail!(
+                    "Not restarting MCP server {}: the run's retry budget is exhausted",
+                    name
+                );
+            }
+            attempt += 1;
+            match self.connect_server(name, &server_config).await {
+                Ok(()) => {
+                    let mut restarts = self.restarts.write().await;
+                    *restarts.entry(name.to_string()).or_default() += 1;
+                    info!("Restarted MCP server {} (attempt {})", name, attempt);
+                    return Ok(());
+                }
+                Err(e) if attempt >= self.restart_attempts => {
+                    return Err(e.context(format!(
+                        "Failed to restart MCP server {} after {} attempts",
+                        name, attempt
+                    )));
+                }
+                Err(e) => {
+                    let delay = self.restart_delay(attempt);
+                    warn!(
+                        "Restart of MCP server {} failed, retrying in {:?}: {}",
+                        name, delay, e
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        }
+    }
+
+    /// The wait after failed restart `attempt`, doubling from
+    /// `MCP_RESTART_BACKOFF_MS` up to a cap.
+    fn restart_delay(&self, attempt: u32) -> StdDuration {
+        self.restart_backoff
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(StdDuration::from_millis(MAX_RESTART_BACKOFF_MS))
+    }
+
     pub async fn enable_server(&self, name: &str) -> Result<()> {
         let config = self.config.read().await;
         let server_config = config
@@ -176,6 +687,7 @@ impl McpManager {
         drop(config);
 
         self.enabled_servers.write().await.insert(name.to_string());
+        self.persist_enabled(name, true);
 
         if !self.servers.read().await.contains_key(name) {
             self.connect_server(name, &server_config).await?;
@@ -187,6 +699,7 @@ impl McpManager {
 
     pub async fn disable_server(&self, name: &str) -> Result<()> {
         self.enabled_servers.write().await.remove(name);
+        self.persist_enabled(name, false);
 
         if self.servers.write().await.remove(name).is_some() {
             info!("Disabled and disconnected MCP server: {}", name);
@@ -198,18 +711,33 @@ impl McpManager {
     }
 
     pub async fn get_servers_status(&self) -> Vec<McpServerInfo> {
+        // Servers are locked first, as each may be busy with a request, so
+        // the other maps aren't held while waiting on them.
+        let mut connected = HashMap::new();
+        for (name, server) in self.sorted_servers(false).await {
+            connected.insert(name, server.lock_owned().await);
+        }
+
         let config = self.config.read().await;
         let enabled = self.enabled_servers.read().await;
-        let servers = self.servers.read().await;
+        let next_reconnect_at = self.next_reconnect_at.read().await;
+        let restarts = self.restarts.read().await;
+        let last_stderr = self.last_stderr.read().await;
 
         config
             .mcp_servers
             .iter()
             .map(|(name, cfg)| {
-                let connected_instance = servers.get(name);
+                let connected_instance = connected.get(name).map(|guard| &**guard);
                 let tools: Vec<String> = connected_instance
                     .map(|i| i.tools.iter().map(|t| t.name.clone()).collect())
                     .unwrap_or_default();
+                let resources: Vec<String> = connected_instance
+                    .map(|i| i.resources.iter().map(|r| r.uri.clone()).collect())
+                    .unwrap_or_default();
+                let prompts: Vec<String> = connected_instance
+                    .map(|i| i.prompts.iter().map(|p| p.name.clone()).collect())
+                    .unwrap_or_default();
 
                 McpServerInfo {
                     name: name.clone(),
@@ -221,48 +749,250 @@ impl McpManager {
                         .unwrap_or_else(|| "stdio".to_string()),
                     tools_count: tools.len(),
                     tools,
+                    resources_count: resources.len(),
+                    resources,
+                    prompts,
+                    capabilities: connected_instance.and_then(|i| i.capabilities.clone()),
+                    next_reconnect_at: next_reconnect_at.get(name).map(|t| t.to_rfc3339()),
+                    restarts: restarts.get(name).copied().unwrap_or_default(),
+                    last_errors: match connected_instance {
+                        Some(instance) => instance.transport.stderr_tail(),
+                        None => last_stderr.get(name).cloned().unwrap_or_default(),
+                    },
                 }
             })
             .collect()
     }
 
-    pub async fn get_all_tools(&self) -> Vec<(String, McpTool)> {
+    /// Connected servers sorted by name, optionally only the enabled ones.
+    /// The maps are released before the caller locks any server.
+    async fn sorted_servers(&self, enabled_only: bool) -> Vec<(String, SharedServer)> {
         let servers = self.servers.read().await;
         let enabled = self.enabled_servers.read().await;
+
+        let mut sorted: Vec<(String, SharedServer)> = servers
+            .iter()
+            .filter(|(name, _)| !enabled_only || enabled.contains(*name))
+            .map(|(name, server)| (name.clone(), server.clone()))
+            .collect();
+        sorted.sort_by(|(a, _), (b, _)| a.cmp(b));
+        sorted
+    }
+
+    /// Locks a connected server for a request. A server that a request
+    /// ahead in line marked for reconnection counts as not connected.
+    async fn lock_server(
+        &self,
+        name: &str,
+    ) -> Result<(SharedServer, OwnedMutexGuard<McpServerInstance>)> {
+        let server = self
+            .servers
+            .read()
+            .await
+            .get(name)
+            .cloned()
+            .context(format!("Server {} not connected", name))?;
+        let instance = server.clone().lock_owned().await;
+        if instance.needs_reconnect {
+            anyhow::bail!("Server {} not connected", name);
+        }
+        Ok((server, instance))
+    }
+
+    /// Drops a server from the connected set, unless a reconnect already
+    /// replaced it. Dropping the instance closes its connection.
+    async fn remove_server(&self, name: &str, server: &SharedServer) {
+        let mut servers = self.servers.write().await;
+        if servers.get(name).is_some_and(|s| Arc::ptr_eq(s, server)) {
+            servers.remove(name);
+        }
+    }
+
+    /// Locks a server to read what it listed, first re-listing anything it
+    /// announced as changed. A server that fails to answer is dropped.
+    async fn lock_refreshed(
+        &self,
+        name: &str,
+        server: &SharedServer,
+    ) -> Option<OwnedMutexGuard<McpServerInstance>> {
+        let mut instance = server.clone().lock_owned().await;
+        instance.refresh_changed_lists().await;
+        if instance.needs_reconnect {
+            drop(instance);
+            self.remove_server(name, server).await;
+            return None;
+        }
+        Some(instance)
+    }
+
+    pub async fn get_all_tools(&self) -> Vec<(String, McpTool)> {
         let mut all_tools = Vec::new();
 
-        for (server_name, instance) in servers.iter() {
-            if enabled.contains(server_name) {
-                for tool in &instance.tools {
-                    all_tools.push((server_name.clone(), tool.clone()));
-                }
+        // Servers are visited by name so the tool order is stable; each
+        // server's tools keep the order it listed them in.
+        for (server_name, server) in self.sorted_servers(true).await {
+            let Some(instance) = self.lock_refreshed(&server_name, &server).await else {
+                continue;
+            };
+            for tool in &instance.tools {
+                all_tools.push((server_name.clone(), tool.clone()));
             }
         }
 
         all_tools
     }
 
+    /// Resources of every enabled server, in the same order as
+    /// [`McpManager::get_all_tools`].
+    pub async fn list_resources(&self) -> Vec<(String, McpResource)> {
+        let mut all_resources = Vec::new();
+
+        for (server_name, server) in self.sorted_servers(true).await {
+            let Some(instance) = self.lock_refreshed(&server_name, &server).await else {
+                continue;
+            };
+            for resource in &instance.resources {
+                all_resources.push((server_name.clone(), resource.clone()));
+            }
+        }
+
+        all_resources
+    }
+
+    /// Reads a resource with `resources/read`. A resource can have several
+    /// parts, each holding either `text` or a base64 `blob`.
+    pub async fn read_resource(
+        &self,
+        server_name: &str,
+        uri: &str,
+    ) -> Result<Vec<McpResourceContents>> {
+        if !self.enabled_servers.read().await.contains(server_name) {
+            anyhow::bail!("Server {} is disabled", server_name);
+        }
+
+        if !self.servers.read().await.contains_key(server_name) {
+            self.try_reconnect(server_name).await?;
+        }
+
+        let (server, mut instance) = self.lock_server(server_name).await?;
+        let result = instance
+            .send_request("resources/read", Some(serde_json::json!({ "uri": uri })))
+            .await;
+        if instance.needs_reconnect {
+            drop(instance);
+            self.remove_server(server_name, &server).await;
+        }
+
+        let contents = result?
+            .get_mut("contents")
+            .map(Value::take)
+            .context("No contents in resources/read result")?;
+        serde_json::from_value(contents).context("Invalid resource contents")
+    }
+
     pub async fn call_tool(
         &self,
         server_name: &str,
         tool_name: &str,
         arguments: Value,
+        cancel: &CancellationToken,
+        retry_budget: Option<&RetryBudget>,
     ) -> Result<Value> {
         if !self.enabled_servers.read().await.contains(server_name) {
             anyhow::bail!("Server {} is disabled", server_name);
         }
 
-        let mut servers = self.servers.write().await;
-        let instance = servers
-            .get_mut(server_name)
-            .context(format!("Server {} not connected", server_name))?;
+        if !self.servers.read().await.contains_key(server_name) {
+            self.try_reconnect(server_name).await?;
+        }
 
         let params = serde_json::json!({
             "name": tool_name,
             "arguments": arguments
         });
 
-        instance.send_request("tools/call", Some(params)).await
+        match self
+            .send_tool_call(server_name, tool_name, params.clone(), cancel)
+            .await?
+        {
+            CallOutcome::Done(result) => result,
+            CallOutcome::Crashed(e) if self.restart_attempts > 0 => {
+                warn!(
+                    "[{}] Server process exited, restarting: {:#}",
+                    server_name, e
+                );
+                self.restart_server(server_name, retry_budget).await?;
+                match self
+                    .send_tool_call(server_name, tool_name, params, cancel)
+                    .await?
+                {
+                    CallOutcome::Done(result) => result,
+                    CallOutcome::Crashed(e) => Err(e),
+                }
+            }
+            CallOutcome::Crashed(e) => Err(e),
+        }
+    }
+
+    /// Sends one `tools/call`. A server whose process exited is removed
+    /// and reported as crashed so the caller can restart it.
+    async fn send_tool_call(
+        &self,
+        server_name: &str,
+        tool_name: &str,
+        params: Value,
+        cancel: &CancellationToken,
+    ) -> Result<CallOutcome> {
+        let (server, mut instance) = self.lock_server(server_name).await?;
+        let result = tokio::select! {
+            result = instance.send_request("tools/call", Some(params)) => Some(result),
+            _ = cancel.cancelled() => None,
+        };
+
+        let result = match result {
+            Some(result) => result,
+            None => {
+                let request_id = instance.request_id;
+                info!(
+                    "[{}] Cancelling tool call {} ({})",
+                    server_name, tool_name, request_id
+                );
+                let params = serde_json::json!({
+                    "requestId": request_id,
+                    "reason": "Client disconnected"
+                });
+                if let Err(e) = instance
+                    .notify("notifications/cancelled", Some(params))
+                    .await
+                {
+                    warn!("[{}] Failed to send cancellation: {}", server_name, e);
+                }
+                return Ok(CallOutcome::Done(Err(anyhow::anyhow!(
+                    "Tool call {} on {} was cancelled",
+                    tool_name,
+                    server_name
+                ))));
+            }
+        };
+
+        match result {
+            Err(e) if instance.transport.has_exited().await => {
+                self.keep_stderr(server_name, &instance.transport).await;
+                // Calls queued behind this one must not use the dead process.
+                instance.needs_reconnect = true;
+                drop(instance);
+                self.remove_server(server_name, &server).await;
+                Ok(CallOutcome::Crashed(e))
+            }
+            result => {
+                // The next call reconnects through `try_reconnect`.
+                if instance.needs_reconnect {
+                    drop(instance);
+                    self.remove_server(server_name, &server).await;
+                }
+                Ok(CallOutcome::Done(result))
+            }
+        }
     }
 
     pub async fn call_tool_by_full_name(
@@ -270,15 +1000,27 @@ impl McpManager {
         full_name: &str,
         arguments: Value,
     ) -> Result<Value> {
-        let parts: Vec<&str> = full_name.splitn(2, '_').collect();
-        if parts.len() != 2 {
-            anyhow::bail!("Invalid tool name format: {}", full_name);
-        }
+        let (server_name, tool_name) = self.resolve_full_name(full_name).await?;
 
-        let server_name = parts[0];
-        let tool_name = parts[1];
+        self.call_tool(
+            &server_name,
+            &tool_name,
+            arguments,
+            &CancellationToken::new(),
+            None,
+        )
+        .await
+    }
 
-        self.call_tool(server_name, tool_name, arguments).await
+    /// Splits a name built by [`full_tool_name`] back into server and tool,
+    /// checking that the server is configured.
+    pub async fn resolve_full_name(&self, full_name: &str) -> Result<(String, String)> {
+        let (server, tool) = split_full_tool_name(full_name)
+            .context(format!("{} is not an MCP tool name", full_name))?;
+        if !self.config.read().await.mcp_servers.contains_key(server) {
+            anyhow::bail!("No MCP server named {}", server);
+        }
+        Ok((server.to_string(), tool.to_string()))
     }
 
     pub async fn call_tool_text(
@@ -286,8 +1028,12 @@ impl McpManager {
         server_name: &str,
         tool_name: &str,
         arguments: Value,
+        cancel: &CancellationToken,
+        retry_budget: Option<&RetryBudget>,
     ) -> Result<String> {
-        let result = self.call_tool(server_name, tool_name, arguments).await?;
+        let result = self
+            .call_tool(server_name, tool_name, arguments, cancel, retry_budget)
+            .await?;
         Ok(Self::extract_text(&result))
     }
 
@@ -296,7 +1042,7 @@ impl McpManager {
             if let Some(arr) = content.as_array() {
                 return arr
                     .iter()
-                    .filter_map(|item| item.get("text").and_then(|t| t.as_str()))
+                    .map(Self::content_item_text)
                     .collect::<Vec<_>>()
                     .join("\n");
             }
@@ -305,7 +1051,605 @@ impl McpManager {
         result.to_string()
     }
 
+    fn content_item_text(item: &Value) -> String {
+        let kind = item
+            .get("type")
+            .and_then(|t| t.as_str())
+            .unwrap_or("unknown");
+
+        match serde_json::from_value::<McpContent>(item.clone()) {
+            Ok(McpContent::Text { text }) => text,
+            Ok(McpContent::Image { mime_type }) | Ok(McpContent::Audio { mime_type }) => format!(
+                "[{} content ({})]",
+                kind,
+                mime_type.as_deref().unwrap_or("unknown type")
+            ),
+            Ok(McpContent::Resource { resource }) => match resource.text {
+                Some(text) => text,
+                None => format!("[binary resource: {}]", resource.uri),
+            },
+            Ok(McpContent::ResourceLink { uri }) => format!("[resource link: {}]", uri),
+            Ok(McpContent::Unknown) => format!("[unsupported content type: {}]", kind),
+            Err(e) => format!("[malformed {} content: {}]", kind, e),
+        }
+    }
+
     pub async fn connected_servers(&self) -> Vec<String> {
         self.servers.read().await.keys().cloned().collect()
     }
+
+    /// Disconnects every server at once, giving each up to
+    /// `MCP_SHUTDOWN_TIMEOUT_MS` to exit before it is killed.
+    pub async fn shutdown(&self) {
+        let servers: Vec<_> = self.servers.write().await.drain().collect();
+        if servers.is_empty() {
+            return;
+        }
+
+        info!("Stopping {} MCP servers", servers.len());
+        join_all(servers.into_iter().map(|(name, server)| async move {
+            match Arc::try_unwrap(server) {
+                Ok(instance) => {
+                    let instance = instance.into_inner();
+                    instance.transport.close(&name, self.shutdown_timeout).await
+                }
+                // A call still holds the server; its process is killed once
+                // the call lets go of it.
+                Err(_) => warn!("MCP server {} is still in use, not waiting for it", name),
+            }
+        }))
+        .await;
+        info!("All MCP servers stopped");
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::mcp::stub::{
+        manager, named_tool_server, stub_server, stub_server_with, HttpStub, REPLY_DONE,
+    };
+    use serde_json::json;
+    use std::time::Instant;
+
+    /// Exits on the first tool call of all its processes, then answers.
+    const CRASH_ONCE: &str = r#"if [ ! -e "$STUB_MARK" ]; then : > "$STUB_MARK"; exit 1; fi; reply '{"content":[{"type":"text","text":"done"}]}'"#;
+
+    fn temp_path() -> PathBuf {
+        env::temp_dir().join(format!("mcp-stub-{}", uuid::Uuid::new_v4()))
+    }
+
+    /// Sets `$STUB_MARK` to a fresh path, for a stub to keep state in
+    /// across restarts and background jobs.
+    fn with_mark(mut config: McpServerConfig) -> (McpServerConfig, PathBuf) {
+        let mark = temp_path();
+        config
+            .env
+            .insert("STUB_MARK".to_string(), mark.display().to_string());
+        (config, mark)
+    }
+
+    #[test]
+    fn full_tool_names_round_trip() {
+        for (server, tool) in [
+            ("github_api", "create_issue"),
+            ("github", "api_create_issue"),
+            ("a", "b"),
+            ("weather.v2", "get_forecast"),
+            ("ünïcode", "tool_"),
+        ] {
+            let full_name = full_tool_name(server, tool);
+            assert_eq!(
+                split_full_tool_name(&full_name),
+                Some((server, tool)),
+                "{}",
+                full_name
+            );
+        }
+        assert_eq!(
+            full_tool_name("github_api", "create_issue"),
+            "10_github_api_create_issue"
+        );
+        assert_ne!(
+            full_tool_name("github_api", "create_issue"),
+            full_tool_name("github", "api_create_issue")
+        );
+    }
+
+    #[test]
+    fn malformed_full_tool_names_are_rejected() {
+        for name in [
+            "",
+            "github_api_create_issue",
+            "10_github_api",
+            "3_abc_",
+            "_3_abc_x",
+            "+3_abc_x",
+        ] {
+            assert_eq!(split_full_tool_name(name), None, "{}", name);
+        }
+    }
+
+    #[test]
+    fn nested_config_variables_are_expanded() {
+        env::set_var("MCP_TEST_HOST", "example.com");
+        env::set_var("MCP_TEST_URL", "https://${MCP_TEST_HOST}/mcp");
+        let expanded = McpManager::expand_env_vars(r#"{"url": "${MCP_TEST_URL}"}"#).unwrap();
+        assert_eq!(expanded, r#"{"url": "https://example.com/mcp"}"#);
+
+        let re = regex::Regex::new(r"\$\{(\w+)\}").unwrap();
+        let error =
+            McpManager::expand_nested(&re, "${MCP_TEST_URL}", &mut Vec::new(), 1).unwrap_err();
+        assert!(
+            error.to_string().contains("nested more than 1 levels"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn config_variable_cycles_are_rejected() {
+        env::set_var("MCP_TEST_PING", "${MCP_TEST_PONG}");
+        env::set_var("MCP_TEST_PONG", "x${MCP_TEST_PING}");
+        let error = McpManager::expand_env_vars("${MCP_TEST_PING}").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Cycle in MCP config variables: MCP_TEST_PING -> MCP_TEST_PONG -> MCP_TEST_PING"
+        );
+    }
+
+    #[tokio::test]
+    async fn full_tool_names_reach_the_right_server() {
+        let manager = Arc::new(manager(vec![
+            (
+                "github_api",
+                named_tool_server("create_issue", "from github_api"),
+            ),
+            (
+                "github",
+                named_tool_server("api_create_issue", "from github"),
+            ),
+        ]));
+        manager.connect_all().await.unwrap();
+
+        for (server, tool) in [
+            ("github_api", "create_issue"),
+            ("github", "api_create_issue"),
+        ] {
+            let full_name = full_tool_name(server, tool);
+            assert_eq!(
+                manager.resolve_full_name(&full_name).await.unwrap(),
+                (server.to_string(), tool.to_string())
+            );
+            let result = manager
+                .call_tool_by_full_name(&full_name, json!({}))
+                .await
+                .unwrap();
+            assert_eq!(
+                McpManager::extract_text(&result),
+                format!("from {}", server)
+            );
+        }
+        assert!(manager.resolve_full_name("5_other_tool").await.is_err());
+
+        // The agent offers the same names behind its prefix.
+        let config = crate::test_support::test_config(String::new());
+        let prefix = config.mcp_tool_prefix.clone();
+        let agent = crate::agent::Agent::new(config, Some(manager.clone()));
+        let names: HashSet<String> = agent
+            .get_tools()
+            .await
+            .into_iter()
+            .map(|t| t.function.name)
+            .collect();
+        assert!(names.contains(&format!("{}10_github_api_create_issue", prefix)));
+        assert!(names.contains(&format!("{}6_github_api_create_issue", prefix)));
+    }
+
+    #[tokio::test]
+    async fn initialized_notification_does_not_wait_for_a_reply() {
+        let mut config = stub_server(REPLY_DONE);
+        config.timeout_ms = Some(1_000);
+        let manager = manager(vec![("stub", config)]);
+
+        let started = Instant::now();
+        manager.connect_all().await.unwrap();
+        assert!(
+            started.elapsed() < StdDuration::from_millis(1_000),
+            "connecting took {:?}",
+            started.elapsed()
+        );
+
+        let result = manager
+            .call_tool_text("stub", "work", json!({}), &CancellationToken::new(), None)
+            .await;
+        assert_eq!(result.unwrap(), "done");
+        assert_eq!(manager.connected_servers().await, vec!["stub".to_string()]);
+    }
+
+    /// Holds the `tools/list` reply until `prompts/list` has been read, so
+    /// discovery only finishes when its requests are sent together.
+    const ANSWER_TOOLS_AFTER_PROMPTS: &str = r#"
+list_tools() { tools_id=$id; }
+list_prompts() {
+  reply '{"prompts":[{"name":"summarize"}]}'
+  id=$tools_id
+  reply '{"tools":[{"name":"work","inputSchema":{"type":"object"}}]}'
+}
+"#;
+
+    #[tokio::test]
+    async fn discovery_requests_are_sent_together() {
+        let mut config = stub_server_with(ANSWER_TOOLS_AFTER_PROMPTS, REPLY_DONE);
+        config.timeout_ms = Some(1_000);
+        let manager = manager(vec![("stub", config)]);
+        manager.connect_all().await.unwrap();
+
+        let status = manager.get_servers_status().await;
+        assert!(status[0].connected);
+        assert_eq!(status[0].tools, ["work"]);
+        assert_eq!(status[0].prompts, ["summarize"]);
+    }
+
+    #[tokio::test]
+    async fn declared_capabilities_are_reported() {
+        let manager = manager(vec![("stub", stub_server(REPLY_DONE))]);
+        assert_eq!(manager.get_servers_status().await[0].capabilities, None);
+
+        manager.connect_all().await.unwrap();
+        let status = manager.get_servers_status().await;
+        assert_eq!(status[0].capabilities, Some(json!({"tools": {}})));
+    }
+
+    #[tokio::test]
+    async fn tools_past_the_cap_are_dropped() {
+        let prelude = r#"list_tools() { reply '{"tools":[{"name":"a","inputSchema":{}},{"name":"b","inputSchema":{}},{"name":"c","inputSchema":{}},{"name":"d","inputSchema":{}}]}'; }"#;
+        let mut config = stub_server_with(prelude, REPLY_DONE);
+        config.max_tools = Some(2);
+        let manager = manager(vec![("stub", config)]);
+        manager.connect_all().await.unwrap();
+
+        let status = manager.get_servers_status().await;
+        assert_eq!(status[0].tools, ["a", "b"]);
+        assert_eq!(status[0].tools_count, 2);
+    }
+
+    #[tokio::test]
+    async fn calls_to_different_servers_run_concurrently() {
+        let slow = format!("sleep 1; {}", REPLY_DONE);
+        let manager = manager(vec![
+            ("slow_a", stub_server(&slow)),
+            ("slow_b", stub_server(&slow)),
+        ]);
+        manager.connect_all().await.unwrap();
+        let cancel = CancellationToken::new();
+
+        let started = Instant::now();
+        let (a, b) = tokio::join!(
+            manager.call_tool_text("slow_a", "work", json!({}), &cancel, None),
+            manager.call_tool_text("slow_b", "work", json!({}), &cancel, None),
+        );
+        let elapsed = started.elapsed();
+
+        assert_eq!(a.unwrap(), "done");
+        assert_eq!(b.unwrap(), "done");
+        assert!(
+            elapsed < StdDuration::from_millis(1800),
+            "two 1s calls took {:?}",
+            elapsed
+        );
+    }
+
+    #[tokio::test]
+    async fn unanswered_call_times_out() {
+        // The tool never answers.
+        let mut config = stub_server(":");
+        config.timeout_ms = Some(200);
+        let manager = manager(vec![("stub", config)]);
+        manager.connect_all().await.unwrap();
+
+        let started = Instant::now();
+        let error = manager
+            .call_tool_text("stub", "work", json!({}), &CancellationToken::new(), None)
+            .await
+            .unwrap_err();
+        let elapsed = started.elapsed();
+
+        assert!(
+            error.to_string().contains("did not respond within"),
+            "{}",
+            error
+        );
+        assert!(
+            elapsed < StdDuration::from_millis(1_000),
+            "a 200ms timeout took {:?}",
+            elapsed
+        );
+        assert!(manager.connected_servers().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn notification_before_the_response_is_skipped() {
+        let on_call = format!(
+            r#"printf '%s\n' '{{"jsonrpc":"2.0","method":"notifications/progress","params":{{"progress":1}}}}'; {}"#,
+            REPLY_DONE
+        );
+        let manager = manager(vec![("stub", stub_server(&on_call))]);
+        manager.connect_all().await.unwrap();
+
+        let result = manager
+            .call_tool_text("stub", "work", json!({}), &CancellationToken::new(), None)
+            .await;
+        assert_eq!(result.unwrap(), "done");
+    }
+
+    #[tokio::test]
+    async fn response_with_another_id_is_skipped() {
+        let on_call = format!(
+            r#"printf '%s\n' '{{"jsonrpc":"2.0","id":999,"result":{{"content":[{{"type":"text","text":"stray"}}]}}}}'; {}"#,
+            REPLY_DONE
+        );
+        let manager = manager(vec![("stub", stub_server(&on_call))]);
+        manager.connect_all().await.unwrap();
+
+        let result = manager
+            .call_tool_text("stub", "work", json!({}), &CancellationToken::new(), None)
+            .await;
+        assert_eq!(result.unwrap(), "done");
+    }
+
+    #[tokio::test]
+    async fn http_session_id_is_sent_back() {
+        let server = HttpStub::start().await;
+        let manager = manager(vec![("remote", server.config(json!({})))]);
+        manager.connect_all().await.unwrap();
+
+        let result = manager
+            .call_tool_text("remote", "work", json!({}), &CancellationToken::new(), None)
+            .await;
+        assert_eq!(result.unwrap(), "done");
+
+        let requests = server.requests();
+        let session = |headers: &axum::http::HeaderMap| {
+            headers
+                .get("mcp-session-id")
+                .map(|v| v.to_str().unwrap().to_string())
+        };
+        assert_eq!(requests[0].0, "initialize");
+        assert_eq!(session(&requests[0].1), None);
+        assert_eq!(requests.last().unwrap().0, "tools/call");
+        for (method, headers) in &requests[1..] {
+            assert_eq!(session(headers).as_deref(), Some("session-1"), "{}", method);
+        }
+    }
+
+    #[tokio::test]
+    async fn http_auth_headers_are_sent() {
+        let server = HttpStub::start().await;
+        let config = server.config(json!({
+            "auth_token": "secret",
+            "headers": {"X-Team": "agents"}
+        }));
+        let manager = manager(vec![("remote", config)]);
+        manager.connect_all().await.unwrap();
+        let result = manager
+            .call_tool_text("remote", "work", json!({}), &CancellationToken::new(), None)
+            .await;
+        assert_eq!(result.unwrap(), "done");
+
+        let requests = server.requests();
+        assert!(requests.len() > 1);
+        for (method, headers) in &requests {
+            assert_eq!(headers["authorization"], "Bearer secret", "{}", method);
+            assert_eq!(headers["x-team"], "agents", "{}", method);
+        }
+    }
+
+    #[tokio::test]
+    async fn crashed_server_is_restarted_from_the_retry_budget() {
+        let (config, mark) = with_mark(stub_server(CRASH_ONCE));
+        let mut manager = manager(vec![("flaky", config)]);
+        manager.restart_backoff = StdDuration::from_millis(10);
+        manager.connect_all().await.unwrap();
+        let budget = RetryBudget::new(2);
+
+        let result = manager
+            .call_tool_text(
+                "flaky",
+                "work",
+                json!({}),
+                &CancellationToken::new(),
+                Some(&budget),
+            )
+            .await;
+        let _ = std::fs::remove_file(mark);
+
+        assert_eq!(result.unwrap(), "done");
+        assert_eq!(manager.restarts.read().await.get("flaky"), Some(&1));
+        assert_eq!(budget.remaining(), 1);
+    }
+
+    #[tokio::test]
+    async fn crashed_server_is_not_restarted_without_retry_budget() {
+        let (config, mark) = with_mark(stub_server(CRASH_ONCE));
+        let manager = manager(vec![("flaky", config)]);
+        manager.connect_all().await.unwrap();
+
+        let error = manager
+            .call_tool_text(
+                "flaky",
+                "work",
+                json!({}),
+                &CancellationToken::new(),
+                Some(&RetryBudget::new(0)),
+            )
+            .await
+            .unwrap_err();
+        let _ = std::fs::remove_file(mark);
+
+        assert!(
+            error.to_string().contains("retry budget is exhausted"),
+            "{}",
+            error
+        );
+        assert!(manager.connected_servers().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn quick_failures_share_one_reconnect_attempt() {
+        // Counts its starts in `$STUB_MARK` and exits without answering.
+        let config: McpServerConfig = serde_json::from_value(json!({
+            "command": "sh",
+            "args": ["-c", "echo started >> \"$STUB_MARK\""],
+            "timeout_ms": 1000
+        }))
+        .unwrap();
+        let (config, mark) = with_mark(config);
+        let manager = manager(vec![("dead", config)]);
+        let cancel = CancellationToken::new();
+
+        let first = manager
+            .call_tool("dead", "work", json!({}), &cancel, None)
+            .await;
+        let second = manager
+            .call_tool("dead", "work", json!({}), &cancel, None)
+            .await;
+        let starts = std::fs::read_to_string(&mark).unwrap_or_default();
+        let _ = std::fs::remove_file(mark);
+
+        assert!(first.is_err());
+        let second = second.unwrap_err().to_string();
+        assert!(second.contains("next reconnect attempt at"), "{}", second);
+        assert_eq!(starts.lines().count(), 1);
+        let status = manager.get_servers_status().await;
+        assert!(status[0].next_reconnect_at.is_some());
+    }
+
+    #[test]
+    fn restart_backoff_is_capped() {
+        let manager = manager(Vec::new());
+        for attempt in [1, 40, u32::MAX] {
+            assert!(
+                manager.restart_delay(attempt) <= StdDuration::from_millis(MAX_RESTART_BACKOFF_MS)
+            );
+        }
+    }
+
+    /// Adds an `extra` tool half a second after starting, announcing it
+    /// with `tools/list_changed` while no request is in flight.
+    const LIST_CHANGES_WHEN_IDLE: &str = r#"
+list_tools() {
+  if [ -e "$STUB_MARK" ]; then
+    reply '{"tools":[{"name":"work","inputSchema":{"type":"object"}},{"name":"extra","inputSchema":{"type":"object"}}]}'
+  else
+    reply '{"tools":[{"name":"work","inputSchema":{"type":"object"}}]}'
+  fi
+}
+(sleep 0.5; : > "$STUB_MARK"; printf '%s\n' '{"jsonrpc":"2.0","method":"notifications/tools/list_changed"}') &
+"#;
+
+    #[tokio::test]
+    async fn tools_changed_while_idle_are_listed() {
+        let (config, mark) = with_mark(stub_server_with(LIST_CHANGES_WHEN_IDLE, REPLY_DONE));
+        let manager = manager(vec![("stub", config)]);
+        manager.connect_all().await.unwrap();
+        let tool_names = |tools: Vec<(String, McpTool)>| -> Vec<String> {
+            tools.into_iter().map(|(_, tool)| tool.name).collect()
+        };
+        assert_eq!(tool_names(manager.get_all_tools().await), ["work"]);
+
+        tokio::time::sleep(StdDuration::from_secs(1)).await;
+        let tools = manager.get_all_tools().await;
+        let _ = std::fs::remove_file(mark);
+
+        assert_eq!(tool_names(tools), ["work", "extra"]);
+    }
+
+    #[tokio::test]
+    async fn cancelled_call_notifies_the_server() {
+        // The tool never answers, so the call only ends by cancellation.
+        let mut config = stub_server(":");
+        let log = temp_path();
+        config
+            .env
+            .insert("STUB_LOG".to_string(), log.display().to_string());
+        let manager = manager(vec![("stub", config)]);
+        manager.connect_all().await.unwrap();
+
+        let cancel = CancellationToken::new();
+        let disconnect = async {
+            tokio::time::sleep(StdDuration::from_millis(100)).await;
+            cancel.cancel();
+        };
+        let (result, ()) = tokio::join!(
+            manager.call_tool_text("stub", "work", json!({}), &cancel, None),
+            disconnect,
+        );
+        assert!(result.unwrap_err().to_string().contains("was cancelled"));
+
+        // The stub logs each line as it reads it, which may be just after
+        // the call returned.
+        let sent = |method: &str, received: &[Value]| {
+            received
+                .iter()
+                .find(|message| message["method"] == method)
+                .cloned()
+        };
+        let mut received: Vec<Value> = Vec::new();
+        for _ in 0..50 {
+            received = std::fs::read_to_string(&log)
+                .unwrap_or_default()
+                .lines()
+                .filter_map(|line| serde_json::from_str(line).ok())
+                .collect();
+            if sent("notifications/cancelled", &received).is_some() {
+                break;
+            }
+            tokio::time::sleep(StdDuration::from_millis(20)).await;
+        }
+        let _ = std::fs::remove_file(log);
+
+        let call = sent("tools/call", &received).expect("tools/call was sent");
+        let cancellation =
+            sent("notifications/cancelled", &received).expect("cancellation was sent");
+        assert_eq!(cancellation["params"]["requestId"], call["id"]);
+        assert!(cancellation.get("id").is_none());
+    }
+
+    async fn enabled(manager: &McpManager, name: &str) -> bool {
+        manager
+            .get_servers_status()
+            .await
+            .into_iter()
+            .find(|info| info.name == name)
+            .is_some_and(|info| info.enabled)
+    }
+
+    fn stateful_manager(state_path: &Path) -> McpManager {
+        let config = McpConfig {
+            mcp_servers: HashMap::from([("stub".to_string(), stub_server(REPLY_DONE))]),
+        };
+        McpManager::with_state_path(config, Some(state_path.to_path_buf()))
+    }
+
+    #[tokio::test]
+    async fn toggles_survive_a_restart_with_a_state_file() {
+        let state_path = temp_path();
+        let manager = stateful_manager(&state_path);
+        manager.connect_all().await.unwrap();
+        assert!(enabled(&manager, "stub").await);
+
+        manager.disable_server("stub").await.unwrap();
+        manager.shutdown().await;
+
+        let reloaded = stateful_manager(&state_path);
+        reloaded.connect_all().await.unwrap();
+        assert!(!enabled(&reloaded, "stub").await);
+        assert!(reloaded.connected_servers().await.is_empty());
+
+        reloaded.enable_server("stub").await.unwrap();
+        let reloaded = stateful_manager(&state_path);
+        assert!(enabled(&reloaded, "stub").await);
+        std::fs::remove_file(&state_path).ok();
+    }
 }
@@ -1,7 +1,8 @@
 mod connection;
 mod manager;
 mod protocol;
+#[cfg(all(test, unix))]
+pub mod stub;
 mod types;
 
-pub use manager::McpManager;
-pub use types::{McpConfig, McpResource, McpServerConfig, McpServerInfo, McpTool};
+pub use manager::{full_tool_name, McpManager};
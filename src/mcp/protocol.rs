@@ -20,13 +20,35 @@ impl JsonRpcRequest {
             params,
         }
     }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+/// A JSON-RPC message without an id; the server sends no response to it.
+#[derive(Debug, Serialize)]
+pub struct JsonRpcNotification {
+    jsonrpc: String,
+    method: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    params: Option<Value>,
+}
+
+impl JsonRpcNotification {
+    pub fn new(method: &str, params: Option<Value>) -> Self {
+        Self {
+            jsonrpc: "2.0".to_string(),
+            method: method.to_string(),
+            params,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct JsonRpcResponse {
     #[allow(dead_code)]
     pub jsonrpc: String,
-    #[allow(dead_code)]
     pub id: Option<u64>,
     pub result: Option<Value>,
     pub error: Option<JsonRpcError>,
@@ -41,26 +63,34 @@ impl JsonRpcResponse {
     }
 }
 
+/// A message the server sent on its own initiative: a notification, or a
+/// request to the client when it carries an id.
+#[derive(Debug, Deserialize)]
+pub struct JsonRpcServerMessage {
+    #[serde(default)]
+    pub id: Option<Value>,
+    pub method: String,
+}
+
+/// Anything a server writes to the client. Server messages are tried first
+/// because they are the ones with a `method`.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum JsonRpcIncoming {
+    Server(JsonRpcServerMessage),
+    Response(JsonRpcResponse),
+}
+
 #[derive(Debug, Deserialize)]
 pub struct JsonRpcError {
     pub code: i64,
     pub message: String,
 }
 
-pub fn parse_sse_response(body: &str) -> String {
-    body.lines()
-        .filter(|line| line.starts_with("data:"))
-        .filter_map(|line| line.strip_prefix("data:").map(|s| s.trim()))
-        .filter(|s| !s.is_empty())
-        .last()
-        .unwrap_or(body)
-        .to_string()
-}
-
 pub const MCP_PROTOCOL_VERSION: &str = "2025-11-25";
 
-pub fn create_init_params() -> Value {
-    serde_json::json!({
+pub fn create_init_params(capabilities: Option<&Value>, client_info: Option<&Value>) -> Value {
+    let mut params = serde_json::json!({
         "protocolVersion": MCP_PROTOCOL_VERSION,
         "capabilities": {
             "roots": { "listChanged": true },
@@ -70,5 +100,13 @@ pub fn create_init_params() -> Value {
             "name": "llm-agent",
             "version": "0.1.0"
         }
-    })
+    });
+
+    if let Some(capabilities) = capabilities {
+        params["capabilities"] = capabilities.clone();
+    }
+    if let Some(client_info) = client_info {
+        params["clientInfo"] = client_info.clone();
+    }
+    params
 }
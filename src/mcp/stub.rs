@@ -0,0 +1,154 @@
+//! Scripted stdio and HTTP MCP servers for tests, and managers that run
+//! them.
+
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::post,
+    Json, Router,
+};
+use serde_json::{json, Value};
+use std::sync::{Arc, Mutex};
+
+use crate::mcp::types::{McpConfig, McpServerConfig};
+use crate::mcp::McpManager;
+
+/// A stdio MCP server in sh. It answers initialize and discovery with
+/// a single `work` tool, and runs ON_CALL for each `tools/call`, where
+/// `reply` answers the request. PRELUDE runs at startup and may redefine
+/// `list_tools` and `list_prompts`. Lines received are appended to `$STUB_LOG` when it is
+/// set.
+const STUB_SERVER: &str = r#"
+reply() { printf '{"jsonrpc":"2.0","id":%s,"result":%s}\n' "$id" "$1"; }
+list_tools() { reply '{"tools":[{"name":"work","inputSchema":{"type":"object"}}]}'; }
+list_prompts() { reply '{"prompts":[]}'; }
+PRELUDE
+while IFS= read -r line; do
+  [ -n "$STUB_LOG" ] && printf '%s\n' "$line" >> "$STUB_LOG"
+  id=$(printf '%s' "$line" | sed -n 's/.*"id":\([0-9]*\).*/\1/p')
+  case "$line" in
+    *'"method":"initialize"'*)
+      reply '{"protocolVersion":"2024-11-05","capabilities":{"tools":{}},"serverInfo":{"name":"stub","version":"1"}}' ;;
+    *'"method":"tools/list"'*) list_tools ;;
+    *'"method":"resources/list"'*) reply '{"resources":[]}' ;;
+    *'"method":"prompts/list"'*) list_prompts ;;
+    *'"method":"tools/call"'*) ON_CALL ;;
+  esac
+done
+"#;
+
+pub const REPLY_DONE: &str = r#"reply '{"content":[{"type":"text","text":"done"}]}'"#;
+
+pub fn stub_server(on_call: &str) -> McpServerConfig {
+    stub_server_with("", on_call)
+}
+
+pub fn stub_server_with(prelude: &str, on_call: &str) -> McpServerConfig {
+    let script = STUB_SERVER
+        .replace("PRELUDE", prelude)
+        .replace("ON_CALL", on_call);
+    serde_json::from_value(json!({ "command": "sh", "args": ["-c", script] }))
+        .expect("stub server config")
+}
+
+/// Lists a single tool named `tool` and answers calls with `text`.
+pub fn named_tool_server(tool: &str, text: &str) -> McpServerConfig {
+    let prelude = format!(
+        r#"list_tools() {{ reply '{{"tools":[{{"name":"{}","inputSchema":{{"type":"object"}}}}]}}'; }}"#,
+        tool
+    );
+    let on_call = format!(
+        r#"reply '{{"content":[{{"type":"text","text":"{}"}}]}}'"#,
+        text
+    );
+    stub_server_with(&prelude, &on_call)
+}
+
+/// A streamable-HTTP MCP server at `/mcp`. It assigns session `session-1`
+/// on initialize, answers other requests as SSE with a progress
+/// notification ahead of the response, and records the method and headers
+/// of every POST.
+pub struct HttpStub {
+    pub url: String,
+    requests: HttpRequests,
+}
+
+type HttpRequests = Arc<Mutex<Vec<(String, HeaderMap)>>>;
+
+impl HttpStub {
+    pub async fn start() -> Self {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let app = Router::new()
+            .route("/mcp", post(http_reply))
+            .with_state(requests.clone());
+        Self {
+            url: format!("{}/mcp", crate::test_support::serve(app).await),
+            requests,
+        }
+    }
+
+    /// A config for this server, with `extra` fields merged in.
+    pub fn config(&self, extra: Value) -> McpServerConfig {
+        let mut config = json!({ "type": "http", "url": self.url });
+        config
+            .as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().cloned().unwrap_or_default());
+        serde_json::from_value(config).expect("HTTP stub config")
+    }
+
+    pub fn requests(&self) -> Vec<(String, HeaderMap)> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+async fn http_reply(
+    State(requests): State<HttpRequests>,
+    headers: HeaderMap,
+    Json(body): Json<Value>,
+) -> Response {
+    let method = body["method"].as_str().unwrap_or_default().to_string();
+    requests.lock().unwrap().push((method.clone(), headers));
+
+    let result = match method.as_str() {
+        "initialize" => json!({
+            "protocolVersion": "2025-03-26",
+            "capabilities": {"tools": {}},
+            "serverInfo": {"name": "http-stub", "version": "1"}
+        }),
+        "tools/list" => json!({"tools": [{"name": "work", "inputSchema": {"type": "object"}}]}),
+        "resources/list" => json!({"resources": []}),
+        "prompts/list" => json!({"prompts": []}),
+        "tools/call" => json!({"content": [{"type": "text", "text": "done"}]}),
+        _ => return StatusCode::ACCEPTED.into_response(),
+    };
+    let response = json!({"jsonrpc": "2.0", "id": body["id"], "result": result});
+
+    if method == "initialize" {
+        return ([("Mcp-Session-Id", "session-1")], Json(response)).into_response();
+    }
+    let progress = json!({
+        "jsonrpc": "2.0",
+        "method": "notifications/progress",
+        "params": {"progress": 1}
+    });
+    (
+        [(header::CONTENT_TYPE, "text/event-stream")],
+        format!("data: {}\n\ndata: {}\n\n", progress, response),
+    )
+        .into_response()
+}
+
+/// A manager for `servers` that keeps toggles in memory.
+pub fn manager(servers: Vec<(&str, McpServerConfig)>) -> McpManager {
+    McpManager::with_state_path(
+        McpConfig {
+            mcp_servers: servers
+                .into_iter()
+                .map(|(name, config)| (name.to_string(), config))
+                .collect(),
+        },
+        None,
+    )
+}
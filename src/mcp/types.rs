@@ -1,6 +1,6 @@
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct McpServerConfig {
@@ -15,6 +15,28 @@ pub struct McpServerConfig {
     #[serde(rename = "type")]
     pub transport_type: Option<String>,
     pub url: Option<String>,
+    /// Extra headers sent on every request to an HTTP server.
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Sent to an HTTP server as `Authorization: Bearer <token>`.
+    #[serde(default)]
+    pub auth_token: Option<String>,
+    /// Minimum time between automatic reconnect attempts for this server.
+    #[serde(default)]
+    pub reconnect_interval_ms: Option<u64>,
+    /// How long a request to this server may take before it fails and the
+    /// server is reconnected. Defaults to `MCP_REQUEST_TIMEOUT_MS`.
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+    /// Most tools taken from this server's `tools/list`; extras are dropped.
+    #[serde(default)]
+    pub max_tools: Option<usize>,
+    /// Replaces the client capabilities declared to this server on initialize.
+    #[serde(default)]
+    pub capabilities: Option<Value>,
+    /// Replaces the `clientInfo` sent to this server on initialize.
+    #[serde(default)]
+    pub client_info: Option<Value>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -23,6 +45,15 @@ pub struct McpConfig {
     pub mcp_servers: HashMap<String, McpServerConfig>,
 }
 
+/// Enable/disable toggles made at runtime, keyed by server name. Stored
+/// separately from `mcp_config.json` and applied on top of its `disabled`
+/// flags at startup.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct McpServerState {
+    #[serde(default)]
+    pub enabled: BTreeMap<String, bool>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct McpTool {
     pub name: String,
@@ -31,6 +62,12 @@ pub struct McpTool {
     pub input_schema: Value,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct McpPrompt {
+    pub name: String,
+    pub description: Option<String>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct McpResource {
     pub uri: String,
@@ -40,6 +77,39 @@ pub struct McpResource {
     pub mime_type: Option<String>,
 }
 
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum McpContent {
+    Text {
+        text: String,
+    },
+    Image {
+        #[serde(rename = "mimeType")]
+        mime_type: Option<String>,
+    },
+    Audio {
+        #[serde(rename = "mimeType")]
+        mime_type: Option<String>,
+    },
+    Resource {
+        resource: McpResourceContents,
+    },
+    ResourceLink {
+        uri: String,
+    },
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct McpResourceContents {
+    pub uri: String,
+    #[serde(rename = "mimeType")]
+    pub mime_type: Option<String>,
+    pub text: Option<String>,
+    pub blob: Option<String>,
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct McpServerInfo {
     pub name: String,
@@ -48,4 +118,21 @@ pub struct McpServerInfo {
     pub transport_type: String,
     pub tools_count: usize,
     pub tools: Vec<String>,
+    pub resources_count: usize,
+    /// URIs of the resources the server listed.
+    pub resources: Vec<String>,
+    /// Names of the prompts the server listed.
+    pub prompts: Vec<String>,
+    /// Capabilities the server declared on initialize (`tools`, `resources`,
+    /// `prompts`, `logging`, ...), while it is connected.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub capabilities: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_reconnect_at: Option<String>,
+    /// Times the server was respawned after its process exited.
+    pub restarts: u32,
+    /// Last lines the server wrote to stderr; from its last process when
+    /// it failed to connect or crashed.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub last_errors: Vec<String>,
 }
@@ -0,0 +1,61 @@
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counters for how agent runs end, used to spot prompt or tool problems.
+#[derive(Default)]
+pub struct AgentMetrics {
+    runs_started: AtomicU64,
+    runs_failed: AtomicU64,
+    runs_iteration_limit: AtomicU64,
+    runs_retry_budget_exhausted: AtomicU64,
+    runs_timed_out: AtomicU64,
+    runs_step_limit: AtomicU64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AgentMetricsSnapshot {
+    pub runs_started: u64,
+    pub runs_failed: u64,
+    pub runs_iteration_limit: u64,
+    pub runs_retry_budget_exhausted: u64,
+    pub runs_timed_out: u64,
+    pub runs_step_limit: u64,
+}
+
+impl AgentMetrics {
+    pub fn record_run_started(&self) {
+        self.runs_started.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_run_failed(&self) {
+        self.runs_failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_iteration_limit(&self) {
+        self.runs_iteration_limit.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_retry_budget_exhausted(&self) {
+        self.runs_retry_budget_exhausted
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_timeout(&self) {
+        self.runs_timed_out.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_step_limit(&self) {
+        self.runs_step_limit.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> AgentMetricsSnapshot {
+        AgentMetricsSnapshot {
+            runs_started: self.runs_started.load(Ordering::Relaxed),
+            runs_failed: self.runs_failed.load(Ordering::Relaxed),
+            runs_iteration_limit: self.runs_iteration_limit.load(Ordering::Relaxed),
+            runs_retry_budget_exhausted: self.runs_retry_budget_exhausted.load(Ordering::Relaxed),
+            runs_timed_out: self.runs_timed_out.load(Ordering::Relaxed),
+            runs_step_limit: self.runs_step_limit.load(Ordering::Relaxed),
+        }
+    }
+}
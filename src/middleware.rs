@@ -0,0 +1,167 @@
+use axum::{
+    body::{to_bytes, Body},
+    extract::{Request, State},
+    http::header,
+    middleware::Next,
+    response::Response,
+};
+use std::sync::Arc;
+use subtle::ConstantTimeEq;
+use tracing::warn;
+
+use crate::error::AgentError;
+
+/// Requires one of `tokens` as a bearer token on every `/v1/*` request.
+/// Other paths, such as `/health`, stay open.
+pub async fn require_api_token(
+    State(tokens): State<Arc<Vec<String>>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AgentError> {
+    if request.uri().path().starts_with("/v1/") {
+        let provided = request
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+        match provided {
+            None => return Err(AgentError::Unauthorized("Missing API token".to_string())),
+            Some(token) if !tokens.iter().any(|t| token_matches(token, t)) => {
+                return Err(AgentError::Unauthorized("Invalid API token".to_string()))
+            }
+            Some(_) => {}
+        }
+    }
+    Ok(next.run(request).await)
+}
+
+/// Compares a provided token with an expected one in constant time, so
+/// response timing doesn't reveal how much of a guess was right.
+pub fn token_matches(provided: &str, expected: &str) -> bool {
+    provided.as_bytes().ct_eq(expected.as_bytes()).into()
+}
+
+/// Re-serializes JSON responses with indentation when the request has
+/// `?pretty=true`, for reading output from curl. Other responses, including
+/// SSE streams, pass through untouched.
+pub async fn pretty_json(request: Request, next: Next) -> Response {
+    let pretty = request
+        .uri()
+        .query()
+        .is_some_and(|q| q.split('&').any(|p| p == "pretty=true" || p == "pretty=1"));
+
+    let response = next.run(request).await;
+    if !pretty || !is_json(&response) {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let bytes = match to_bytes(body, usize::MAX).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            warn!("Failed to buffer response for pretty-printing: {}", e);
+            return Response::from_parts(parts, Body::empty());
+        }
+    };
+
+    let body = match serde_json::from_slice::<serde_json::Value>(&bytes)
+        .and_then(|value| serde_json::to_vec_pretty(&value))
+    {
+        Ok(pretty) => {
+            parts.headers.remove(header::CONTENT_LENGTH);
+            Body::from(pretty)
+        }
+        Err(_) => Body::from(bytes),
+    };
+
+    Response::from_parts(parts, body)
+}
+
+fn is_json(response: &Response) -> bool {
+    response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|ct| ct.starts_with("application/json"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{http::StatusCode, middleware::from_fn_with_state, routing::get, Router};
+    use tower::ServiceExt;
+
+    fn app() -> Router {
+        let tokens = Arc::new(vec!["secret".to_string()]);
+        Router::new()
+            .route("/health", get(|| async { "ok" }))
+            .route("/v1/models", get(|| async { "models" }))
+            .layer(from_fn_with_state(tokens, require_api_token))
+    }
+
+    async fn status(path: &str, authorization: Option<&str>) -> StatusCode {
+        let mut request = Request::builder().uri(path);
+        if let Some(value) = authorization {
+            request = request.header(header::AUTHORIZATION, value);
+        }
+        let request = request.body(Body::empty()).unwrap();
+        app().oneshot(request).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn v1_routes_require_a_valid_token() {
+        let unauthorized = StatusCode::UNAUTHORIZED;
+        assert_eq!(status("/v1/models", None).await, unauthorized);
+        assert_eq!(
+            status("/v1/models", Some("Bearer wrong")).await,
+            unauthorized
+        );
+        assert_eq!(status("/v1/models", Some("secret")).await, unauthorized);
+        assert_eq!(
+            status("/v1/models", Some("Bearer secret")).await,
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn health_stays_open() {
+        assert_eq!(status("/health", None).await, StatusCode::OK);
+        assert_eq!(
+            status("/health", Some("Bearer wrong")).await,
+            StatusCode::OK
+        );
+    }
+
+    async fn body_text(path: &str) -> String {
+        let app = Router::new()
+            .route(
+                "/v1/models",
+                get(|| async { axum::Json(serde_json::json!({"data": [{"id": "a"}]})) }),
+            )
+            .route("/v1/text", get(|| async { "{\"plain\":true}" }))
+            .layer(axum::middleware::from_fn(pretty_json));
+        let request = Request::builder().uri(path).body(Body::empty()).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn pretty_query_indents_json() {
+        assert_eq!(
+            body_text("/v1/models?pretty=true").await,
+            "{\n  \"data\": [\n    {\n      \"id\": \"a\"\n    }\n  ]\n}"
+        );
+        assert_eq!(body_text("/v1/models").await, r#"{"data":[{"id":"a"}]}"#);
+        // Only JSON responses are re-serialized.
+        assert_eq!(body_text("/v1/text?pretty=true").await, r#"{"plain":true}"#);
+    }
+
+    #[test]
+    fn tokens_match_only_when_equal() {
+        assert!(token_matches("secret", "secret"));
+        assert!(!token_matches("secreT", "secret"));
+        assert!(!token_matches("secret2", "secret"));
+        assert!(!token_matches("", "secret"));
+    }
+}
@@ -1,12 +1,20 @@
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+use crate::error::AgentError;
+use crate::formatting::Locale;
 
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum Role {
+    /// Also accepts `developer`, the newer name for system instructions.
+    #[serde(alias = "developer")]
     System,
     User,
     Assistant,
+    /// Also accepts the legacy `function` role.
+    #[serde(alias = "function")]
     Tool,
 }
 
@@ -89,6 +97,42 @@ impl Message {
     }
 }
 
+/// Providers reject a conversation with two `tool` messages for the same
+/// `tool_call_id`. Repeats are folded into the first result for that id:
+/// identical content is dropped, differing content is appended to it.
+pub fn merge_duplicate_tool_results(messages: Vec<Message>) -> Vec<Message> {
+    let mut merged: Vec<Message> = Vec::with_capacity(messages.len());
+    let mut first_result: HashMap<String, usize> = HashMap::new();
+
+    for message in messages {
+        let id = match (&message.role, &message.tool_call_id) {
+            (Role::Tool, Some(id)) => id.clone(),
+            _ => {
+                merged.push(message);
+                continue;
+            }
+        };
+
+        match first_result.get(&id) {
+            Some(&index) => {
+                let existing = &mut merged[index];
+                let content = message.content.unwrap_or_default();
+                let current = existing.content.get_or_insert_with(String::new);
+                if *current != content {
+                    current.push_str("\n\n");
+                    current.push_str(&content);
+                }
+            }
+            None => {
+                first_result.insert(id, merged.len());
+                merged.push(message);
+            }
+        }
+    }
+
+    merged
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Tool {
     #[serde(rename = "type")]
@@ -103,7 +147,7 @@ pub struct FunctionDefinition {
     pub parameters: serde_json::Value,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Default, Serialize)]
 pub struct ChatCompletionRequest {
     pub model: String,
     pub messages: Vec<Message>,
@@ -121,68 +165,278 @@ pub struct ChatCompletionRequest {
     pub frequency_penalty: Option<f32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub presence_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parallel_tool_calls: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<ResponseFormat>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_choice: Option<ToolChoice>,
+    /// OpenRouter routing: the models to try, in order, if `model` fails.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub models: Option<Vec<String>>,
+}
+
+/// Optional generation settings a caller can pass through to the model.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct GenerationParams {
+    #[serde(default)]
+    pub temperature: Option<f32>,
+    #[serde(default)]
+    pub max_tokens: Option<u32>,
+    #[serde(default)]
+    pub top_p: Option<f32>,
+    #[serde(default)]
+    pub frequency_penalty: Option<f32>,
+    #[serde(default)]
+    pub presence_penalty: Option<f32>,
+    /// Sequences that end generation when the model produces them.
+    #[serde(default)]
+    pub stop: Option<Vec<String>>,
+    #[serde(default)]
+    pub response_format: Option<ResponseFormat>,
+    /// Models OpenRouter may route to when `model` is unavailable. The
+    /// response's `model` names the one that answered.
+    #[serde(default)]
+    pub models: Option<Vec<String>>,
+}
+
+/// Whether and which tool the model must call: `"auto"`, `"none"`,
+/// `"required"`, or `{"type": "function", "function": {"name": ...}}`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum ToolChoice {
+    Mode(ToolChoiceMode),
+    Function {
+        #[serde(rename = "type")]
+        choice_type: String,
+        function: ToolChoiceFunction,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ToolChoiceMode {
+    Auto,
+    None,
+    Required,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ToolChoiceFunction {
+    pub name: String,
+}
+
+impl ToolChoice {
+    /// True for choices that make the model call a tool instead of answering.
+    pub fn forces_call(&self) -> bool {
+        matches!(
+            self,
+            Self::Mode(ToolChoiceMode::Required) | Self::Function { .. }
+        )
+    }
+
+    pub fn function_name(&self) -> Option<&str> {
+        match self {
+            Self::Function { function, .. } => Some(&function.name),
+            Self::Mode(_) => None,
+        }
+    }
+}
+
+/// OpenAI-style `response_format`, sent to the model as given.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    Text,
+    JsonObject,
+    JsonSchema { json_schema: JsonSchemaFormat },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JsonSchemaFormat {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub strict: Option<bool>,
+    pub schema: serde_json::Value,
 }
 
+impl ResponseFormat {
+    /// Checks that `content` is JSON and, for a schema format, that it
+    /// matches the schema. Models don't always honor the format.
+    pub fn validate(&self, content: &str) -> Result<(), AgentError> {
+        let schema = match self {
+            Self::Text => return Ok(()),
+            Self::JsonObject => None,
+            Self::JsonSchema { json_schema } => Some(&json_schema.schema),
+        };
+
+        let value: serde_json::Value = serde_json::from_str(content.trim())
+            .map_err(|e| AgentError::ParseError(format!("Response is not valid JSON: {}", e)))?;
+        let Some(schema) = schema else {
+            return Ok(());
+        };
+
+        let validator = jsonschema::validator_for(schema)
+            .map_err(|e| AgentError::InvalidRequest(format!("Invalid JSON schema: {}", e)))?;
+        let errors: Vec<String> = validator
+            .iter_errors(&value)
+            .map(|e| format!("{} at '{}'", e, e.instance_path))
+            .collect();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(AgentError::ParseError(format!(
+                "Response does not match the JSON schema: {}",
+                errors.join("; ")
+            )))
+        }
+    }
+}
+
+impl ChatCompletionRequest {
+    pub fn builder() -> ChatCompletionRequestBuilder {
+        ChatCompletionRequestBuilder::default()
+    }
+}
+
+/// Builds a `ChatCompletionRequest`; fields that are never set stay `None`
+/// and are omitted from the serialized request.
+#[derive(Debug, Default)]
+pub struct ChatCompletionRequestBuilder {
+    request: ChatCompletionRequest,
+}
+
+impl ChatCompletionRequestBuilder {
+    pub fn model(mut self, model: impl Into<String>) -> Self {
+        self.request.model = model.into();
+        self
+    }
+
+    pub fn messages(mut self, messages: Vec<Message>) -> Self {
+        self.request.messages = messages;
+        self
+    }
+
+    pub fn stream(mut self, stream: bool) -> Self {
+        self.request.stream = Some(stream);
+        self
+    }
+
+    pub fn tools(mut self, tools: impl Into<Option<Vec<Tool>>>) -> Self {
+        self.request.tools = tools.into();
+        self
+    }
+
+    pub fn parallel_tool_calls(mut self, parallel: impl Into<Option<bool>>) -> Self {
+        self.request.parallel_tool_calls = parallel.into();
+        self
+    }
+
+    pub fn tool_choice(mut self, choice: impl Into<Option<ToolChoice>>) -> Self {
+        self.request.tool_choice = choice.into();
+        self
+    }
+
+    pub fn generation(mut self, generation: GenerationParams) -> Self {
+        self.request.temperature = generation.temperature;
+        self.request.max_tokens = generation.max_tokens;
+        self.request.top_p = generation.top_p;
+        self.request.frequency_penalty = generation.frequency_penalty;
+        self.request.presence_penalty = generation.presence_penalty;
+        self.request.stop = generation.stop;
+        self.request.response_format = generation.response_format;
+        self.request.models = generation.models;
+        self
+    }
+
+    pub fn build(self) -> ChatCompletionRequest {
+        self.request
+    }
+}
+
+// Response types are lenient: providers behind OpenRouter omit or add fields,
+// and only `choices[].message` is actually required to make progress.
 #[derive(Debug, Deserialize)]
 pub struct ChatCompletionResponse {
+    #[serde(default)]
     pub id: String,
+    #[serde(default)]
     pub choices: Vec<Choice>,
+    #[serde(default)]
     pub model: String,
+    #[serde(default)]
     pub usage: Option<Usage>,
+    #[serde(flatten)]
+    pub extra: HashMap<String, serde_json::Value>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Choice {
-    pub index: u32,
     pub message: ResponseMessage,
+    #[serde(default)]
     pub finish_reason: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct ResponseMessage {
-    pub role: Role,
+    #[serde(default)]
     pub content: Option<String>,
+    #[serde(default)]
     pub tool_calls: Option<Vec<ToolCall>>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct ToolCall {
     pub id: String,
-    #[serde(rename = "type")]
-    pub call_type: String,
     pub function: FunctionCall,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct FunctionCall {
     pub name: String,
+    #[serde(default = "default_arguments")]
     pub arguments: String,
 }
 
+fn default_arguments() -> String {
+    "{}".to_string()
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Usage {
+    #[serde(default)]
     pub prompt_tokens: u32,
+    #[serde(default)]
     pub completion_tokens: u32,
+    #[serde(default)]
     pub total_tokens: u32,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct ChatCompletionChunk {
+    #[serde(default)]
     pub id: String,
+    #[serde(default)]
     pub choices: Vec<StreamChoice>,
+    #[serde(default)]
     pub model: Option<String>,
+    /// Sent on the last chunk by providers that report streamed usage.
+    #[serde(default)]
+    pub usage: Option<Usage>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct StreamChoice {
-    pub index: u32,
+    #[serde(default)]
     pub delta: Delta,
+    #[serde(default)]
     pub finish_reason: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize)]
 pub struct Delta {
-    pub role: Option<String>,
     pub content: Option<String>,
     pub tool_calls: Option<Vec<ToolCallDelta>>,
 }
@@ -202,19 +456,90 @@ pub struct FunctionCallDelta {
     pub arguments: Option<String>,
 }
 
+/// Rebuilds tool calls from streamed `ToolCallDelta`s. The first delta for
+/// an index carries the id and name; the arguments arrive in fragments,
+/// which providers may interleave between parallel calls. A call is only
+/// known to be complete when the stream finishes.
+#[derive(Debug, Default)]
+pub struct ToolCallAssembler {
+    calls: BTreeMap<u32, MessageToolCall>,
+}
+
+impl ToolCallAssembler {
+    /// Adds one chunk's deltas to the calls they belong to.
+    pub fn push(&mut self, deltas: &[ToolCallDelta]) {
+        for delta in deltas {
+            let call = self
+                .calls
+                .entry(delta.index)
+                .or_insert_with(|| MessageToolCall {
+                    id: String::new(),
+                    call_type: "function".to_string(),
+                    function: MessageFunctionCall {
+                        name: String::new(),
+                        arguments: String::new(),
+                    },
+                });
+
+            // Some providers repeat the id and name on every fragment.
+            if let Some(ref id) = delta.id {
+                if call.id.is_empty() {
+                    call.id.clone_from(id);
+                }
+            }
+            if let Some(ref call_type) = delta.call_type {
+                call.call_type.clone_from(call_type);
+            }
+            if let Some(ref function) = delta.function {
+                if let Some(ref name) = function.name {
+                    if call.function.name.is_empty() {
+                        call.function.name.clone_from(name);
+                    }
+                }
+                if let Some(ref arguments) = function.arguments {
+                    call.function.arguments.push_str(arguments);
+                }
+            }
+        }
+    }
+
+    /// Returns the assembled calls in index order, once the stream has
+    /// finished or sent a `finish_reason`.
+    pub fn finish(&mut self) -> Vec<MessageToolCall> {
+        std::mem::take(&mut self.calls).into_values().collect()
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct AgentRequest {
     pub messages: Vec<Message>,
     #[serde(default)]
     pub model: Option<String>,
-    #[serde(default)]
-    pub temperature: Option<f32>,
-    #[serde(default)]
-    pub max_tokens: Option<u32>,
+    /// `temperature`, `max_tokens` and the other settings passed through to
+    /// the model.
+    #[serde(flatten)]
+    pub generation: GenerationParams,
     #[serde(default)]
     pub stream: Option<bool>,
     #[serde(default)]
     pub system_prompt: Option<String>,
+    /// Adds a `t_ms` offset from stream start to each streamed chunk.
+    #[serde(default)]
+    pub include_timing: bool,
+    /// Adds a `partial` field with the JSON parsed from the content so far,
+    /// for clients that stream a JSON answer.
+    #[serde(default)]
+    pub partial_json: bool,
+    #[serde(default)]
+    pub locale: Option<Locale>,
+    /// Tools the model may call. Streamed calls are sent as `tool_call`
+    /// events once the model finishes.
+    #[serde(default)]
+    pub tools: Option<Vec<Tool>>,
+    /// Checks the answer against `response_format` and fails with a parse
+    /// error when it does not match.
+    #[serde(default)]
+    pub validate_response_format: bool,
 }
 
 #[derive(Debug, Serialize)]
@@ -226,15 +551,276 @@ pub struct AgentResponse {
     pub finish_reason: Option<String>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Default, Serialize)]
 pub struct UsageInfo {
     pub prompt_tokens: u32,
     pub completion_tokens: u32,
     pub total_tokens: u32,
 }
 
-#[derive(Debug, Serialize)]
-pub struct ErrorResponse {
-    pub error: String,
-    pub code: String,
+impl UsageInfo {
+    /// Adds one response's usage to a running total.
+    pub fn add(&mut self, usage: &Usage) {
+        self.prompt_tokens = self.prompt_tokens.saturating_add(usage.prompt_tokens);
+        self.completion_tokens = self
+            .completion_tokens
+            .saturating_add(usage.completion_tokens);
+        self.total_tokens = self.total_tokens.saturating_add(usage.total_tokens);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn legacy_role_names_are_accepted() {
+        let messages: Vec<Message> = serde_json::from_value(json!([
+            {"role": "developer", "content": "Be brief."},
+            {"role": "function", "content": "42", "tool_call_id": "call_1"},
+        ]))
+        .unwrap();
+
+        assert_eq!(messages[0].role, Role::System);
+        assert_eq!(messages[1].role, Role::Tool);
+        // They are sent upstream under their current names.
+        let sent = serde_json::to_value(&messages).unwrap();
+        assert_eq!(sent[0]["role"], "system");
+        assert_eq!(sent[1]["role"], "tool");
+    }
+
+    fn deltas(value: serde_json::Value) -> Vec<ToolCallDelta> {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn fragmented_tool_call_is_reassembled() {
+        let mut assembler = ToolCallAssembler::default();
+        assembler.push(&deltas(json!([{
+            "index": 0,
+            "id": "call_1",
+            "type": "function",
+            "function": {"name": "calculator", "arguments": ""}
+        }])));
+        for fragment in ["{\"expr", "ession\": \"2", " + 2\"}"] {
+            assembler.push(&deltas(json!([
+                {"index": 0, "function": {"arguments": fragment}}
+            ])));
+        }
+
+        let calls = assembler.finish();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].id, "call_1");
+        assert_eq!(calls[0].function.name, "calculator");
+        assert_eq!(calls[0].function.arguments, r#"{"expression": "2 + 2"}"#);
+        assert!(assembler.finish().is_empty());
+    }
+
+    #[test]
+    fn interleaved_tool_calls_are_kept_apart() {
+        let mut assembler = ToolCallAssembler::default();
+        for chunk in [
+            json!([{
+                "index": 0,
+                "id": "call_a",
+                "function": {"name": "search", "arguments": "{\"q\":"}
+            }]),
+            json!([{
+                "index": 1,
+                "id": "call_b",
+                "function": {"name": "calculator", "arguments": "{\"expression\":"}
+            }]),
+            json!([{"index": 0, "function": {"arguments": "\"rust\"}"}}]),
+            json!([{"index": 1, "function": {"arguments": "\"1+1\"}"}}]),
+        ] {
+            assembler.push(&deltas(chunk));
+        }
+
+        let calls: Vec<(String, String, String)> = assembler
+            .finish()
+            .into_iter()
+            .map(|c| (c.id, c.function.name, c.function.arguments))
+            .collect();
+        assert_eq!(
+            calls,
+            [
+                (
+                    "call_a".to_string(),
+                    "search".to_string(),
+                    r#"{"q":"rust"}"#.to_string()
+                ),
+                (
+                    "call_b".to_string(),
+                    "calculator".to_string(),
+                    r#"{"expression":"1+1"}"#.to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn minimal_and_extended_responses_parse() {
+        let minimal: ChatCompletionResponse =
+            serde_json::from_value(json!({"choices": [{"message": {"content": "hi"}}]})).unwrap();
+        assert_eq!(minimal.id, "");
+        assert_eq!(minimal.model, "");
+        assert!(minimal.usage.is_none());
+        assert_eq!(minimal.choices[0].message.content.as_deref(), Some("hi"));
+        assert_eq!(minimal.choices[0].finish_reason, None);
+
+        let extended: ChatCompletionResponse = serde_json::from_value(json!({
+            "id": "gen-1",
+            "object": "chat.completion",
+            "provider": "Acme",
+            "model": "test/model",
+            "system_fingerprint": "fp_1",
+            "choices": [{
+                "index": 0,
+                "message": {"role": "assistant", "content": "hi", "refusal": null},
+                "logprobs": null,
+                "finish_reason": "stop"
+            }],
+            "usage": {"prompt_tokens": 1, "completion_tokens": 2, "total_tokens": 3, "cost": 0.1}
+        }))
+        .unwrap();
+        assert_eq!(extended.model, "test/model");
+        assert_eq!(extended.usage.unwrap().total_tokens, 3);
+        assert_eq!(extended.extra["provider"], "Acme");
+        assert_eq!(extended.choices[0].finish_reason.as_deref(), Some("stop"));
+    }
+
+    #[test]
+    fn builder_matches_a_literal_request() {
+        let tools = vec![Tool {
+            tool_type: "function".to_string(),
+            function: FunctionDefinition {
+                name: "calculator".to_string(),
+                description: "Evaluates an expression".to_string(),
+                parameters: json!({"type": "object"}),
+            },
+        }];
+        let built = ChatCompletionRequest::builder()
+            .model("test/model")
+            .messages(vec![Message::user("hi")])
+            .stream(false)
+            .tools(tools.clone())
+            .generation(GenerationParams {
+                temperature: Some(0.5),
+                max_tokens: Some(100),
+                ..Default::default()
+            })
+            .build();
+        let literal = ChatCompletionRequest {
+            model: "test/model".to_string(),
+            messages: vec![Message::user("hi")],
+            temperature: Some(0.5),
+            max_tokens: Some(100),
+            stream: Some(false),
+            tools: Some(tools),
+            top_p: None,
+            frequency_penalty: None,
+            presence_penalty: None,
+            stop: None,
+            parallel_tool_calls: None,
+            response_format: None,
+            tool_choice: None,
+            models: None,
+        };
+
+        assert_eq!(
+            serde_json::to_value(&built).unwrap(),
+            serde_json::to_value(&literal).unwrap()
+        );
+    }
+
+    #[test]
+    fn duplicate_tool_results_are_merged() {
+        let call = |id: &str| MessageToolCall {
+            id: id.to_string(),
+            call_type: "function".to_string(),
+            function: MessageFunctionCall {
+                name: "calculator".to_string(),
+                arguments: "{}".to_string(),
+            },
+        };
+        let messages = merge_duplicate_tool_results(vec![
+            Message::user("hi"),
+            Message::assistant_with_tool_calls(None, vec![call("call_1"), call("call_2")]),
+            Message::tool_result("call_1", "42"),
+            Message::tool_result("call_2", "first"),
+            Message::tool_result("call_1", "42"),
+            Message::tool_result("call_2", "second"),
+        ]);
+
+        let results: Vec<(&str, &str)> = messages
+            .iter()
+            .filter(|m| m.role == Role::Tool)
+            .map(|m| {
+                (
+                    m.tool_call_id.as_deref().unwrap(),
+                    m.content.as_deref().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(results, [("call_1", "42"), ("call_2", "first\n\nsecond")]);
+        assert_eq!(messages.len(), 4);
+    }
+
+    fn request_with(generation: GenerationParams) -> serde_json::Value {
+        let request = ChatCompletionRequest::builder()
+            .model("test/model")
+            .messages(vec![Message::user("hi")])
+            .generation(generation)
+            .build();
+        serde_json::to_value(request).unwrap()
+    }
+
+    #[test]
+    fn sampling_settings_are_sent_only_when_set() {
+        let unset = request_with(GenerationParams::default());
+        for field in ["top_p", "frequency_penalty", "presence_penalty"] {
+            assert!(unset.get(field).is_none(), "{}", field);
+        }
+
+        let set = request_with(GenerationParams {
+            top_p: Some(0.5),
+            frequency_penalty: Some(0.25),
+            presence_penalty: Some(-1.0),
+            ..Default::default()
+        });
+        assert_eq!(set["top_p"], 0.5);
+        assert_eq!(set["frequency_penalty"], 0.25);
+        assert_eq!(set["presence_penalty"], -1.0);
+    }
+
+    #[test]
+    fn parallel_tool_calls_is_sent_only_when_set() {
+        let request = |parallel: Option<bool>| {
+            let request = ChatCompletionRequest::builder()
+                .model("test/model")
+                .messages(vec![Message::user("hi")])
+                .parallel_tool_calls(parallel)
+                .build();
+            serde_json::to_value(request).unwrap()
+        };
+
+        assert!(request(None).get("parallel_tool_calls").is_none());
+        assert_eq!(request(Some(false))["parallel_tool_calls"], false);
+        assert_eq!(request(Some(true))["parallel_tool_calls"], true);
+    }
+
+    #[test]
+    fn routing_models_are_sent_only_when_set() {
+        assert!(request_with(GenerationParams::default())
+            .get("models")
+            .is_none());
+
+        let models = vec!["primary/model".to_string(), "backup/model".to_string()];
+        let request = request_with(GenerationParams {
+            models: Some(models),
+            ..Default::default()
+        });
+        assert_eq!(request["models"], json!(["primary/model", "backup/model"]));
+    }
 }
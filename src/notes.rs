@@ -0,0 +1,149 @@
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Characters of content shown per note by `list`.
+const PREVIEW_CHARS: usize = 80;
+
+#[derive(Debug, Clone)]
+pub struct Note {
+    pub title: String,
+    pub content: String,
+    pub created_at: String,
+}
+
+/// SQLite-backed storage for the notes tools. Queries run on the blocking
+/// thread pool so a slow disk never stalls the runtime.
+#[derive(Clone)]
+pub struct NoteStore {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl NoteStore {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let conn = Connection::open(path.as_ref())
+            .with_context(|| format!("Failed to open {}", path.as_ref().display()))?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS notes (
+                title TEXT PRIMARY KEY,
+                content TEXT NOT NULL,
+                created_at TEXT NOT NULL
+            )",
+            [],
+        )?;
+
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Saves a note, replacing any note with the same title.
+    pub async fn save(&self, title: &str, content: &str) -> Result<()> {
+        let (title, content) = (title.to_string(), content.to_string());
+        let created_at = chrono::Utc::now().to_rfc3339();
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO notes (title, content, created_at) VALUES (?1, ?2, ?3)",
+                params![title, content, created_at],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn get(&self, title: &str) -> Result<Option<Note>> {
+        let title = title.to_string();
+        self.with_conn(move |conn| {
+            let note = conn
+                .query_row(
+                    "SELECT title, content, created_at FROM notes WHERE title = ?1",
+                    params![title],
+                    |row| {
+                        Ok(Note {
+                            title: row.get(0)?,
+                            content: row.get(1)?,
+                            created_at: row.get(2)?,
+                        })
+                    },
+                )
+                .optional()?;
+            Ok(note)
+        })
+        .await
+    }
+
+    /// Every note, newest first, with content cut to a short preview.
+    pub async fn list(&self) -> Result<Vec<Note>> {
+        self.with_conn(|conn| {
+            let mut stmt = conn.prepare(
+                "SELECT title, substr(content, 1, ?1), created_at FROM notes
+                 ORDER BY created_at DESC",
+            )?;
+            let notes = stmt
+                .query_map(params![PREVIEW_CHARS], |row| {
+                    Ok(Note {
+                        title: row.get(0)?,
+                        content: row.get(1)?,
+                        created_at: row.get(2)?,
+                    })
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(notes)
+        })
+        .await
+    }
+
+    async fn with_conn<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> Result<T> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn
+                .lock()
+                .map_err(|_| anyhow::anyhow!("Note store lock poisoned"))?;
+            f(&conn)
+        })
+        .await
+        .context("Note store task failed")?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn saved_note_is_returned_with_its_creation_time() {
+        let store = NoteStore::open(":memory:").unwrap();
+        store.save("groceries", "milk, eggs").await.unwrap();
+
+        let note = store.get("groceries").await.unwrap().expect("saved note");
+        assert_eq!(note.title, "groceries");
+        assert_eq!(note.content, "milk, eggs");
+        assert!(
+            chrono::DateTime::parse_from_rfc3339(&note.created_at).is_ok(),
+            "{}",
+            note.created_at
+        );
+        assert!(store.get("missing").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn list_cuts_content_to_a_preview() {
+        let store = NoteStore::open(":memory:").unwrap();
+        let long = "é".repeat(PREVIEW_CHARS + 20);
+        store.save("long", &long).await.unwrap();
+        store.save("short", "brief").await.unwrap();
+
+        let notes = store.list().await.unwrap();
+        assert_eq!(notes.len(), 2);
+        let preview = notes.iter().find(|n| n.title == "long").unwrap();
+        assert_eq!(preview.content.chars().count(), PREVIEW_CHARS);
+        assert!(long.starts_with(&preview.content));
+        let short = notes.iter().find(|n| n.title == "short").unwrap();
+        assert_eq!(short.content, "brief");
+    }
+}
@@ -1,10 +1,18 @@
 use crate::config::Config;
 use crate::error::AgentError;
-use crate::models::{ChatCompletionChunk, ChatCompletionRequest, ChatCompletionResponse, Message, Tool};
+use crate::models::{
+    merge_duplicate_tool_results, ChatCompletionChunk, ChatCompletionRequest,
+    ChatCompletionResponse, Choice, FunctionCall, GenerationParams, Message, ResponseMessage, Tool,
+    ToolCall, ToolCallAssembler, ToolChoice,
+};
+use crate::retry::{self, RetryBudget};
+use crate::sse::SseDecoder;
 use futures::StreamExt;
 use reqwest::Client;
+use std::collections::HashMap;
+use std::time::Duration;
 use tokio::sync::mpsc;
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 
 #[derive(Clone)]
 pub struct OpenRouterClient {
@@ -15,6 +23,7 @@ pub struct OpenRouterClient {
 impl OpenRouterClient {
     pub fn new(config: Config) -> Self {
         let client = Client::builder()
+            .user_agent(Config::user_agent())
             .timeout(std::time::Duration::from_secs(120))
             .build()
             .expect("Failed to create HTTP client");
@@ -22,109 +31,211 @@ impl OpenRouterClient {
         Self { client, config }
     }
 
+    /// Sends the request to its model, then to each of `MODEL_FALLBACKS` in
+    /// turn while the failure says the model itself is unavailable.
+    async fn send_with_fallbacks(
+        &self,
+        mut request: ChatCompletionRequest,
+        retry_budget: Option<&RetryBudget>,
+    ) -> Result<ChatCompletionResponse, AgentError> {
+        let primary = request.model.clone();
+        let mut result = self.send_request(&request, retry_budget).await;
+
+        for fallback in &self.config.model_fallbacks {
+            match result {
+                Err(ref e) if is_model_unavailable(e) && *fallback != primary => {
+                    warn!(
+                        "Model {} unavailable ({}), falling back to {}",
+                        request.model, e, fallback
+                    );
+                    request.model = fallback.clone();
+                    result = self.send_request(&request, retry_budget).await;
+                }
+                _ => break,
+            }
+        }
+
+        result
+    }
+
+    /// Posts the request, retrying transient failures up to
+    /// `OPENROUTER_MAX_RETRIES` times. Within an agent run each retry also
+    /// takes one from the run's `retry_budget`.
     async fn send_request(
         &self,
-        request: ChatCompletionRequest,
+        request: &ChatCompletionRequest,
+        retry_budget: Option<&RetryBudget>,
     ) -> Result<ChatCompletionResponse, AgentError> {
         info!("Sending request to model: {}", request.model);
         debug!("Request: {:?}", request);
 
-        let response = self
-            .client
-            .post(format!("{}/chat/completions", self.config.openrouter_base_url))
-            .header("Authorization", format!("Bearer {}", self.config.openrouter_api_key))
-            .header("Content-Type", "application/json")
-            .header("HTTP-Referer", "https://github.com/anthropics/claude-code")
-            .header("X-Title", "LLM Agent")
-            .json(&request)
-            .send()
-            .await
-            .map_err(|e| AgentError::RequestFailed(e.to_string()))?;
+        let mut attempt = 0;
+        let response = loop {
+            let result = self
+                .client
+                .post(format!(
+                    "{}/chat/completions",
+                    self.config.openrouter_base_url
+                ))
+                .header(
+                    "Authorization",
+                    format!("Bearer {}", self.config.openrouter_api_key),
+                )
+                .header("Content-Type", "application/json")
+                .header("HTTP-Referer", "https://github.com/anthropics/claude-code")
+                .header("X-Title", "LLM Agent")
+                .json(request)
+                .send()
+                .await;
 
-        let status = response.status();
-        if !status.is_success() {
-            let error_text = response.text().await.unwrap_or_default();
-            error!("OpenRouter API error: {} - {}", status, error_text);
-            return Err(AgentError::ApiError {
-                status: status.as_u16(),
-                message: error_text,
-            });
-        }
+            let (error, retryable) = match result {
+                Ok(response) if response.status().is_success() => break response,
+                Ok(response) => {
+                    let status = response.status();
+                    let error_text = response.text().await.unwrap_or_default();
+                    error!("OpenRouter API error: {} - {}", status, error_text);
+                    (
+                        api_error(status.as_u16(), error_text),
+                        matches!(status.as_u16(), 429 | 500 | 502 | 503 | 504),
+                    )
+                }
+                Err(e) => {
+                    let retryable = e.is_connect() || e.is_timeout();
+                    (AgentError::RequestFailed(e.to_string()), retryable)
+                }
+            };
 
-        let completion: ChatCompletionResponse = response
-            .json()
-            .await
-            .map_err(|e| AgentError::ParseError(e.to_string()))?;
+            if !retryable
+                || attempt >= self.config.openrouter_max_retries
+                || retry_budget.is_some_and(|budget| !budget.try_consume())
+            {
+                return Err(error);
+            }
+            let delay = backoff_delay(self.config.openrouter_retry_base_ms, attempt);
+            attempt += 1;
+            warn!(
+                "OpenRouter request failed, retrying in {:?} (attempt {} of {}): {}",
+                delay, attempt, self.config.openrouter_max_retries, error
+            );
+            tokio::time::sleep(delay).await;
+        };
+
+        let body = self.read_limited_body(response).await?;
+        let completion: ChatCompletionResponse =
+            serde_json::from_slice(&body).map_err(|e| AgentError::ParseError(e.to_string()))?;
 
         info!("Received response with {} choices", completion.choices.len());
+        if !completion.extra.is_empty() {
+            debug!(
+                "Response contained unrecognised fields: {:?}",
+                completion.extra.keys().collect::<Vec<_>>()
+            );
+        }
         Ok(completion)
     }
 
+    async fn read_limited_body(&self, response: reqwest::Response) -> Result<Vec<u8>, AgentError> {
+        let limit = self.config.max_response_bytes;
+        if response
+            .content_length()
+            .is_some_and(|len| len as usize > limit)
+        {
+            error!("OpenRouter response is larger than {} bytes", limit);
+            return Err(AgentError::ResponseTooLarge { limit });
+        }
+
+        let mut body = Vec::new();
+        let mut stream = response.bytes_stream();
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk.map_err(|e| AgentError::RequestFailed(e.to_string()))?;
+            if body.len() + chunk.len() > limit {
+                error!("OpenRouter response exceeded {} bytes", limit);
+                return Err(AgentError::ResponseTooLarge { limit });
+            }
+            body.extend_from_slice(&chunk);
+        }
+
+        Ok(body)
+    }
+
     pub async fn chat_completion(
         &self,
         messages: Vec<Message>,
         model: Option<String>,
-        temperature: Option<f32>,
-        max_tokens: Option<u32>,
+        generation: GenerationParams,
     ) -> Result<ChatCompletionResponse, AgentError> {
-        let request = ChatCompletionRequest {
-            model: model.unwrap_or_else(|| self.config.default_model.clone()),
-            messages,
-            temperature,
-            max_tokens,
-            stream: Some(false),
-            tools: None,
-            top_p: None,
-            frequency_penalty: None,
-            presence_penalty: None,
-        };
+        let request = ChatCompletionRequest::builder()
+            .model(model.unwrap_or_else(|| self.config.default_model.clone()))
+            .messages(merge_duplicate_tool_results(messages))
+            .generation(generation)
+            .stream(false)
+            .build();
 
-        self.send_request(request).await
+        self.send_with_fallbacks(request, None).await
     }
 
+    /// Sends an agent request. With `deltas`, the response is streamed and
+    /// each piece of content is sent there as it arrives; streamed requests
+    /// are not retried or sent to fallback models.
+    #[allow(clippy::too_many_arguments)]
     pub async fn chat_completion_with_tools(
         &self,
         messages: Vec<Message>,
         model: Option<String>,
         tools: Option<Vec<Tool>>,
+        parallel_tool_calls: Option<bool>,
+        tool_choice: Option<ToolChoice>,
+        generation: GenerationParams,
+        deltas: Option<mpsc::Sender<String>>,
+        retry_budget: Option<&RetryBudget>,
     ) -> Result<ChatCompletionResponse, AgentError> {
-        let request = ChatCompletionRequest {
-            model: model.unwrap_or_else(|| self.config.default_model.clone()),
-            messages,
-            temperature: Some(0.7),
-            max_tokens: Some(4096),
-            stream: Some(false),
-            tools,
-            top_p: None,
-            frequency_penalty: None,
-            presence_penalty: None,
+        // Agent runs fall back to the configured settings, not the model's.
+        let generation = GenerationParams {
+            temperature: generation
+                .temperature
+                .or(Some(self.config.agent_temperature)),
+            max_tokens: generation.max_tokens.or(Some(self.config.agent_max_tokens)),
+            ..generation
         };
+        let request = ChatCompletionRequest::builder()
+            .model(model.unwrap_or_else(|| self.config.default_model.clone()))
+            .messages(merge_duplicate_tool_results(messages))
+            .stream(deltas.is_some())
+            .tools(tools)
+            .parallel_tool_calls(parallel_tool_calls)
+            .tool_choice(tool_choice)
+            .generation(generation)
+            .build();
 
-        self.send_request(request).await
+        match deltas {
+            Some(deltas) => collect_stream(self.open_stream(&request).await?, &deltas).await,
+            None => self.send_with_fallbacks(request, retry_budget).await,
+        }
     }
 
     pub async fn chat_completion_stream(
         &self,
         messages: Vec<Message>,
         model: Option<String>,
-        temperature: Option<f32>,
-        max_tokens: Option<u32>,
+        generation: GenerationParams,
+        tools: Option<Vec<Tool>>,
     ) -> Result<mpsc::Receiver<Result<ChatCompletionChunk, AgentError>>, AgentError> {
-        let model = model.unwrap_or_else(|| self.config.default_model.clone());
-
-        let request = ChatCompletionRequest {
-            model: model.clone(),
-            messages,
-            temperature,
-            max_tokens,
-            stream: Some(true),
-            tools: None,
-            top_p: None,
-            frequency_penalty: None,
-            presence_penalty: None,
-        };
+        let request = ChatCompletionRequest::builder()
+            .model(model.unwrap_or_else(|| self.config.default_model.clone()))
+            .messages(merge_duplicate_tool_results(messages))
+            .generation(generation)
+            .stream(true)
+            .tools(tools)
+            .build();
 
-        info!("Sending streaming request to model: {}", model);
+        self.open_stream(&request).await
+    }
+
+    async fn open_stream(
+        &self,
+        request: &ChatCompletionRequest,
+    ) -> Result<mpsc::Receiver<Result<ChatCompletionChunk, AgentError>>, AgentError> {
+        info!("Sending streaming request to model: {}", request.model);
 
         let response = self
             .client
@@ -133,7 +244,7 @@ impl OpenRouterClient {
             .header("Content-Type", "application/json")
             .header("HTTP-Referer", "https://github.com/anthropics/claude-code")
             .header("X-Title", "LLM Agent")
-            .json(&request)
+            .json(request)
             .send()
             .await
             .map_err(|e| AgentError::RequestFailed(e.to_string()))?;
@@ -142,43 +253,51 @@ impl OpenRouterClient {
         if !status.is_success() {
             let error_text = response.text().await.unwrap_or_default();
             error!("OpenRouter API error: {} - {}", status, error_text);
-            return Err(AgentError::ApiError {
-                status: status.as_u16(),
-                message: error_text,
-            });
+            return Err(api_error(status.as_u16(), error_text));
+        }
+
+        // Streams are held to the same size limit as whole responses.
+        let limit = self.config.max_response_bytes;
+        if response
+            .content_length()
+            .is_some_and(|len| len as usize > limit)
+        {
+            error!("OpenRouter stream is larger than {} bytes", limit);
+            return Err(AgentError::ResponseTooLarge { limit });
         }
 
         let (tx, rx) = mpsc::channel(100);
         let mut stream = response.bytes_stream();
 
         tokio::spawn(async move {
-            let mut buffer = String::new();
+            let mut decoder = SseDecoder::default();
+            let mut received = 0;
 
             while let Some(chunk_result) = stream.next().await {
                 match chunk_result {
                     Ok(bytes) => {
-                        buffer.push_str(&String::from_utf8_lossy(&bytes));
-
-                        while let Some(pos) = buffer.find("\n\n") {
-                            let event = buffer[..pos].to_string();
-                            buffer = buffer[pos + 2..].to_string();
+                        received += bytes.len();
+                        if received > limit {
+                            error!("OpenRouter stream exceeded {} bytes", limit);
+                            let _ = tx.send(Err(AgentError::ResponseTooLarge { limit })).await;
+                            return;
+                        }
 
-                            if let Some(data) = event.strip_prefix("data: ") {
-                                if data == "[DONE]" {
-                                    debug!("Stream completed");
-                                    return;
-                                }
+                        for data in decoder.push(&bytes) {
+                            if data == "[DONE]" {
+                                debug!("Stream completed");
+                                return;
+                            }
 
-                                match serde_json::from_str::<ChatCompletionChunk>(data) {
-                                    Ok(chunk) => {
-                                        if tx.send(Ok(chunk)).await.is_err() {
-                                            return;
-                                        }
-                                    }
-                                    Err(e) => {
-                                        debug!("Failed to parse chunk: {} - data: {}", e, data);
+                            match serde_json::from_str::<ChatCompletionChunk>(&data) {
+                                Ok(chunk) => {
+                                    if tx.send(Ok(chunk)).await.is_err() {
+                                        return;
                                     }
                                 }
+                                Err(e) => {
+                                    debug!("Failed to parse chunk: {} - data: {}", e, data);
+                                }
                             }
                         }
                     }
@@ -205,10 +324,7 @@ impl OpenRouterClient {
         let status = response.status();
         if !status.is_success() {
             let error_text = response.text().await.unwrap_or_default();
-            return Err(AgentError::ApiError {
-                status: status.as_u16(),
-                message: error_text,
-            });
+            return Err(api_error(status.as_u16(), error_text));
         }
 
         response
@@ -216,4 +332,288 @@ impl OpenRouterClient {
             .await
             .map_err(|e| AgentError::ParseError(e.to_string()))
     }
+
+    /// `/models` is public, so the key is checked against `/key`, which
+    /// rejects invalid keys with a 401.
+    pub async fn verify_api_key(&self) -> Result<(), AgentError> {
+        let response = self
+            .client
+            .get(format!("{}/key", self.config.openrouter_base_url))
+            .header("Authorization", format!("Bearer {}", self.config.openrouter_api_key))
+            .send()
+            .await
+            .map_err(|e| AgentError::RequestFailed(e.to_string()))?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let error_text = response.text().await.unwrap_or_default();
+            return Err(api_error(status.as_u16(), error_text));
+        }
+
+        Ok(())
+    }
+
+    pub async fn get_generation(&self, id: &str) -> Result<serde_json::Value, AgentError> {
+        let response = self
+            .client
+            .get(format!("{}/generation", self.config.openrouter_base_url))
+            .query(&[("id", id)])
+            .header("Authorization", format!("Bearer {}", self.config.openrouter_api_key))
+            .send()
+            .await
+            .map_err(|e| AgentError::RequestFailed(e.to_string()))?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let error_text = response.text().await.unwrap_or_default();
+            return Err(api_error(status.as_u16(), error_text));
+        }
+
+        response
+            .json()
+            .await
+            .map_err(|e| AgentError::ParseError(e.to_string()))
+    }
+}
+
+/// Rebuilds a complete response from a streamed one, sending each piece of
+/// content to `deltas` as it arrives.
+async fn collect_stream(
+    mut chunks: mpsc::Receiver<Result<ChatCompletionChunk, AgentError>>,
+    deltas: &mpsc::Sender<String>,
+) -> Result<ChatCompletionResponse, AgentError> {
+    let mut response = ChatCompletionResponse {
+        id: String::new(),
+        choices: Vec::new(),
+        model: String::new(),
+        usage: None,
+        extra: HashMap::new(),
+    };
+    let mut content = String::new();
+    let mut finish_reason = None;
+    let mut assembler = ToolCallAssembler::default();
+
+    while let Some(chunk) = chunks.recv().await {
+        let chunk = chunk?;
+        if response.id.is_empty() {
+            response.id = chunk.id;
+        }
+        if let Some(model) = chunk.model {
+            response.model = model;
+        }
+        if chunk.usage.is_some() {
+            response.usage = chunk.usage;
+        }
+        let Some(choice) = chunk.choices.into_iter().next() else {
+            continue;
+        };
+
+        if let Some(text) = choice.delta.content.filter(|text| !text.is_empty()) {
+            content.push_str(&text);
+            // The caller only stops listening when its client went away.
+            let _ = deltas.send(text).await;
+        }
+        if let Some(ref calls) = choice.delta.tool_calls {
+            assembler.push(calls);
+        }
+        if choice.finish_reason.is_some() {
+            finish_reason = choice.finish_reason;
+        }
+    }
+
+    let tool_calls: Vec<ToolCall> = assembler
+        .finish()
+        .into_iter()
+        .map(|call| ToolCall {
+            id: call.id,
+            function: FunctionCall {
+                name: call.function.name,
+                arguments: match call.function.arguments {
+                    arguments if arguments.is_empty() => "{}".to_string(),
+                    arguments => arguments,
+                },
+            },
+        })
+        .collect();
+    response.choices.push(Choice {
+        message: ResponseMessage {
+            content: (!content.is_empty()).then_some(content),
+            tool_calls: (!tool_calls.is_empty()).then_some(tool_calls),
+        },
+        finish_reason,
+    });
+
+    Ok(response)
+}
+
+/// Builds an `ApiError` from an OpenRouter error body, keeping the
+/// provider-specific `error.metadata` (e.g. the upstream `raw` message) when
+/// the body is in OpenRouter's JSON error shape.
+fn api_error(status: u16, body: String) -> AgentError {
+    let parsed: Option<serde_json::Value> = serde_json::from_str(&body).ok();
+    let error = parsed.as_ref().and_then(|v| v.get("error"));
+
+    let metadata = error.and_then(|e| e.get("metadata")).cloned();
+    let message = error
+        .and_then(|e| e.get("message"))
+        .and_then(|m| m.as_str())
+        .map(str::to_string)
+        .unwrap_or(body);
+
+    AgentError::ApiError {
+        status,
+        message,
+        metadata,
+    }
+}
+
+/// Errors that mean the model can't serve requests right now, as opposed to
+/// a problem with the request itself.
+fn is_model_unavailable(error: &AgentError) -> bool {
+    let AgentError::ApiError {
+        status, message, ..
+    } = error
+    else {
+        return false;
+    };
+    if matches!(status, 404 | 503) {
+        return true;
+    }
+
+    let message = message.to_lowercase();
+    message.contains("model")
+        && ["unavailable", "not available", "not found", "no endpoints"]
+            .iter()
+            .any(|phrase| message.contains(phrase))
+}
+
+/// Exponential backoff from `base_ms`, plus up to the same again as jitter
+/// so concurrent requests don't retry in lockstep.
+fn backoff_delay(base_ms: u64, attempt: u32) -> Duration {
+    let delay = base_ms.saturating_mul(1 << attempt.min(10));
+    Duration::from_millis(delay) + retry::jitter(delay)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{
+        completion, content_chunk, streaming_upstream, test_config, Upstream,
+    };
+    use axum::http::StatusCode;
+    use serde_json::json;
+
+    fn user(content: &str) -> Vec<Message> {
+        vec![Message::user(content)]
+    }
+
+    fn unavailable() -> (StatusCode, serde_json::Value) {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            json!({"error": {"message": "overloaded"}}),
+        )
+    }
+
+    #[tokio::test]
+    async fn transient_errors_are_retried() {
+        let upstream = Upstream::start(vec![
+            unavailable(),
+            unavailable(),
+            (StatusCode::OK, completion("recovered")),
+        ])
+        .await;
+        let mut config = test_config(upstream.url.clone());
+        config.openrouter_max_retries = 3;
+        let client = OpenRouterClient::new(config);
+
+        let response = client
+            .chat_completion(user("hi"), None, GenerationParams::default())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            response.choices[0].message.content.as_deref(),
+            Some("recovered")
+        );
+        assert_eq!(upstream.requests().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn client_errors_are_not_retried() {
+        let upstream = Upstream::start(vec![
+            (
+                StatusCode::BAD_REQUEST,
+                json!({"error": {"message": "bad request"}}),
+            ),
+            (StatusCode::OK, completion("unreachable")),
+        ])
+        .await;
+        let mut config = test_config(upstream.url.clone());
+        config.openrouter_max_retries = 3;
+        let client = OpenRouterClient::new(config);
+
+        let result = client
+            .chat_completion(user("hi"), None, GenerationParams::default())
+            .await;
+
+        assert!(
+            matches!(result, Err(AgentError::ApiError { status: 400, .. })),
+            "{:?}",
+            result
+        );
+        assert_eq!(upstream.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn oversized_response_is_rejected() {
+        let upstream = Upstream::ok(vec![completion(&"x".repeat(2_000))]).await;
+        let mut config = test_config(upstream.url.clone());
+        config.max_response_bytes = 1_000;
+        let client = OpenRouterClient::new(config);
+
+        let result = client
+            .chat_completion(user("hi"), None, GenerationParams::default())
+            .await;
+
+        assert!(
+            matches!(result, Err(AgentError::ResponseTooLarge { limit: 1_000 })),
+            "{:?}",
+            result
+        );
+    }
+
+    #[tokio::test]
+    async fn oversized_stream_is_cut_off() {
+        let chunks = (0..50).map(|_| content_chunk(&"x".repeat(100))).collect();
+        let mut config = test_config(streaming_upstream(chunks).await);
+        config.max_response_bytes = 1_000;
+        let client = OpenRouterClient::new(config);
+
+        let mut rx = client
+            .chat_completion_stream(user("hi"), None, GenerationParams::default(), None)
+            .await
+            .unwrap();
+        let mut results = Vec::new();
+        while let Some(result) = rx.recv().await {
+            results.push(result);
+        }
+
+        assert!(results.len() < 50, "{} chunks got through", results.len());
+        assert!(matches!(
+            results.last(),
+            Some(Err(AgentError::ResponseTooLarge { limit: 1_000 }))
+        ));
+
+        // The agent's streamed calls share the limit.
+        let (deltas, _rx) = mpsc::channel(100);
+        let result = collect_stream(
+            client
+                .open_stream(&ChatCompletionRequest::default())
+                .await
+                .unwrap(),
+            &deltas,
+        )
+        .await;
+        assert!(matches!(result, Err(AgentError::ResponseTooLarge { .. })));
+    }
 }
@@ -0,0 +1,129 @@
+use serde_json::Value;
+
+/// Parses the longest usable prefix of an incomplete JSON document, closing
+/// any open strings, arrays and objects. Members that are still being written
+/// (a key without a value, a half-typed number or literal) are dropped.
+/// Returns `None` until the first `{` or `[` has arrived.
+pub fn parse_partial(input: &str) -> Option<Value> {
+    let input = input.trim_start();
+    if !input.starts_with(['{', '[']) {
+        return None;
+    }
+
+    if let Ok(value) = serde_json::from_str(input) {
+        return Some(value);
+    }
+
+    // Places the document can be cut back to, with the containers open there.
+    let mut cuts: Vec<(usize, Vec<char>)> = Vec::new();
+    let mut stack = Vec::new();
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for (i, c) in input.char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+
+        match c {
+            '"' => in_string = true,
+            '{' | '[' => {
+                stack.push(c);
+                cuts.push((i + 1, stack.clone()));
+            }
+            '}' | ']' => {
+                stack.pop();
+                if stack.is_empty() {
+                    break;
+                }
+            }
+            ',' => cuts.push((i, stack.clone())),
+            _ => {}
+        }
+    }
+
+    // A number at the very end may still be growing, so it is cut back too.
+    let number_pending = !in_string
+        && input
+            .trim_end()
+            .ends_with(|c: char| c.is_ascii_digit() || matches!(c, '.' | '-' | '+'));
+    let mut whole = input.to_string();
+    if in_string {
+        if escaped {
+            whole.pop();
+        }
+        whole.push('"');
+    }
+    if !number_pending {
+        if let Some(value) = close_and_parse(&whole, &stack) {
+            return Some(value);
+        }
+    }
+
+    cuts.iter()
+        .rev()
+        .find_map(|(pos, open)| close_and_parse(&input[..*pos], open))
+}
+
+fn close_and_parse(prefix: &str, open: &[char]) -> Option<Value> {
+    let mut document = prefix.to_string();
+    for c in open.iter().rev() {
+        document.push(if *c == '{' { '}' } else { ']' });
+    }
+    serde_json::from_str(&document).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn nothing_is_parsed_before_the_document_starts() {
+        assert_eq!(parse_partial(""), None);
+        assert_eq!(parse_partial("Sure: {"), None);
+    }
+
+    #[test]
+    fn fragments_parse_to_growing_values() {
+        let document = r#"{"name": "Ada", "tags": ["math", "code"], "done": true, "age": 36}"#;
+        let fragments = [
+            (r#"{"na"#, json!({})),
+            (r#"{"name": "Ad"#, json!({"name": "Ad"})),
+            (r#"{"name": "Ada", "tags"#, json!({"name": "Ada"})),
+            (
+                r#"{"name": "Ada", "tags": ["math", "co"#,
+                json!({"name": "Ada", "tags": ["math", "co"]}),
+            ),
+            (
+                r#"{"name": "Ada", "tags": ["math", "code"], "done": tr"#,
+                json!({"name": "Ada", "tags": ["math", "code"]}),
+            ),
+            (
+                r#"{"name": "Ada", "tags": ["math", "code"], "done": true, "age": 3"#,
+                json!({"name": "Ada", "tags": ["math", "code"], "done": true}),
+            ),
+            (
+                document,
+                json!({"name": "Ada", "tags": ["math", "code"], "done": true, "age": 36}),
+            ),
+        ];
+
+        for (fragment, expected) in fragments {
+            assert!(document.starts_with(fragment));
+            assert_eq!(parse_partial(fragment), Some(expected), "{}", fragment);
+        }
+    }
+
+    #[test]
+    fn escapes_cut_mid_way_are_dropped() {
+        assert_eq!(parse_partial(r#"["a\"#), Some(json!(["a"])));
+        assert_eq!(parse_partial(r#"["a\"b"#), Some(json!(["a\"b"])));
+    }
+}
@@ -0,0 +1,44 @@
+use rand::Rng;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Retries left for a whole agent run, shared by every kind of retry (the
+/// run's own, the OpenRouter client's and MCP server restarts) so a flaky
+/// dependency can't multiply calls across iterations. Clones draw from the
+/// same budget.
+#[derive(Debug, Clone, Default)]
+pub struct RetryBudget {
+    budget: usize,
+    remaining: Arc<AtomicUsize>,
+}
+
+impl RetryBudget {
+    pub fn new(budget: usize) -> Self {
+        Self {
+            budget,
+            remaining: Arc::new(AtomicUsize::new(budget)),
+        }
+    }
+
+    pub fn budget(&self) -> usize {
+        self.budget
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.remaining.load(Ordering::Relaxed)
+    }
+
+    /// Takes one retry, or returns false when none are left.
+    pub fn try_consume(&self) -> bool {
+        self.remaining
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+            .is_ok()
+    }
+}
+
+/// A random delay of up to `max_ms`, added to waits so concurrent runs and
+/// requests don't hit the upstream in lockstep.
+pub fn jitter(max_ms: u64) -> Duration {
+    Duration::from_millis(rand::thread_rng().gen_range(0..=max_ms))
+}
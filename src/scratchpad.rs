@@ -0,0 +1,95 @@
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+
+use crate::error::AgentError;
+use crate::models::{FunctionDefinition, Tool};
+
+const WRITE_TOOL: &str = "scratchpad_write";
+const READ_TOOL: &str = "scratchpad_read";
+
+/// Notes the agent keeps for itself during a single run. Each run owns its
+/// own scratchpad, so notes disappear when the run ends.
+#[derive(Debug, Default)]
+pub struct Scratchpad {
+    notes: BTreeMap<String, String>,
+}
+
+impl Scratchpad {
+    pub fn handles(tool_name: &str) -> bool {
+        tool_name == WRITE_TOOL || tool_name == READ_TOOL
+    }
+
+    pub fn tools() -> Vec<Tool> {
+        vec![
+            Tool {
+                tool_type: "function".to_string(),
+                function: FunctionDefinition {
+                    name: WRITE_TOOL.to_string(),
+                    description: "Save an intermediate note for later in this task. \
+                                  Writing to an existing key replaces it."
+                        .to_string(),
+                    parameters: json!({
+                        "type": "object",
+                        "properties": {
+                            "key": { "type": "string", "description": "Name of the note" },
+                            "content": { "type": "string", "description": "Note text" }
+                        },
+                        "required": ["key", "content"]
+                    }),
+                },
+            },
+            Tool {
+                tool_type: "function".to_string(),
+                function: FunctionDefinition {
+                    name: READ_TOOL.to_string(),
+                    description: "Read a note saved earlier in this task, or all notes \
+                                  when no key is given."
+                        .to_string(),
+                    parameters: json!({
+                        "type": "object",
+                        "properties": {
+                            "key": { "type": "string", "description": "Name of the note" }
+                        }
+                    }),
+                },
+            },
+        ]
+    }
+
+    pub fn execute(&mut self, tool_name: &str, args_json: &str) -> Result<String, AgentError> {
+        let args: Value = serde_json::from_str(args_json)
+            .map_err(|e| AgentError::ToolError(format!("Invalid arguments: {}", e)))?;
+        let key = args.get("key").and_then(|v| v.as_str());
+
+        match tool_name {
+            WRITE_TOOL => {
+                let key = key.ok_or_else(|| missing_argument("key"))?;
+                let content = args
+                    .get("content")
+                    .and_then(|v| v.as_str())
+                    .ok_or_else(|| missing_argument("content"))?;
+                self.notes.insert(key.to_string(), content.to_string());
+                Ok(format!("Saved note '{}'", key))
+            }
+            READ_TOOL => match key {
+                Some(key) => self
+                    .notes
+                    .get(key)
+                    .cloned()
+                    .ok_or_else(|| AgentError::ToolError(format!("No note named '{}'", key))),
+                None if self.notes.is_empty() => Ok("The scratchpad is empty".to_string()),
+                None => Ok(self
+                    .notes
+                    .iter()
+                    .map(|(key, content)| format!("{}: {}", key, content))
+                    .collect::<Vec<_>>()
+                    .join("\n")),
+            },
+            other => Err(AgentError::ToolError(format!("Unknown tool: {}", other))),
+        }
+    }
+}
+
+fn missing_argument(name: &str) -> AgentError {
+    AgentError::ToolError(format!("Missing required string argument '{}'", name))
+}
@@ -0,0 +1,167 @@
+use futures::future::BoxFuture;
+use reqwest::Client as HttpClient;
+use serde_json::Value;
+use tracing::warn;
+
+use crate::config::Config;
+
+#[derive(Debug, Clone)]
+pub struct SearchResult {
+    pub title: String,
+    pub url: String,
+    pub snippet: String,
+}
+
+/// A web search backend for the `web_search` tool.
+pub trait SearchProvider: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn search<'a>(
+        &'a self,
+        client: &'a HttpClient,
+        query: &'a str,
+        limit: usize,
+    ) -> BoxFuture<'a, Result<Vec<SearchResult>, String>>;
+}
+
+/// Builds the provider selected by `SEARCH_PROVIDER`, if any.
+pub fn provider_from_config(config: &Config) -> Option<Box<dyn SearchProvider>> {
+    let provider = config.search_provider.as_deref()?;
+    match provider.to_ascii_lowercase().as_str() {
+        "searxng" => match config.search_url.clone() {
+            Some(base_url) => Some(Box::new(SearxngProvider { base_url })),
+            None => {
+                warn!("SEARCH_PROVIDER=searxng requires SEARCH_URL; web search disabled");
+                None
+            }
+        },
+        "brave" => match config.search_api_key.clone() {
+            Some(api_key) => Some(Box::new(BraveProvider { api_key })),
+            None => {
+                warn!("SEARCH_PROVIDER=brave requires SEARCH_API_KEY; web search disabled");
+                None
+            }
+        },
+        other => {
+            warn!("Unknown SEARCH_PROVIDER '{}'; web search disabled", other);
+            None
+        }
+    }
+}
+
+pub struct SearxngProvider {
+    base_url: String,
+}
+
+impl SearchProvider for SearxngProvider {
+    fn name(&self) -> &str {
+        "searxng"
+    }
+
+    fn search<'a>(
+        &'a self,
+        client: &'a HttpClient,
+        query: &'a str,
+        limit: usize,
+    ) -> BoxFuture<'a, Result<Vec<SearchResult>, String>> {
+        Box::pin(async move {
+            let url = format!("{}/search", self.base_url.trim_end_matches('/'));
+            let body =
+                fetch_json(client.get(url).query(&[("q", query), ("format", "json")])).await?;
+
+            Ok(parse_results(&body["results"], "content", limit))
+        })
+    }
+}
+
+pub struct BraveProvider {
+    api_key: String,
+}
+
+impl SearchProvider for BraveProvider {
+    fn name(&self) -> &str {
+        "brave"
+    }
+
+    fn search<'a>(
+        &'a self,
+        client: &'a HttpClient,
+        query: &'a str,
+        limit: usize,
+    ) -> BoxFuture<'a, Result<Vec<SearchResult>, String>> {
+        Box::pin(async move {
+            let request = client
+                .get("https://api.search.brave.com/res/v1/web/search")
+                .header("Accept", "application/json")
+                .header("X-Subscription-Token", &self.api_key)
+                .query(&[("q", query), ("count", &limit.to_string())]);
+            let body = fetch_json(request).await?;
+
+            Ok(parse_results(&body["web"]["results"], "description", limit))
+        })
+    }
+}
+
+async fn fetch_json(request: reqwest::RequestBuilder) -> Result<Value, String> {
+    let response = request
+        .send()
+        .await
+        .map_err(|e| format!("Search request failed: {}", e))?;
+
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        return Err(format!(
+            "Search provider returned HTTP {}: {}",
+            status.as_u16(),
+            body
+        ));
+    }
+
+    response
+        .json()
+        .await
+        .map_err(|e| format!("Invalid search response: {}", e))
+}
+
+fn parse_results(results: &Value, snippet_field: &str, limit: usize) -> Vec<SearchResult> {
+    let field =
+        |result: &Value, name: &str| result[name].as_str().unwrap_or_default().trim().to_string();
+
+    results
+        .as_array()
+        .map(|results| {
+            results
+                .iter()
+                .map(|result| SearchResult {
+                    title: field(result, "title"),
+                    url: field(result, "url"),
+                    snippet: field(result, snippet_field),
+                })
+                .filter(|result| !result.url.is_empty())
+                .take(limit)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+pub fn format_results(query: &str, results: &[SearchResult]) -> String {
+    if results.is_empty() {
+        return format!("No results found for '{}'", query);
+    }
+
+    results
+        .iter()
+        .enumerate()
+        .map(|(i, result)| {
+            format!(
+                "{}. {}\n   {}\n   {}",
+                i + 1,
+                result.title,
+                result.url,
+                result.snippet
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
@@ -0,0 +1,103 @@
+//! Server-sent events parsing, shared by the OpenRouter stream and the MCP
+//! HTTP transport.
+
+/// Incremental SSE parser: feed it the body as it arrives and it returns the
+/// data of each event once the blank line ending it has been read. Follows
+/// the SSE spec: lines starting with `:` are comments, fields other than
+/// `data` (`event`, `id`, `retry`) are ignored, one space after the colon is
+/// dropped, and an event's `data` lines are joined with newlines. Lines may
+/// end in LF or CRLF.
+#[derive(Debug, Default)]
+pub struct SseDecoder {
+    buffer: Vec<u8>,
+    data: Option<String>,
+}
+
+impl SseDecoder {
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<String> {
+        self.buffer.extend_from_slice(bytes);
+
+        let mut events = Vec::new();
+        // Lines are decoded whole, so a character split across chunks
+        // survives.
+        while let Some(end) = self.buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line[..end]);
+            if let Some(data) = self.line(line.strip_suffix('\r').unwrap_or(&line)) {
+                events.push(data);
+            }
+        }
+        events
+    }
+
+    fn line(&mut self, line: &str) -> Option<String> {
+        if line.is_empty() {
+            return self.data.take();
+        }
+        if line.starts_with(':') {
+            return None;
+        }
+
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line, ""),
+        };
+        if field == "data" {
+            match self.data {
+                Some(ref mut data) => {
+                    data.push('\n');
+                    data.push_str(value);
+                }
+                None => self.data = Some(value.to_string()),
+            }
+        }
+        None
+    }
+}
+
+/// The data of each event in a complete SSE body. An event the body ends in
+/// the middle of is dropped, as the spec requires.
+pub fn parse_events(body: &str) -> Vec<String> {
+    SseDecoder::default().push(body.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn comments_and_other_fields_are_skipped() {
+        let body = ": keep-alive\nevent: message\nid: 7\ndata: {\"a\":1}\n\n";
+        assert_eq!(parse_events(body), ["{\"a\":1}"]);
+    }
+
+    #[test]
+    fn data_lines_are_joined_with_newlines() {
+        let body = "data: first\ndata:second\ndata:  indented\n\ndata: next\n\n";
+        assert_eq!(parse_events(body), ["first\nsecond\n indented", "next"]);
+    }
+
+    #[test]
+    fn crlf_line_endings_are_accepted() {
+        let body = "event: message\r\ndata: one\r\n\r\ndata: two\r\n\r\n";
+        assert_eq!(parse_events(body), ["one", "two"]);
+    }
+
+    #[test]
+    fn events_without_data_and_unfinished_events_are_dropped() {
+        let body = ": ping\n\nevent: end\n\ndata: cut off";
+        assert!(parse_events(body).is_empty());
+    }
+
+    #[test]
+    fn events_can_arrive_in_any_chunks() {
+        let body = "data: héllo\n\n: comment\ndata: [DONE]\n\n".as_bytes();
+        let mut decoder = SseDecoder::default();
+        let mut events = Vec::new();
+        // One byte at a time splits the `é` and every line ending.
+        for byte in body {
+            events.extend(decoder.push(std::slice::from_ref(byte)));
+        }
+        assert_eq!(events, ["héllo", "[DONE]"]);
+    }
+}
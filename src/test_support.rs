@@ -0,0 +1,151 @@
+//! Helpers shared by the unit tests: a config pointed at a local stand-in
+//! for the OpenRouter API, and the stand-in itself.
+
+use axum::{
+    body::{Body, Bytes},
+    extract::State,
+    http::{header, StatusCode},
+    response::IntoResponse,
+    routing::post,
+    Json, Router,
+};
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+
+use crate::config::Config;
+
+/// Config for a client or agent whose OpenRouter requests go to `base_url`.
+pub fn test_config(base_url: String) -> Config {
+    std::env::set_var("OPENROUTER_API_KEY", "test-key");
+    let mut config = Config::from_env().expect("config");
+    config.openrouter_base_url = base_url;
+    config.model_fallbacks.clear();
+    config.openrouter_retry_base_ms = 1;
+    config.iteration_delay_ms = 0;
+    config.iteration_jitter_ms = 0;
+    config.tools_config_path = String::new();
+    config.notes_db_path = ":memory:".to_string();
+    config
+}
+
+/// Serves `app` on an ephemeral local port and returns its base URL.
+pub async fn serve(app: Router) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    url
+}
+
+/// A stand-in for `/chat/completions` that answers with `replies` in order,
+/// repeating the last one, and records every request body.
+pub struct Upstream {
+    pub url: String,
+    requests: Arc<Mutex<Vec<Value>>>,
+}
+
+impl Upstream {
+    pub async fn start(replies: Vec<(StatusCode, Value)>) -> Self {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let state = (Arc::new(replies), requests.clone());
+        let app = Router::new()
+            .route("/chat/completions", post(reply))
+            .with_state(state);
+        Self {
+            url: serve(app).await,
+            requests,
+        }
+    }
+
+    /// Answers every request with a 200 and the next of `bodies`.
+    pub async fn ok(bodies: Vec<Value>) -> Self {
+        Self::start(bodies.into_iter().map(|b| (StatusCode::OK, b)).collect()).await
+    }
+
+    pub fn requests(&self) -> Vec<Value> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+type UpstreamState = (Arc<Vec<(StatusCode, Value)>>, Arc<Mutex<Vec<Value>>>);
+
+async fn reply(
+    State((replies, requests)): State<UpstreamState>,
+    Json(body): Json<Value>,
+) -> (StatusCode, Json<Value>) {
+    let mut requests = requests.lock().unwrap();
+    requests.push(body);
+    let (status, reply) = &replies[(requests.len() - 1).min(replies.len() - 1)];
+    (*status, Json(reply.clone()))
+}
+
+/// A stand-in for a streamed `/chat/completions` that sends each of
+/// `chunks` as its own SSE event, in its own piece of a chunked body, then
+/// `[DONE]`.
+pub async fn streaming_upstream(chunks: Vec<Value>) -> String {
+    let events: Vec<String> = chunks
+        .iter()
+        .map(|chunk| format!("data: {}\n\n", chunk))
+        .chain(std::iter::once("data: [DONE]\n\n".to_string()))
+        .collect();
+    let app = Router::new().route(
+        "/chat/completions",
+        post(move || async move {
+            let pieces = events
+                .into_iter()
+                .map(|event| Ok::<_, Infallible>(Bytes::from(event)));
+            (
+                [(header::CONTENT_TYPE, "text/event-stream")],
+                Body::from_stream(futures::stream::iter(pieces)),
+            )
+                .into_response()
+        }),
+    );
+    serve(app).await
+}
+
+/// A streamed chunk carrying `content`.
+pub fn content_chunk(content: &str) -> Value {
+    json!({
+        "id": "gen-stream",
+        "model": "test/model",
+        "choices": [{"delta": {"content": content}, "finish_reason": null}]
+    })
+}
+
+/// A completion that answers with `content`.
+pub fn completion(content: &str) -> Value {
+    json!({
+        "id": "gen-answer",
+        "model": "test/model",
+        "choices": [{
+            "message": {"role": "assistant", "content": content},
+            "finish_reason": "stop"
+        }],
+        "usage": {"prompt_tokens": 10, "completion_tokens": 5, "total_tokens": 15}
+    })
+}
+
+/// A completion that calls each `(name, arguments)` tool, with ids
+/// `call_0`, `call_1`, ...
+pub fn tool_calls(calls: &[(&str, Value)]) -> Value {
+    let calls: Vec<Value> = calls
+        .iter()
+        .enumerate()
+        .map(|(i, (name, arguments))| {
+            json!({
+                "id": format!("call_{}", i),
+                "type": "function",
+                "function": {"name": name, "arguments": arguments.to_string()}
+            })
+        })
+        .collect();
+    json!({
+        "id": "gen-tools",
+        "model": "test/model",
+        "choices": [{
+            "message": {"role": "assistant", "content": null, "tool_calls": calls},
+            "finish_reason": "tool_calls"
+        }]
+    })
+}
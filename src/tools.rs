@@ -0,0 +1,910 @@
+use anyhow::{Context, Result};
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use reqwest::Client as HttpClient;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::models::{FunctionDefinition, Tool};
+use crate::notes::NoteStore;
+use crate::search::{format_results, SearchProvider};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ToolResult {
+    pub success: bool,
+    pub output: String,
+}
+
+impl ToolResult {
+    pub fn ok(output: impl Into<String>) -> Self {
+        Self {
+            success: true,
+            output: output.into(),
+        }
+    }
+
+    pub fn err(output: impl Into<String>) -> Self {
+        Self {
+            success: false,
+            output: output.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ToolDefinition {
+    pub name: String,
+    pub description: String,
+    pub parameters: Value,
+}
+
+pub enum ToolHandler {
+    Builtin(fn(&Value) -> ToolResult),
+    Http(HttpEndpoint),
+    WebSearch,
+    Notes(NoteOperation),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum NoteOperation {
+    Save,
+    Get,
+    List,
+}
+
+/// A tool declared in the tools config file, executed by calling `url`
+/// with the tool arguments.
+#[derive(Debug, Clone, Deserialize)]
+pub struct HttpToolConfig {
+    pub name: String,
+    pub description: String,
+    #[serde(default = "default_parameters")]
+    pub parameters: Value,
+    #[serde(flatten)]
+    pub endpoint: HttpEndpoint,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct HttpEndpoint {
+    pub url: String,
+    #[serde(default = "default_method")]
+    pub method: String,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ToolsConfig {
+    tools: Vec<HttpToolConfig>,
+}
+
+const DEFAULT_SEARCH_RESULT_COUNT: usize = 5;
+
+fn default_parameters() -> Value {
+    json!({ "type": "object", "properties": {} })
+}
+
+fn default_method() -> String {
+    "POST".to_string()
+}
+
+struct RegisteredTool {
+    definition: ToolDefinition,
+    handler: ToolHandler,
+}
+
+pub struct ToolRegistry {
+    // Ordered so the tools list sent to the model is stable between runs.
+    tools: BTreeMap<String, RegisteredTool>,
+    http_client: HttpClient,
+    search_provider: Option<Box<dyn SearchProvider>>,
+    search_result_count: usize,
+    note_store: Option<NoteStore>,
+}
+
+impl ToolRegistry {
+    pub fn new() -> Self {
+        let http_client = HttpClient::builder()
+            .user_agent(Config::user_agent())
+            .timeout(Duration::from_secs(30))
+            .build()
+            .expect("Failed to create HTTP client");
+
+        Self {
+            tools: BTreeMap::new(),
+            http_client,
+            search_provider: None,
+            search_result_count: DEFAULT_SEARCH_RESULT_COUNT,
+            note_store: None,
+        }
+    }
+
+    pub fn with_default_tools() -> Self {
+        let mut registry = Self::new();
+        registry.register_default_tools();
+        registry
+    }
+
+    /// Backs the `web_search` tool with a real search provider instead of
+    /// the simulated response.
+    pub fn set_search_provider(&mut self, provider: Box<dyn SearchProvider>, result_count: usize) {
+        self.search_provider = Some(provider);
+        self.search_result_count = result_count;
+    }
+
+    /// Storage for the `save_note`, `get_note` and `list_notes` tools.
+    pub fn set_note_store(&mut self, store: NoteStore) {
+        self.note_store = Some(store);
+    }
+
+    pub fn register(&mut self, definition: ToolDefinition, handler: ToolHandler) {
+        self.tools.insert(
+            definition.name.clone(),
+            RegisteredTool {
+                definition,
+                handler,
+            },
+        );
+    }
+
+    pub fn register_default_tools(&mut self) {
+        self.register(
+            ToolDefinition {
+                name: "encode_decode".to_string(),
+                description: "Encode or decode text using base64, base64url, or hex".to_string(),
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "operation": {
+                            "type": "string",
+                            "enum": ["encode", "decode"],
+                            "description": "Whether to encode or decode the input"
+                        },
+                        "encoding": {
+                            "type": "string",
+                            "enum": ["base64", "base64url", "hex"],
+                            "description": "The encoding to use"
+                        },
+                        "input": {
+                            "type": "string",
+                            "description": "The text to encode, or the encoded data to decode"
+                        }
+                    },
+                    "required": ["operation", "encoding", "input"]
+                }),
+            },
+            ToolHandler::Builtin(execute_encode_decode),
+        );
+
+        self.register(
+            ToolDefinition {
+                name: "calculator".to_string(),
+                description: "Evaluate an arithmetic expression with + - * / % ^ and parentheses"
+                    .to_string(),
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "expression": {
+                            "type": "string",
+                            "description": "The expression to evaluate, e.g. (2 + 3) * 4"
+                        }
+                    },
+                    "required": ["expression"]
+                }),
+            },
+            ToolHandler::Builtin(execute_calculator),
+        );
+
+        self.register(
+            ToolDefinition {
+                name: "get_current_time".to_string(),
+                description: "Get the current date and time in UTC".to_string(),
+                parameters: json!({ "type": "object", "properties": {} }),
+            },
+            ToolHandler::Builtin(execute_get_current_time),
+        );
+
+        self.register(
+            ToolDefinition {
+                name: "format_template".to_string(),
+                description: "Fill {name} placeholders in a template with the given values. \
+                              Use {{ and }} for literal braces"
+                    .to_string(),
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "template": {
+                            "type": "string",
+                            "description": "Text with {name} placeholders, e.g. Hello, {user}!"
+                        },
+                        "values": {
+                            "type": "object",
+                            "description": "Value for each placeholder, keyed by name"
+                        }
+                    },
+                    "required": ["template", "values"]
+                }),
+            },
+            ToolHandler::Builtin(execute_format_template),
+        );
+
+        self.register(
+            ToolDefinition {
+                name: "web_search".to_string(),
+                description: "Search the web for information".to_string(),
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "query": {
+                            "type": "string",
+                            "description": "The search query"
+                        }
+                    },
+                    "required": ["query"]
+                }),
+            },
+            ToolHandler::WebSearch,
+        );
+
+        self.register(
+            ToolDefinition {
+                name: "save_note".to_string(),
+                description: "Save a note with a title and content".to_string(),
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "title": {
+                            "type": "string",
+                            "description": "Title of the note"
+                        },
+                        "content": {
+                            "type": "string",
+                            "description": "Text of the note"
+                        }
+                    },
+                    "required": ["title", "content"]
+                }),
+            },
+            ToolHandler::Notes(NoteOperation::Save),
+        );
+
+        self.register(
+            ToolDefinition {
+                name: "get_note".to_string(),
+                description: "Read a saved note by its title".to_string(),
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "title": {
+                            "type": "string",
+                            "description": "Title of the note"
+                        }
+                    },
+                    "required": ["title"]
+                }),
+            },
+            ToolHandler::Notes(NoteOperation::Get),
+        );
+
+        self.register(
+            ToolDefinition {
+                name: "list_notes".to_string(),
+                description: "List saved notes with a short preview of each".to_string(),
+                parameters: json!({ "type": "object", "properties": {} }),
+            },
+            ToolHandler::Notes(NoteOperation::List),
+        );
+    }
+
+    /// Registers every tool declared in a tools config file and returns how
+    /// many were added.
+    pub fn load_http_tools<P: AsRef<Path>>(&mut self, path: P) -> Result<usize> {
+        let content = std::fs::read_to_string(path)?;
+        let config: ToolsConfig = serde_json::from_str(&content).context("Invalid tools config")?;
+        let count = config.tools.len();
+
+        for tool in config.tools {
+            self.register(
+                ToolDefinition {
+                    name: tool.name,
+                    description: tool.description,
+                    parameters: tool.parameters,
+                },
+                ToolHandler::Http(tool.endpoint),
+            );
+        }
+
+        Ok(count)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.tools.contains_key(name)
+    }
+
+    pub fn get_all(&self) -> Vec<Tool> {
+        self.tools
+            .values()
+            .map(|t| Tool {
+                tool_type: "function".to_string(),
+                function: FunctionDefinition {
+                    name: t.definition.name.clone(),
+                    description: t.definition.description.clone(),
+                    parameters: t.definition.parameters.clone(),
+                },
+            })
+            .collect()
+    }
+
+    pub async fn execute(&self, name: &str, args: &Value) -> ToolResult {
+        let Some(tool) = self.tools.get(name) else {
+            return ToolResult::err(format!("Unknown tool: {}", name));
+        };
+
+        match &tool.handler {
+            ToolHandler::Builtin(handler) => handler(args),
+            ToolHandler::Http(endpoint) => self.execute_http(endpoint, args).await,
+            ToolHandler::WebSearch => self.execute_web_search(args).await,
+            ToolHandler::Notes(operation) => self.execute_notes(*operation, args).await,
+        }
+    }
+
+    async fn execute_notes(&self, operation: NoteOperation, args: &Value) -> ToolResult {
+        let Some(ref store) = self.note_store else {
+            return ToolResult::err("Notes are unavailable: the note store could not be opened");
+        };
+
+        let result = match operation {
+            NoteOperation::Save => {
+                let (title, content) = match (str_arg(args, "title"), str_arg(args, "content")) {
+                    (Ok(title), Ok(content)) => (title, content),
+                    (Err(e), _) | (_, Err(e)) => return ToolResult::err(e),
+                };
+                store
+                    .save(title, content)
+                    .await
+                    .map(|_| format!("Note '{}' saved", title))
+            }
+            NoteOperation::Get => {
+                let title = match str_arg(args, "title") {
+                    Ok(title) => title,
+                    Err(e) => return ToolResult::err(e),
+                };
+                match store.get(title).await {
+                    Ok(Some(note)) => Ok(format!(
+                        "{} (saved {})\n\n{}",
+                        note.title, note.created_at, note.content
+                    )),
+                    Ok(None) => return ToolResult::err(format!("No note titled '{}'", title)),
+                    Err(e) => Err(e),
+                }
+            }
+            NoteOperation::List => store.list().await.map(|notes| {
+                if notes.is_empty() {
+                    return "No notes saved".to_string();
+                }
+                notes
+                    .iter()
+                    .map(|note| format!("- {}: {}", note.title, note.content))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            }),
+        };
+
+        match result {
+            Ok(output) => ToolResult::ok(output),
+            Err(e) => ToolResult::err(format!("Note store error: {}", e)),
+        }
+    }
+
+    async fn execute_web_search(&self, args: &Value) -> ToolResult {
+        let query = match str_arg(args, "query") {
+            Ok(query) => query,
+            Err(e) => return ToolResult::err(e),
+        };
+
+        let Some(ref provider) = self.search_provider else {
+            return ToolResult::ok(format!(
+                "Simulated search results for '{}': no search provider is configured \
+                 (set SEARCH_PROVIDER), so no live results are available.",
+                query
+            ));
+        };
+
+        match provider
+            .search(&self.http_client, query, self.search_result_count)
+            .await
+        {
+            Ok(results) => ToolResult::ok(format_results(query, &results)),
+            Err(e) => ToolResult::err(format!("{} search failed: {}", provider.name(), e)),
+        }
+    }
+
+    async fn execute_http(&self, endpoint: &HttpEndpoint, args: &Value) -> ToolResult {
+        let request = if endpoint.method.eq_ignore_ascii_case("GET") {
+            let query: Vec<(String, String)> = args
+                .as_object()
+                .map(|obj| {
+                    obj.iter()
+                        .map(|(k, v)| match v {
+                            Value::String(s) => (k.clone(), s.clone()),
+                            other => (k.clone(), other.to_string()),
+                        })
+                        .collect()
+                })
+                .unwrap_or_default();
+            self.http_client.get(&endpoint.url).query(&query)
+        } else {
+            let method =
+                match reqwest::Method::from_bytes(endpoint.method.to_uppercase().as_bytes()) {
+                    Ok(method) => method,
+                    Err(_) => {
+                        return ToolResult::err(format!("Invalid HTTP method: {}", endpoint.method))
+                    }
+                };
+            self.http_client.request(method, &endpoint.url).json(args)
+        };
+
+        let request = endpoint
+            .headers
+            .iter()
+            .fold(request, |req, (name, value)| req.header(name, value));
+
+        let response = match request.send().await {
+            Ok(response) => response,
+            Err(e) => return ToolResult::err(format!("Request failed: {}", e)),
+        };
+
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        if status.is_success() {
+            ToolResult::ok(body)
+        } else {
+            ToolResult::err(format!("HTTP {}: {}", status.as_u16(), body))
+        }
+    }
+}
+
+impl Default for ToolRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn str_arg<'a>(args: &'a Value, key: &str) -> Result<&'a str, String> {
+    args.get(key)
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| format!("Missing required string argument '{}'", key))
+}
+
+fn execute_encode_decode(args: &Value) -> ToolResult {
+    let (operation, encoding, input) = match (
+        str_arg(args, "operation"),
+        str_arg(args, "encoding"),
+        str_arg(args, "input"),
+    ) {
+        (Ok(op), Ok(enc), Ok(input)) => (op, enc, input),
+        (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => return ToolResult::err(e),
+    };
+
+    match operation {
+        "encode" => match encoding {
+            "base64" => ToolResult::ok(STANDARD.encode(input)),
+            "base64url" => ToolResult::ok(URL_SAFE_NO_PAD.encode(input)),
+            "hex" => ToolResult::ok(hex::encode(input)),
+            other => ToolResult::err(format!("Unsupported encoding: {}", other)),
+        },
+        "decode" => {
+            let decoded = match encoding {
+                "base64" => STANDARD
+                    .decode(input.trim())
+                    .map_err(|e| format!("Invalid base64 input: {}", e)),
+                "base64url" => URL_SAFE_NO_PAD
+                    .decode(input.trim().trim_end_matches('='))
+                    .map_err(|e| format!("Invalid base64url input: {}", e)),
+                "hex" => hex::decode(input.trim()).map_err(|e| format!("Invalid hex input: {}", e)),
+                other => Err(format!("Unsupported encoding: {}", other)),
+            };
+
+            match decoded {
+                Ok(bytes) => match String::from_utf8(bytes) {
+                    Ok(text) => ToolResult::ok(text),
+                    Err(e) => ToolResult::err(format!(
+                        "Decoded data is not valid UTF-8 ({} bytes)",
+                        e.as_bytes().len()
+                    )),
+                },
+                Err(e) => ToolResult::err(e),
+            }
+        }
+        other => ToolResult::err(format!(
+            "Unsupported operation: {} (expected 'encode' or 'decode')",
+            other
+        )),
+    }
+}
+
+fn execute_calculator(args: &Value) -> ToolResult {
+    let expression = match str_arg(args, "expression") {
+        Ok(expression) => expression,
+        Err(e) => return ToolResult::err(e),
+    };
+
+    match eval_math_expression(expression) {
+        Ok(value) => ToolResult::ok(format_number(value)),
+        Err(e) => ToolResult::err(format!("Cannot evaluate '{}': {}", expression, e)),
+    }
+}
+
+fn eval_math_expression(expression: &str) -> Result<f64, String> {
+    let compact: String = expression.chars().filter(|c| !c.is_whitespace()).collect();
+    if compact.is_empty() {
+        return Err("empty expression".to_string());
+    }
+    let value = simple_eval(&compact, 0)?;
+    if value.is_finite() {
+        Ok(value)
+    } else {
+        Err("result is not a finite number".to_string())
+    }
+}
+
+/// Deepest nesting of parentheses and unary signs accepted. Each level is
+/// a recursive call, so unbounded nesting could overflow the stack.
+const MAX_EXPRESSION_DEPTH: usize = 64;
+
+/// Recursive-descent evaluation: split on the lowest-precedence operators
+/// outside parentheses, then evaluate each operand. Precedence from lowest
+/// to highest is `+ -`, `* / %`, unary signs, then `^`. Chains of binary
+/// operators are folded in a loop, so only nesting adds to `depth`.
+fn simple_eval(expr: &str, depth: usize) -> Result<f64, String> {
+    if expr.is_empty() {
+        return Err("missing operand".to_string());
+    }
+    if depth > MAX_EXPRESSION_DEPTH {
+        return Err("expression too deeply nested".to_string());
+    }
+
+    for ops in [&['+', '-'][..], &['*', '/', '%'][..]] {
+        let positions = find_operators(expr, ops);
+        let Some(&first) = positions.first() else {
+            continue;
+        };
+        let mut value = simple_eval(&expr[..first], depth)?;
+        for (i, &pos) in positions.iter().enumerate() {
+            let end = positions.get(i + 1).copied().unwrap_or(expr.len());
+            let right = simple_eval(&expr[pos + 1..end], depth)?;
+            value = match &expr[pos..pos + 1] {
+                "+" => value + right,
+                "-" => value - right,
+                "*" => value * right,
+                _ if right == 0.0 => return Err("division by zero".to_string()),
+                "/" => value / right,
+                _ => value % right,
+            };
+        }
+        return Ok(value);
+    }
+
+    if let Some(operand) = expr.strip_prefix('-') {
+        return simple_eval(operand, depth + 1).map(|value| -value);
+    }
+    if let Some(operand) = expr.strip_prefix('+') {
+        return simple_eval(operand, depth + 1);
+    }
+
+    let positions = find_operators(expr, &['^']);
+    if let Some(&last) = positions.last() {
+        let mut value = simple_eval(&expr[last + 1..], depth)?;
+        for (i, &pos) in positions.iter().enumerate().rev() {
+            let start = if i == 0 { 0 } else { positions[i - 1] + 1 };
+            value = simple_eval(&expr[start..pos], depth)?.powf(value);
+        }
+        return Ok(value);
+    }
+
+    if let Some(inner) = expr.strip_prefix('(').and_then(|e| e.strip_suffix(')')) {
+        return simple_eval(inner, depth + 1);
+    }
+
+    expr.parse::<f64>()
+        .map_err(|_| format!("invalid number '{}'", expr))
+}
+
+/// Finds the binary operators from `ops` at parenthesis depth zero, in
+/// order. A `+` or `-` that does not follow an operand is a sign, not an
+/// operator, and one after the `e` of a number like `1e-5` is part of it.
+fn find_operators(expr: &str, ops: &[char]) -> Vec<usize> {
+    let mut depth = 0i32;
+    let mut found = Vec::new();
+    let (mut before_prev, mut prev) = (None, None);
+    for (i, c) in expr.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            '+' | '-' if !prev.is_some_and(ends_operand) => {}
+            '+' | '-' if is_exponent(before_prev, prev) => {}
+            _ if depth == 0 && ops.contains(&c) => found.push(i),
+            _ => {}
+        }
+        (before_prev, prev) = (prev, Some(c));
+    }
+    found
+}
+
+/// Whether `prev` is the exponent marker of a number literal.
+fn is_exponent(before_prev: Option<char>, prev: Option<char>) -> bool {
+    matches!(prev, Some('e' | 'E')) && before_prev.is_some_and(|c| c.is_ascii_digit() || c == '.')
+}
+
+fn ends_operand(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '.' || c == ')'
+}
+
+fn format_number(value: f64) -> String {
+    if value.fract() == 0.0 && value.abs() < 1e15 {
+        format!("{}", value as i64)
+    } else {
+        value.to_string()
+    }
+}
+
+fn execute_format_template(args: &Value) -> ToolResult {
+    let template = match str_arg(args, "template") {
+        Ok(template) => template,
+        Err(e) => return ToolResult::err(e),
+    };
+    let Some(values) = args.get("values").and_then(|v| v.as_object()) else {
+        return ToolResult::err("Missing required object argument 'values'".to_string());
+    };
+
+    match fill_template(template, values) {
+        Ok(filled) => ToolResult::ok(filled),
+        Err(e) => ToolResult::err(e),
+    }
+}
+
+/// Replaces each `{name}` with its value; `{{` and `}}` are literal braces.
+/// Every placeholder without a value is reported, not just the first.
+fn fill_template(
+    template: &str,
+    values: &serde_json::Map<String, Value>,
+) -> Result<String, String> {
+    let mut output = String::with_capacity(template.len());
+    let mut missing: Vec<&str> = Vec::new();
+    let mut rest = template;
+
+    while let Some(pos) = rest.find(['{', '}']) {
+        output.push_str(&rest[..pos]);
+        let brace = &rest[pos..pos + 1];
+        rest = &rest[pos + 1..];
+
+        if let Some(after) = rest.strip_prefix(brace) {
+            output.push_str(brace);
+            rest = after;
+            continue;
+        }
+        if brace == "}" {
+            return Err("Unmatched '}' in template; use '}}' for a literal brace".to_string());
+        }
+
+        let end = rest
+            .find('}')
+            .ok_or_else(|| "Unclosed '{' in template; use '{{' for a literal brace".to_string())?;
+        let name = rest[..end].trim();
+        rest = &rest[end + 1..];
+
+        match values.get(name) {
+            Some(Value::String(s)) => output.push_str(s),
+            Some(Value::Null) => {}
+            Some(other) => output.push_str(&other.to_string()),
+            None if !missing.contains(&name) => missing.push(name),
+            None => {}
+        }
+    }
+    output.push_str(rest);
+
+    if !missing.is_empty() {
+        return Err(format!(
+            "Missing values for placeholders: {}",
+            missing.join(", ")
+        ));
+    }
+    Ok(output)
+}
+
+fn execute_get_current_time(_args: &Value) -> ToolResult {
+    let now = chrono::Utc::now();
+    ToolResult::ok(format!(
+        "{} (unix timestamp {})",
+        now.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        now.timestamp()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn encode_decode(operation: &str, encoding: &str, input: &str) -> ToolResult {
+        execute_encode_decode(&json!({
+            "operation": operation,
+            "encoding": encoding,
+            "input": input,
+        }))
+    }
+
+    #[test]
+    fn encodings_round_trip() {
+        let text = "hello?>~ world";
+        for (encoding, encoded) in [
+            ("base64", "aGVsbG8/Pn4gd29ybGQ="),
+            ("base64url", "aGVsbG8_Pn4gd29ybGQ"),
+            ("hex", "68656c6c6f3f3e7e20776f726c64"),
+        ] {
+            let result = encode_decode("encode", encoding, text);
+            assert!(result.success, "{}: {}", encoding, result.output);
+            assert_eq!(result.output, encoded, "{}", encoding);
+
+            let result = encode_decode("decode", encoding, encoded);
+            assert!(result.success, "{}: {}", encoding, result.output);
+            assert_eq!(result.output, text, "{}", encoding);
+        }
+    }
+
+    #[test]
+    fn base64url_decode_accepts_padding() {
+        let result = encode_decode("decode", "base64url", "aGk=");
+        assert!(result.success, "{}", result.output);
+        assert_eq!(result.output, "hi");
+    }
+
+    #[test]
+    fn invalid_input_is_rejected() {
+        for (encoding, input, message) in [
+            ("base64", "not base64!", "Invalid base64 input"),
+            ("base64url", "a+b/", "Invalid base64url input"),
+            ("hex", "abc", "Invalid hex input"),
+            ("hex", "zz", "Invalid hex input"),
+        ] {
+            let result = encode_decode("decode", encoding, input);
+            assert!(!result.success, "{} accepted {:?}", encoding, input);
+            assert!(result.output.starts_with(message), "{}", result.output);
+        }
+    }
+
+    #[test]
+    fn non_utf8_output_is_rejected() {
+        let result = encode_decode("decode", "hex", "ff");
+        assert!(!result.success);
+        assert!(
+            result.output.contains("not valid UTF-8"),
+            "{}",
+            result.output
+        );
+    }
+
+    #[test]
+    fn unary_signs() {
+        for (expression, expected) in [
+            ("-5 + 3", -2.0),
+            ("10 * -2", -20.0),
+            ("-(3-5)", 2.0),
+            ("--4", 4.0),
+            ("+7 - -1", 8.0),
+            ("2 ^ -1", 0.5),
+        ] {
+            assert_eq!(
+                eval_math_expression(expression),
+                Ok(expected),
+                "{}",
+                expression
+            );
+        }
+    }
+
+    #[test]
+    fn exponentiation_is_right_associative() {
+        assert_eq!(eval_math_expression("2^3^2"), Ok(512.0));
+        assert_eq!(eval_math_expression("(2^3)^2"), Ok(64.0));
+        assert_eq!(eval_math_expression("-2^2"), Ok(-4.0));
+    }
+
+    #[test]
+    fn remainder() {
+        assert_eq!(eval_math_expression("10 % 3"), Ok(1.0));
+        assert_eq!(eval_math_expression("2 + 10 % 4 * 2"), Ok(6.0));
+        assert_eq!(
+            eval_math_expression("5 % 0"),
+            Err("division by zero".to_string())
+        );
+    }
+
+    #[test]
+    fn scientific_notation() {
+        for (expression, expected) in [
+            ("1e-5", 1e-5),
+            ("2.5e+3 * 2", 5000.0),
+            ("1e3-1", 999.0),
+            ("3E2 - 1e1", 290.0),
+            ("-1.e-2", -0.01),
+        ] {
+            assert_eq!(
+                eval_math_expression(expression),
+                Ok(expected),
+                "{}",
+                expression
+            );
+        }
+    }
+
+    #[test]
+    fn deep_nesting_is_rejected() {
+        let too_deep = "Err(\"expression too deeply nested\")";
+        let signs = "-".repeat(200_000) + "1";
+        assert_eq!(format!("{:?}", eval_math_expression(&signs)), too_deep);
+
+        let parens = "(".repeat(100_000) + "1" + &")".repeat(100_000);
+        assert_eq!(format!("{:?}", eval_math_expression(&parens)), too_deep);
+
+        let nested = "(".repeat(10) + "1" + &")".repeat(10);
+        assert_eq!(eval_math_expression(&nested), Ok(1.0));
+    }
+
+    #[test]
+    fn long_operator_chains_are_evaluated() {
+        let sum = vec!["1"; 100_000].join("+");
+        assert_eq!(eval_math_expression(&sum), Ok(100_000.0));
+    }
+
+    #[test]
+    fn unknown_encoding_and_operation_are_rejected() {
+        assert!(!encode_decode("encode", "rot13", "x").success);
+        assert!(!encode_decode("compress", "hex", "x").success);
+    }
+
+    fn values(value: Value) -> serde_json::Map<String, Value> {
+        value.as_object().cloned().unwrap()
+    }
+
+    #[test]
+    fn template_placeholders_are_filled() {
+        let filled = fill_template(
+            "Hello {name}, you have { count } new {kind}.{empty}",
+            &values(json!({"name": "Ada", "count": 3, "kind": "messages", "empty": null})),
+        );
+        assert_eq!(filled.unwrap(), "Hello Ada, you have 3 new messages.");
+    }
+
+    #[test]
+    fn every_missing_placeholder_is_reported() {
+        let error = fill_template(
+            "{greeting} {name}, {greeting} again from {sender}",
+            &values(json!({"name": "Ada"})),
+        )
+        .unwrap_err();
+        assert_eq!(error, "Missing values for placeholders: greeting, sender");
+    }
+
+    #[test]
+    fn doubled_braces_are_literal() {
+        let filled = fill_template(
+            "{{\"user\": \"{name}\"}} and {{name}}",
+            &values(json!({"name": "Ada"})),
+        );
+        assert_eq!(filled.unwrap(), "{\"user\": \"Ada\"} and {name}");
+    }
+
+    #[test]
+    fn unmatched_braces_are_rejected() {
+        let error = fill_template("oops }", &values(json!({}))).unwrap_err();
+        assert!(error.starts_with("Unmatched '}'"), "{}", error);
+
+        let error = fill_template("oops {name", &values(json!({"name": "Ada"}))).unwrap_err();
+        assert!(error.starts_with("Unclosed '{'"), "{}", error);
+    }
+}